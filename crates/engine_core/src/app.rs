@@ -84,6 +84,7 @@ impl<'a> App<'a> {
                                 AppAlert::None => {}
                                 AppAlert::CloseApp => elwt.exit(),
                             }
+                            alloc.swap_buffers();
 
                             self.render(pipeline, alloc, elwt);
 
@@ -129,6 +130,7 @@ impl WindowlessApp {
                 AppAlert::None => {}
                 AppAlert::CloseApp => done = true,
             }
+            alloc.swap_buffers();
         }
     }
 }
@@ -0,0 +1,306 @@
+use std::marker::PhantomData;
+
+use frosty_alloc::{
+    AllocError, Allocator, DataAccess, DataAccessMut, Erased, FrostyAllocatable, ObjectHandleMut,
+};
+
+use crate::{query::RawQuery, MASTER_THREAD};
+
+/*
+ * Double buffered components
+ *
+ * With the semaphores alone, a system reading a component while
+ * another writes it sees either the old or new value depending on
+ * how the systems were scheduled. Components registered with
+ * Spawner::register_double_buffered() keep a second copy of each
+ * object to avoid this:
+ *      next        the object in the Query. Systems write it like
+ *                  any other component
+ *      previous    the value committed at the end of last frame.
+ *                  Nothing writes it during a frame, so it is read
+ *                  without locking and every system sees the same
+ *                  value
+ * The master thread commits next into previous between frames with
+ * Spawner::swap_buffers()
+ */
+
+type CopyFn =
+    fn(&ObjectHandleMut<Erased>, &mut Allocator) -> Result<ObjectHandleMut<Erased>, AllocError>;
+type CommitFn = fn(&ObjectHandleMut<Erased>, &ObjectHandleMut<Erased>);
+
+// The previous copies of a double buffered component
+pub(crate) struct BackBuffer {
+    // previous[i] is the copy of RawQuery.objs[i]
+    previous: Vec<ObjectHandleMut<Erased>>,
+    copy: CopyFn,
+    commit: CommitFn,
+}
+
+impl BackBuffer {
+    pub fn of<C: FrostyAllocatable + Clone>() -> Self {
+        Self {
+            previous: Vec::new(),
+            copy: copy::<C>,
+            commit: commit::<C>,
+        }
+    }
+
+    pub fn empty_like(&self) -> Self {
+        Self {
+            previous: Vec::new(),
+            copy: self.copy,
+            commit: self.commit,
+        }
+    }
+
+    // Allocate the previous copy of an object being added
+    pub fn push_copy(
+        &mut self,
+        next: &ObjectHandleMut<Erased>,
+        alloc: &mut Allocator,
+    ) -> Result<(), AllocError> {
+        self.previous.push((self.copy)(next, alloc)?);
+        Ok(())
+    }
+
+    // Free the copies of objects being removed from the Query.
+    // keep[i] says whether previous[i] stays
+    pub fn retain(&mut self, keep: &[bool], alloc: &mut Allocator) -> Result<(), AllocError> {
+        let mut result = Ok(());
+        for (mut copy, keep) in std::mem::take(&mut self.previous).into_iter().zip(keep) {
+            match keep {
+                true => self.previous.push(copy),
+                false => result = result.and(alloc.free(&mut copy)),
+            }
+        }
+        result
+    }
+
+    pub fn commit(&mut self, next: &[ObjectHandleMut<Erased>]) {
+        for (previous, next) in self.previous.iter().zip(next) {
+            (self.commit)(previous, next);
+        }
+    }
+}
+
+fn copy<C: FrostyAllocatable + Clone>(
+    next: &ObjectHandleMut<Erased>,
+    alloc: &mut Allocator,
+) -> Result<ObjectHandleMut<Erased>, AllocError> {
+    let value = next
+        .cast_clone::<C>()
+        .get_access(MASTER_THREAD)?
+        .as_ref()
+        .clone();
+    Ok(unsafe { alloc.alloc(value)?.dissolve_data() })
+}
+
+fn commit<C: FrostyAllocatable + Clone>(
+    previous: &ObjectHandleMut<Erased>,
+    next: &ObjectHandleMut<Erased>,
+) {
+    let (mut previous, mut next) = (previous.cast_clone::<C>(), next.cast_clone::<C>());
+    // free'd objects have nothing to commit
+    let (Ok(mut previous), Ok(mut next)) = (
+        previous.get_access_mut(MASTER_THREAD),
+        next.get_access_mut(MASTER_THREAD),
+    ) else {
+        return;
+    };
+    // swapping moves the new value over without cloning it,
+    // then clone_from() can reuse what the old value owned.
+    // (next) ends up as it was, so this isn't a change to it
+    std::mem::swap(previous.as_mut_untracked(), next.as_mut_untracked());
+    next.as_mut_untracked().clone_from(previous.as_ref());
+}
+
+// A Query over a double buffered component. Each object is
+// handed out as a [Buffered] holding both of its copies
+pub struct BufferedQuery<T: FrostyAllocatable> {
+    raw: *const RawQuery,
+    obj_ptr: usize,
+    thread: u32,
+    _pd: PhantomData<T>,
+}
+
+impl<T: FrostyAllocatable> BufferedQuery<T> {
+    // Returns None if (raw) isn't double buffered
+    pub(crate) fn new(raw: &RawQuery, thread: u32) -> Option<Self> {
+        raw.buffer.as_ref()?;
+        Some(Self {
+            raw,
+            obj_ptr: 0,
+            thread,
+            _pd: PhantomData,
+        })
+    }
+
+    // resets iteration
+    pub fn reset(&mut self) {
+        self.obj_ptr = 0;
+    }
+}
+
+impl<T: FrostyAllocatable> Iterator for BufferedQuery<T> {
+    type Item = Buffered<T>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let raw = unsafe { self.raw.as_ref() }.expect("Failed to read from raw query");
+        let previous = raw.buffer.as_ref()?.previous.get(self.obj_ptr)?;
+        let next = raw.handles().get(self.obj_ptr)?;
+        self.obj_ptr += 1;
+        Some(Buffered {
+            previous: previous.cast_clone(),
+            next: next.cast_clone(),
+            thread: self.thread,
+        })
+    }
+}
+
+unsafe impl<T: FrostyAllocatable + Send> Send for BufferedQuery<T> {}
+
+// Both copies of one object in a [BufferedQuery]
+pub struct Buffered<T: FrostyAllocatable> {
+    previous: ObjectHandleMut<T>,
+    next: ObjectHandleMut<T>,
+    thread: u32,
+}
+
+impl<T: FrostyAllocatable> Buffered<T> {
+    // The value committed at the end of last frame. Only
+    // swap_buffers() writes to it, which waits on the access
+    pub fn previous(&mut self) -> DataAccess<T> {
+        self.previous
+            .get_access(self.thread)
+            .expect("Failed to access component data")
+    }
+
+    // The value being written this frame. Blocks while
+    // another thread is accessing it
+    pub fn next_mut(&mut self) -> DataAccessMut<T> {
+        self.next
+            .get_access_mut(self.thread)
+            .expect("Failed to access component data")
+    }
+}
+
+#[cfg(test)]
+mod buffered_tests {
+    use frosty_alloc::FrostyAllocatable;
+
+    use crate::{query::Query, Spawner, MASTER_THREAD};
+
+    #[derive(FrostyAllocatable, Clone, Debug, PartialEq)]
+    struct Body {
+        pos: f32,
+        trail: Vec<f32>,
+    }
+
+    fn body(pos: f32) -> Body {
+        Body {
+            pos,
+            trail: Vec::new(),
+        }
+    }
+
+    fn spawner() -> Spawner {
+        let mut spawner = Spawner::new();
+        spawner.register_double_buffered::<Body>();
+        spawner.spawn_obj(body(1.0)).unwrap();
+        spawner.spawn_batch([body(2.0), body(3.0)]).unwrap();
+        spawner
+    }
+
+    fn step(spawner: &Spawner) {
+        for mut body in spawner.get_buffered_query::<Body>(1).unwrap() {
+            let moved = body.previous().as_ref().pos + 1.0;
+            let mut next = body.next_mut();
+            next.as_mut().pos = moved;
+            next.as_mut().trail.push(moved);
+        }
+    }
+
+    fn previous(spawner: &Spawner) -> Vec<f32> {
+        spawner
+            .get_buffered_query::<Body>(1)
+            .unwrap()
+            .map(|mut body| body.previous().as_ref().pos)
+            .collect()
+    }
+
+    #[test]
+    fn previous_lags_a_frame() {
+        let mut spawner = spawner();
+        step(&spawner);
+        // running a system again in the same frame reads the same values
+        step(&spawner);
+        assert_eq!(vec![1.0, 2.0, 3.0], previous(&spawner));
+
+        let mut query: Query<Body> = spawner.get_query(MASTER_THREAD).unwrap();
+        let next = query.next(MASTER_THREAD).unwrap();
+        assert_eq!(
+            (2.0, vec![2.0, 2.0]),
+            (next.as_ref().pos, next.as_ref().trail.clone())
+        );
+        drop(next);
+
+        spawner.swap_buffers();
+        assert_eq!(vec![2.0, 3.0, 4.0], previous(&spawner));
+        step(&spawner);
+        spawner.swap_buffers();
+        assert_eq!(vec![3.0, 4.0, 5.0], previous(&spawner));
+    }
+
+    #[test]
+    fn reads_ignore_writers() {
+        let spawner = spawner();
+        let mut query = spawner.get_buffered_query::<Body>(1).unwrap();
+        let mut body = query.next().unwrap();
+        let mut next = body.next_mut();
+        next.as_mut().pos = 10.0;
+        // the writer is still held, but previous() doesn't wait on it
+        assert_eq!(1.0, body.previous().as_ref().pos);
+        drop(next);
+    }
+
+    #[test]
+    fn only_registered_components_are_buffered() {
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Body>();
+        assert!(spawner.get_buffered_query::<Body>(0).is_none());
+        let query: Query<Body> = spawner.get_query(0).unwrap();
+        assert!(query.buffered().is_none());
+
+        let spawner = self::spawner();
+        let query: Query<Body> = spawner.get_query(0).unwrap();
+        assert!(query.buffered().is_some());
+    }
+
+    #[test]
+    fn despawned_objects_leave_the_buffer() {
+        let mut spawner = Spawner::new();
+        spawner.register_double_buffered::<Body>();
+        let ids: Vec<_> = (0..3)
+            .map(|i| spawner.spawn_obj(body(i as f32)).unwrap())
+            .collect();
+        spawner.despawn(ids[1]).unwrap();
+        step(&spawner);
+        spawner.remove_despawned().unwrap();
+        spawner.swap_buffers();
+        // each previous copy still matches its object
+        assert_eq!(vec![1.0, 3.0], previous(&spawner));
+        assert_eq!(4, spawner.stats().live_slots);
+    }
+
+    #[test]
+    fn restored_components_stay_buffered() {
+        let mut spawner = Spawner::new();
+        spawner.register_serializable::<u32>();
+        spawner.register_double_buffered::<u32>();
+        spawner.spawn_batch([4u32, 5]).unwrap();
+
+        let restored = spawner.restore(&spawner.snapshot().unwrap()).unwrap();
+        let mut query = restored.get_buffered_query::<u32>(1).unwrap();
+        assert_eq!(4, *query.next().unwrap().previous().as_ref());
+        assert_eq!(5, *query.next().unwrap().previous().as_ref());
+    }
+}
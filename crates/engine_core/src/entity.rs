@@ -1,31 +1,164 @@
 use std::any::TypeId;
 
-use frosty_alloc::{AllocId, FrostyAllocatable, ObjectHandle};
+use frosty_alloc::{
+    AllocError, DataAccess, Erased, FrostyAllocatable, ObjectHandle, ObjectHandleMut, WeakHandle,
+};
 use hashbrown::HashMap;
 
-type ComponentLocations = HashMap<TypeId, usize>;
+use crate::MASTER_THREAD;
+
+// index of each component in Entity.comps
+pub(crate) type ComponentIndices = HashMap<TypeId, usize>;
+
+// Where each component of a spawned Entity was allocated
+pub struct ComponentLocations {
+    handles: HashMap<TypeId, ObjectHandleMut<Erased>>,
+}
+
+impl ComponentLocations {
+    pub(crate) fn new() -> Self {
+        Self {
+            handles: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, id: TypeId, handle: ObjectHandleMut<Erased>) {
+        self.handles.insert(id, handle);
+    }
+
+    pub(crate) fn get_raw(&self, id: &TypeId) -> Option<&ObjectHandleMut<Erased>> {
+        self.handles.get(id)
+    }
+
+    pub(crate) fn into_handles(self) -> impl Iterator<Item = (TypeId, ObjectHandleMut<Erased>)> {
+        self.handles.into_iter()
+    }
+
+    pub fn get<T: FrostyAllocatable>(&self) -> Option<ObjectHandle<T>> {
+        Some(self.get_mut::<T>()?.read_only())
+    }
+
+    pub fn get_mut<T: FrostyAllocatable>(&self) -> Option<ObjectHandleMut<T>> {
+        self.handles.get(&T::id()).map(|handle| handle.cast_clone())
+    }
+}
 
 // A trait that indicates one component
 // references another component owned by
 // the same Entity
 pub trait ReferencesSiblingComponent: FrostyAllocatable {
-    // The AllocId's of the references objects
-    const REFERENCE_IDS: &'static [AllocId];
+    // The components referenced. An Entity missing
+    // any of them can't be spawned
+    fn sibling_ids() -> Vec<TypeId>
+    where
+        Self: Sized;
     // Update references based on new location of
     // all components stored by Entity
     fn update_references(&mut self, locs: &ComponentLocations);
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiblingError {
+    // the Entity holding the component hasn't been spawned,
+    // or didn't have the sibling
+    Unlinked,
+    Alloc(AllocError),
+}
+
+impl From<AllocError> for SiblingError {
+    fn from(value: AllocError) -> Self {
+        Self::Alloc(value)
+    }
+}
+
+impl std::fmt::Display for SiblingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unlinked => write!(f, "sibling component has not been linked"),
+            Self::Alloc(e) => write!(f, "failed to access sibling component: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SiblingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Unlinked => None,
+            Self::Alloc(e) => Some(e),
+        }
+    }
+}
+
 // A reference to a component stored in the same
 // entity. This is how a component object should
+// hold onto its siblings. The reference is weak, so
+// it doesn't keep a removed sibling's slot around
 pub struct SiblingComponent<T: FrostyAllocatable> {
-    handle: Option<ObjectHandle<T>>,
+    handle: Option<WeakHandle<T>>,
 }
 
 impl<T: FrostyAllocatable> SiblingComponent<T> {
+    // Unlinked until its Entity is spawned
     pub fn new() -> Self {
-        todo!()
+        Self { handle: None }
+    }
+
+    // Point at the T in (locs). Should be called
+    // from update_references()
+    pub fn update(&mut self, locs: &ComponentLocations) {
+        self.handle = locs.get_mut::<T>().map(|handle| handle.downgrade());
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.handle.is_some()
     }
+
+    // Blocks until no thread is writing to the sibling
+    pub fn get(&mut self, thread: u32) -> Result<DataAccess<T>, SiblingError> {
+        Ok(self.upgrade()?.get_access(thread)?)
+    }
+
+    // Returns WouldBlock instead of waiting if the sibling is locked
+    pub fn try_get(&mut self, thread: u32) -> Result<DataAccess<T>, SiblingError> {
+        Ok(self.upgrade()?.try_get_access(thread)?)
+    }
+
+    // HandleFreed once the sibling has been removed
+    fn upgrade(&self) -> Result<ObjectHandle<T>, SiblingError> {
+        let handle = self.handle.as_ref().ok_or(SiblingError::Unlinked)?;
+        match handle.upgrade() {
+            Some(handle) => Ok(handle.read_only()),
+            None => Err(AllocError::HandleFreed.into()),
+        }
+    }
+}
+
+impl<T: FrostyAllocatable> Default for SiblingComponent<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Calls update_references() on a component once it is allocated
+pub(crate) type LinkFn =
+    fn(&ObjectHandleMut<Erased>, &ComponentLocations) -> Result<(), AllocError>;
+
+pub(crate) fn link<T: ReferencesSiblingComponent>(
+    handle: &ObjectHandleMut<Erased>,
+    locs: &ComponentLocations,
+) -> Result<(), AllocError> {
+    let mut handle = handle.cast_clone::<T>();
+    handle
+        .get_access_mut(MASTER_THREAD)?
+        .as_mut()
+        .update_references(locs);
+    Ok(())
+}
+
+pub(crate) struct SiblingLink {
+    pub comp: TypeId,
+    pub siblings: Vec<TypeId>,
+    pub update: LinkFn,
 }
 
 // An entity is essentially an object
@@ -36,8 +169,9 @@ impl<T: FrostyAllocatable> SiblingComponent<T> {
 // to an Entity at any time up to when it is
 // added to the Allocator
 pub struct Entity {
-    locations: ComponentLocations,
+    locations: ComponentIndices,
     comps: Vec<Box<dyn FrostyAllocatable>>,
+    links: Vec<SiblingLink>,
 }
 
 impl Entity {
@@ -45,6 +179,7 @@ impl Entity {
         Self {
             locations: HashMap::new(),
             comps: Vec::new(),
+            links: Vec::new(),
         }
     }
 
@@ -65,11 +200,140 @@ impl Entity {
     where
         T: ReferencesSiblingComponent,
     {
-        todo!()
+        self.add(comp);
+        self.links.retain(|link| link.comp != T::id());
+        self.links.push(SiblingLink {
+            comp: T::id(),
+            siblings: T::sibling_ids(),
+            update: link::<T>,
+        });
     }
 
     // Drop entity while returning components
-    pub(crate) fn dissolve(self) -> (ComponentLocations, Vec<Box<dyn FrostyAllocatable>>) {
-        (self.locations, self.comps)
+    pub(crate) fn dissolve(
+        self,
+    ) -> (
+        ComponentIndices,
+        Vec<Box<dyn FrostyAllocatable>>,
+        Vec<SiblingLink>,
+    ) {
+        (self.locations, self.comps, self.links)
+    }
+}
+
+#[cfg(test)]
+mod entity_tests {
+    use std::any::TypeId;
+
+    use frosty_alloc::{AllocError, FrostyAllocatable};
+
+    use super::{ComponentLocations, ReferencesSiblingComponent, SiblingComponent, SiblingError};
+    use crate::{query::Query, Entity, SpawnError, Spawner};
+
+    #[derive(FrostyAllocatable)]
+    struct Transform {
+        pos: [f32; 2],
+    }
+
+    #[derive(FrostyAllocatable)]
+    struct Health {
+        hp: u32,
+        transform: SiblingComponent<Transform>,
+    }
+
+    impl ReferencesSiblingComponent for Health {
+        fn sibling_ids() -> Vec<TypeId> {
+            vec![Transform::id()]
+        }
+        fn update_references(&mut self, locs: &ComponentLocations) {
+            self.transform.update(locs);
+        }
+    }
+
+    fn health(hp: u32) -> Health {
+        Health {
+            hp,
+            transform: SiblingComponent::new(),
+        }
+    }
+
+    fn spawner() -> Spawner {
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Transform>();
+        spawner.register_component::<Health>();
+        spawner
+    }
+
+    #[test]
+    fn siblings_are_linked_on_spawn() {
+        let mut spawner = spawner();
+        for i in 0..3 {
+            let mut entity = Entity::new();
+            entity.add_with_siblings(health(i));
+            entity.add(Transform {
+                pos: [i as f32, 0.0],
+            });
+            spawner.spawn(entity).unwrap();
+        }
+
+        let mut transforms: Query<Transform> = spawner.get_query(0).unwrap();
+        transforms.next(0).unwrap().as_mut().pos[1] = 5.0;
+
+        let mut healths: Query<Health> = spawner.get_query(0).unwrap();
+        for i in 0..3 {
+            let mut health = healths.next(0).unwrap();
+            assert_eq!(i, health.as_ref().hp);
+            let transform = health.as_mut().transform.get(1).unwrap();
+            let y = if i == 0 { 5.0 } else { 0.0 };
+            assert_eq!([i as f32, y], transform.as_ref().pos);
+        }
+    }
+
+    #[test]
+    fn missing_siblings_fail() {
+        let mut spawner = spawner();
+        let mut entity = Entity::new();
+        entity.add_with_siblings(health(1));
+        assert!(matches!(
+            spawner.spawn(entity),
+            Err(SpawnError::MissingSibling { component, sibling })
+                if component == Health::id() && sibling == Transform::id()
+        ));
+        // nothing was spawned
+        assert_eq!(0, spawner.stats().live_slots);
+
+        // spawned on its own, the reference is never linked
+        spawner.spawn_obj(health(2)).unwrap();
+        let mut healths: Query<Health> = spawner.get_query(0).unwrap();
+        let mut health = healths.next(0).unwrap();
+        assert!(!health.as_ref().transform.is_linked());
+        assert!(matches!(
+            health.as_mut().transform.get(0),
+            Err(SiblingError::Unlinked)
+        ));
+    }
+
+    #[test]
+    fn removed_siblings_fail() {
+        let mut spawner = spawner();
+        let mut entity = Entity::new();
+        entity.add_with_siblings(health(1));
+        entity.add(Transform { pos: [2.0, 0.0] });
+        let id = spawner.spawn(entity).unwrap();
+
+        spawner.remove_component::<Transform>(id).unwrap();
+        spawner.remove_despawned().unwrap();
+        // the reference didn't keep the transform's slot around
+        let slots = spawner.stats().freed_slots;
+        spawner.spawn_obj(Transform { pos: [0.0; 2] }).unwrap();
+        assert_eq!(slots - 1, spawner.stats().freed_slots);
+
+        let mut health = spawner.get_component::<Health>(id).unwrap();
+        let mut health = health.get_access_mut(0).unwrap();
+        assert!(health.as_ref().transform.is_linked());
+        assert!(matches!(
+            health.as_mut().transform.get(0),
+            Err(SiblingError::Alloc(AllocError::HandleFreed))
+        ));
     }
 }
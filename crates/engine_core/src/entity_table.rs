@@ -0,0 +1,276 @@
+use std::any::TypeId;
+
+use frosty_alloc::{Erased, ObjectHandleMut, ObjectKey};
+use hashbrown::HashMap;
+
+// Names a spawned entity. Slots in the table are reused once
+// an entity is gone, so (generation) tells an old id apart
+// from the entity now living in its slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EntityId {
+    index: u32,
+    generation: u32,
+}
+
+impl EntityId {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+impl std::fmt::Display for EntityId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+struct Slot {
+    generation: u32,
+    // None while the slot is free
+    comps: Option<HashMap<TypeId, ObjectHandleMut<Erased>>>,
+}
+
+// The components of every entity spawned by a Spawner
+pub(crate) struct EntityTable {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    // the entity each component belongs to
+    owners: HashMap<ObjectKey, EntityId>,
+}
+
+impl EntityTable {
+    pub fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+            owners: HashMap::new(),
+        }
+    }
+
+    pub fn insert<I>(&mut self, comps: I) -> EntityId
+    where
+        I: IntoIterator<Item = (TypeId, ObjectHandleMut<Erased>)>,
+    {
+        let comps: HashMap<_, _> = comps.into_iter().collect();
+        let id = match self.free.pop() {
+            Some(index) => EntityId {
+                index,
+                generation: self.slots[index as usize].generation,
+            },
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    comps: None,
+                });
+                EntityId {
+                    index: self.slots.len() as u32 - 1,
+                    generation: 0,
+                }
+            }
+        };
+        for handle in comps.values() {
+            self.owners.insert(handle.key(), id);
+        }
+        self.slots[id.index as usize].comps = Some(comps);
+        id
+    }
+
+    // None if the entity is gone
+    pub fn get(&self, id: EntityId) -> Option<&HashMap<TypeId, ObjectHandleMut<Erased>>> {
+        let slot = self.slots.get(id.index as usize)?;
+        match slot.generation == id.generation {
+            true => slot.comps.as_ref(),
+            false => None,
+        }
+    }
+
+    // Forget (id), handing back its components. The slot is
+    // reused under the next generation
+    pub fn remove(&mut self, id: EntityId) -> Option<HashMap<TypeId, ObjectHandleMut<Erased>>> {
+        self.get(id)?;
+        let slot = &mut self.slots[id.index as usize];
+        let comps = slot.comps.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(id.index);
+        for handle in comps.values() {
+            self.owners.remove(&handle.key());
+        }
+        Some(comps)
+    }
+
+    pub fn remove_component(
+        &mut self,
+        id: EntityId,
+        comp: &TypeId,
+    ) -> Option<ObjectHandleMut<Erased>> {
+        let slot = self.slots.get_mut(id.index as usize)?;
+        if slot.generation != id.generation {
+            return None;
+        }
+        let handle = slot.comps.as_mut()?.remove(comp)?;
+        self.owners.remove(&handle.key());
+        Some(handle)
+    }
+
+    pub fn entity_of(&self, key: &ObjectKey) -> Option<EntityId> {
+        self.owners.get(key).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    // The generation and components of every slot, free or not
+    pub fn slots(
+        &self,
+    ) -> impl Iterator<Item = (u32, Option<&HashMap<TypeId, ObjectHandleMut<Erased>>>)> {
+        self.slots.iter().map(|s| (s.generation, s.comps.as_ref()))
+    }
+
+    // Rebuild a table from what slots() gave, so every
+    // EntityId stays the same
+    pub fn from_slots<I, C>(slots: I) -> Self
+    where
+        I: IntoIterator<Item = (u32, Option<C>)>,
+        C: IntoIterator<Item = (TypeId, ObjectHandleMut<Erased>)>,
+    {
+        let mut table = Self::new();
+        for (index, (generation, comps)) in slots.into_iter().enumerate() {
+            let id = EntityId {
+                index: index as u32,
+                generation,
+            };
+            let comps: Option<HashMap<_, _>> = comps.map(|c| c.into_iter().collect());
+            match &comps {
+                Some(comps) => {
+                    for handle in comps.values() {
+                        table.owners.insert(handle.key(), id);
+                    }
+                }
+                None => table.free.push(id.index),
+            }
+            table.slots.push(Slot { generation, comps });
+        }
+        // reuse the lowest slots first
+        table.free.reverse();
+        table
+    }
+}
+
+#[cfg(test)]
+mod entity_table_tests {
+    use frosty_alloc::FrostyAllocatable;
+
+    use crate::{DespawnError, Entity, EntityId, Spawner};
+
+    #[derive(FrostyAllocatable)]
+    struct Health(u32);
+
+    #[derive(FrostyAllocatable)]
+    struct Name(&'static str);
+
+    fn spawn(spawner: &mut Spawner, hp: u32, name: &'static str) -> EntityId {
+        let mut entity = Entity::new();
+        entity.add(Health(hp));
+        entity.add(Name(name));
+        spawner.spawn(entity).unwrap()
+    }
+
+    #[test]
+    fn components_are_found_by_entity() {
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Health>();
+        spawner.register_component::<Name>();
+
+        let gull = spawn(&mut spawner, 10, "gull");
+        let crab = spawn(&mut spawner, 4, "crab");
+        let lone = spawner.spawn_obj(Health(1)).unwrap();
+        assert_ne!(gull, crab);
+        assert_eq!(3, spawner.entity_count());
+
+        // damage the crab
+        let mut health = spawner.get_component::<Health>(crab).unwrap();
+        health.get_access_mut(0).unwrap().as_mut().0 -= 3;
+        let mut name = spawner.get_component::<Name>(crab).unwrap();
+        assert_eq!("crab", name.get_access(0).unwrap().as_ref().0);
+        assert_eq!(Some(crab), spawner.entity_of(&name));
+        assert_eq!(Some(crab), spawner.entity_of(&health));
+
+        let mut healths = spawner.get_query::<Health>(0).unwrap();
+        assert_eq!(10, healths.next(0).unwrap().as_ref().0);
+        assert_eq!(1, healths.next(0).unwrap().as_ref().0);
+
+        assert!(spawner.get_component::<Name>(lone).is_none());
+        assert_eq!(
+            Some(lone),
+            spawner.entity_of(&spawner.get_component::<Health>(lone).unwrap())
+        );
+    }
+
+    fn names(spawner: &Spawner) -> Vec<&'static str> {
+        let mut query = spawner.get_query::<Name>(0).unwrap();
+        let mut out = Vec::new();
+        while let Some(name) = query.next(0) {
+            out.push(name.as_ref().0);
+        }
+        out
+    }
+
+    #[test]
+    fn despawns_wait_for_the_barrier() {
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Health>();
+        spawner.register_component::<Name>();
+        let gull = spawn(&mut spawner, 10, "gull");
+        let crab = spawn(&mut spawner, 4, "crab");
+        let seal = spawn(&mut spawner, 7, "seal");
+        let mut crab_health = spawner.get_component::<Health>(crab).unwrap();
+
+        spawner.despawn(crab).unwrap();
+        spawner.remove_component::<Health>(seal).unwrap();
+        assert_eq!(Err(DespawnError::NoEntity(crab)), spawner.despawn(crab));
+        assert!(matches!(
+            spawner.remove_component::<Health>(seal),
+            Err(DespawnError::MissingComponent { .. })
+        ));
+        assert!(spawner.get_component::<Name>(crab).is_none());
+        assert_eq!(2, spawner.entity_count());
+
+        // Querys still hold everything until the end of the frame
+        assert_eq!(vec!["gull", "crab", "seal"], names(&spawner));
+        assert_eq!(4, crab_health.get_access(0).unwrap().as_ref().0);
+        assert_eq!(6, spawner.stats().live_slots);
+
+        spawner.remove_despawned().unwrap();
+        assert_eq!(vec!["gull", "seal"], names(&spawner));
+        assert_eq!(3, spawner.stats().live_slots);
+        assert!(crab_health.get_access(0).is_err());
+        let mut healths = spawner.get_query::<Health>(0).unwrap();
+        assert_eq!(10, healths.next(0).unwrap().as_ref().0);
+        assert!(healths.next(0).is_none());
+
+        // the crab's slot is reused, but its id stays dead
+        let shark = spawn(&mut spawner, 20, "shark");
+        assert_eq!(crab.index(), shark.index());
+        assert_ne!(crab, shark);
+        assert!(spawner.get_component::<Health>(crab).is_none());
+        assert!(spawner.get_component::<Name>(seal).is_some());
+        assert!(spawner.get_component::<Health>(gull).is_some());
+    }
+
+    #[test]
+    fn failed_spawns_have_no_entity() {
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Health>();
+        assert!(spawner.spawn_obj(Name("ghost")).is_err());
+        let mut entity = Entity::new();
+        entity.add(Health(1));
+        entity.add(Name("ghost"));
+        assert!(spawner.spawn(entity).is_err());
+        assert_eq!(0, spawner.entity_count());
+    }
+}
@@ -0,0 +1,299 @@
+use std::{any::TypeId, cell::OnceCell, marker::PhantomData};
+
+use frosty_alloc::{Erased, FrostyAllocatable, ObjectHandleMut};
+use hashbrown::HashMap;
+
+use crate::entity_table::EntityTable;
+
+/*
+ * Query filters
+ *
+ * A Query<T, F> only returns the rows which pass F, ie.
+ *      Query<&mut Position, (With<Player>, Without<Frozen>)>
+ *
+ * With and Without look at the other components of the entity
+ * a row belongs to. Objects without an entity only have
+ * themselves.
+ *
+ * Added and Changed use the ticks of the Spawner, which advance
+ * once every frame (see Spawner::advance_tick). They pass if the
+ * component was allocated or written to during the last frame, so
+ * every system sees each change exactly once, no matter whether
+ * it runs before or after the system which made it.
+ */
+
+// The components a filter can look at for a single row of a Query
+pub struct FilterRow<'a> {
+    // the component the Query iterates over
+    id: TypeId,
+    handle: &'a ObjectHandleMut<Erased>,
+    entities: Option<&'a EntityTable>,
+    // the rest of the entity, found the first time it's needed
+    comps: OnceCell<Option<&'a HashMap<TypeId, ObjectHandleMut<Erased>>>>,
+    tick: u32,
+}
+
+impl<'a> FilterRow<'a> {
+    pub(crate) fn new(
+        id: TypeId,
+        handle: &'a ObjectHandleMut<Erased>,
+        entities: Option<&'a EntityTable>,
+        tick: u32,
+    ) -> Self {
+        Self {
+            id,
+            handle,
+            entities,
+            comps: OnceCell::new(),
+            tick,
+        }
+    }
+
+    // The component (id) of the same entity, if it has one
+    pub fn get(&self, id: &TypeId) -> Option<&'a ObjectHandleMut<Erased>> {
+        if *id == self.id {
+            return Some(self.handle);
+        }
+        let comps = self.comps.get_or_init(|| {
+            let entities = self.entities?;
+            entities.get(entities.entity_of(&self.handle.key())?)
+        });
+        comps.as_ref()?.get(id)
+    }
+
+    // The current tick of the Spawner
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    // The tick of the frame before this one
+    pub fn last_frame(&self) -> u32 {
+        self.tick.wrapping_sub(1)
+    }
+}
+
+// Decides which rows a Query returns
+pub trait QueryFilter {
+    fn matches(row: &FilterRow) -> bool;
+}
+
+impl QueryFilter for () {
+    fn matches(_: &FilterRow) -> bool {
+        true
+    }
+}
+
+// The entity has a C
+pub struct With<C>(PhantomData<C>);
+
+impl<C: FrostyAllocatable> QueryFilter for With<C> {
+    fn matches(row: &FilterRow) -> bool {
+        row.get(&C::id()).is_some()
+    }
+}
+
+// The entity doesn't have a C
+pub struct Without<C>(PhantomData<C>);
+
+impl<C: FrostyAllocatable> QueryFilter for Without<C> {
+    fn matches(row: &FilterRow) -> bool {
+        row.get(&C::id()).is_none()
+    }
+}
+
+// The entity's C was spawned last frame
+pub struct Added<C>(PhantomData<C>);
+
+impl<C: FrostyAllocatable> QueryFilter for Added<C> {
+    fn matches(row: &FilterRow) -> bool {
+        row.get(&C::id())
+            .and_then(|handle| handle.ticks().ok())
+            .is_some_and(|ticks| ticks.added == row.last_frame())
+    }
+}
+
+// The entity's C was written to last frame. Spawning counts
+// as a write
+pub struct Changed<C>(PhantomData<C>);
+
+impl<C: FrostyAllocatable> QueryFilter for Changed<C> {
+    fn matches(row: &FilterRow) -> bool {
+        row.get(&C::id())
+            .and_then(|handle| handle.ticks().ok())
+            .is_some_and(|ticks| ticks.changed_at(row.last_frame()))
+    }
+}
+
+// A tuple of filters passes if all of them do
+macro_rules! impl_filter {
+    ($($param:ident),+) => {
+        impl<$($param: QueryFilter),+> QueryFilter for ($($param,)+) {
+            fn matches(row: &FilterRow) -> bool {
+                $($param::matches(row))&&+
+            }
+        }
+    };
+}
+
+impl_filter!(A);
+impl_filter!(A, B);
+impl_filter!(A, B, C);
+impl_filter!(A, B, C, D);
+
+#[cfg(test)]
+mod filter_tests {
+    use frosty_alloc::FrostyAllocatable;
+
+    use super::{Added, Changed, With, Without};
+    use crate::{query::Query, Entity, Spawner};
+
+    #[derive(FrostyAllocatable)]
+    struct Position(i32);
+
+    #[derive(FrostyAllocatable)]
+    struct Player(u32);
+
+    #[derive(FrostyAllocatable)]
+    struct Frozen(bool);
+
+    #[derive(FrostyAllocatable, Clone)]
+    struct Speed(i32);
+
+    fn positions<F: super::QueryFilter>(mut query: Query<Position, F>) -> Vec<i32> {
+        let mut out = Vec::new();
+        while let Some(pos) = query.next(0) {
+            out.push(pos.as_ref().0);
+        }
+        out
+    }
+
+    fn spawner() -> Spawner {
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Position>();
+        spawner.register_component::<Player>();
+        spawner.register_component::<Frozen>();
+        spawner
+    }
+
+    #[test]
+    fn filters_look_at_the_entity() {
+        let mut spawner = spawner();
+        for i in 0..4 {
+            let mut entity = Entity::new();
+            entity.add(Position(i));
+            if i % 2 == 0 {
+                entity.add(Player(i as u32));
+            }
+            if i > 1 {
+                entity.add(Frozen(true));
+            }
+            spawner.spawn(entity).unwrap();
+        }
+        // an entity with nothing but a Position
+        spawner.spawn_batch([Position(9)]).unwrap();
+
+        let players = spawner.get_filtered_query::<Position, With<Player>>(0);
+        assert_eq!(vec![0, 2], positions(players.unwrap()));
+        let moving = spawner.get_filtered_query::<Position, Without<Frozen>>(0);
+        assert_eq!(vec![0, 1, 9], positions(moving.unwrap()));
+        let both = spawner.get_filtered_query::<Position, (With<Player>, Without<Frozen>)>(0);
+        assert_eq!(vec![0], positions(both.unwrap()));
+
+        // tuples are filtered too
+        let mut query = spawner
+            .get_query::<(&Position, &Player)>(0)
+            .unwrap()
+            .filtered::<Without<Frozen>>();
+        let (pos, player) = query.next(0).unwrap();
+        assert_eq!((0, 0), (pos.as_ref().0, player.as_ref().0));
+        drop((pos, player));
+        assert!(query.next(0).is_none());
+        assert!(
+            spawner
+                .get_query::<Frozen>(0)
+                .unwrap()
+                .next(0)
+                .unwrap()
+                .as_ref()
+                .0
+        );
+    }
+
+    #[test]
+    fn changes_are_seen_for_one_frame() {
+        let mut spawner = spawner();
+        spawner.spawn_obj(Position(0)).unwrap();
+        spawner.spawn_obj(Position(1)).unwrap();
+        let added = |spawner: &Spawner| {
+            positions(
+                spawner
+                    .get_filtered_query::<Position, Added<Position>>(0)
+                    .unwrap(),
+            )
+        };
+        let changed = |spawner: &Spawner| {
+            positions(
+                spawner
+                    .get_filtered_query::<Position, Changed<Position>>(0)
+                    .unwrap(),
+            )
+        };
+        // nothing has happened last frame yet
+        assert!(added(&spawner).is_empty());
+
+        spawner.advance_tick();
+        spawner.spawn_obj(Position(2)).unwrap();
+        assert_eq!(vec![0, 1], added(&spawner));
+        assert_eq!(vec![0, 1], changed(&spawner));
+        // reading doesn't count as a change
+        let mut query: Query<Position> = spawner.get_query(0).unwrap();
+        query.next(0).unwrap();
+        query.next(0).unwrap().as_mut().0 = 10;
+
+        spawner.advance_tick();
+        assert_eq!(vec![2], added(&spawner));
+        assert_eq!(vec![10, 2], changed(&spawner));
+
+        // a write last frame isn't hidden by one this frame
+        let mut query: Query<Position> = spawner.get_query(0).unwrap();
+        query.next(0).unwrap().as_mut().0 = 20;
+        assert_eq!(vec![10, 2], changed(&spawner));
+
+        spawner.advance_tick();
+        assert_eq!(vec![20], changed(&spawner));
+        assert!(added(&spawner).is_empty());
+    }
+    #[test]
+    fn swapping_buffers_isnt_a_change() {
+        let mut spawner = Spawner::new();
+        spawner.register_double_buffered::<Speed>();
+        spawner.spawn_obj(Speed(0)).unwrap();
+        spawner.spawn_obj(Speed(1)).unwrap();
+        let changed = |spawner: &Spawner| {
+            let mut query = spawner
+                .get_filtered_query::<Speed, Changed<Speed>>(0)
+                .unwrap();
+            let mut out = Vec::new();
+            while let Some(speed) = query.next(0) {
+                out.push(speed.as_ref().0);
+            }
+            out
+        };
+
+        // frames end with a swap, then the tick moves on
+        spawner.swap_buffers();
+        spawner.advance_tick();
+        assert_eq!(vec![0, 1], changed(&spawner));
+        let mut query: Query<Speed> = spawner.get_query(0).unwrap();
+        query.next(0).unwrap().as_mut().0 = 5;
+        drop(query);
+
+        spawner.swap_buffers();
+        spawner.advance_tick();
+        assert_eq!(vec![5], changed(&spawner));
+
+        spawner.swap_buffers();
+        spawner.advance_tick();
+        assert!(changed(&spawner).is_empty());
+    }
+}
@@ -1,21 +1,35 @@
 #![feature(unsize)]
 #![feature(impl_trait_in_bindings)]
 
+// #[frosty(serialize)] names the crate by path, even in here
+extern crate self as engine_core;
+
 mod concur;
 mod schedule;
 mod thread;
+pub use thread::spawn_local;
 
 #[cfg(not(feature = "no-app"))]
 pub mod app;
 pub use app::App;
 
+mod buffered;
+pub use buffered::{Buffered, BufferedQuery};
 mod entity;
-pub use entity::Entity;
+pub use entity::{
+    ComponentLocations, Entity, ReferencesSiblingComponent, SiblingComponent, SiblingError,
+};
+mod entity_table;
+pub use entity_table::EntityId;
+mod filter;
+pub use filter::{Added, Changed, FilterRow, QueryFilter, With, Without};
 pub mod query;
 mod scene;
 pub use scene::{Scene, SceneBuilder};
 mod spawner;
-pub use spawner::Spawner;
+pub use spawner::{DespawnError, SpawnError, Spawner, UnregisteredComponent};
+mod snapshot;
+pub use snapshot::{SerializableComponent, SnapshotError, SnapshotValue};
 pub mod render_core;
 
 pub mod input;
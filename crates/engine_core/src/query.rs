@@ -1,6 +1,19 @@
-use std::marker::{PhantomData, Unsize};
-
-use frosty_alloc::{DataAccessMut, DynObjectHandle, FrostyAllocatable, ObjectHandleMut};
+use std::{
+    any::TypeId,
+    marker::{PhantomData, Unsize},
+};
+
+use frosty_alloc::{
+    AllocError, Allocator, DataAccessMut, DynObjectHandle, Erased, Fetch, FrostyAllocatable,
+    ObjectHandleMut, ObjectKey,
+};
+use hashbrown::HashSet;
+
+use crate::{
+    buffered::{BackBuffer, BufferedQuery},
+    entity_table::EntityTable,
+    filter::{FilterRow, QueryFilter},
+};
 
 #[derive(Clone)]
 pub(crate) enum QueryForm {
@@ -14,36 +27,77 @@ pub(crate) enum QueryForm {
 // Though it doesn't implement Iter,
 // data can still be cycled through
 // with next()
+//
+// T can also be a tuple of &A and &mut B markers, like
+// Query<(&Velocity, &mut Position)>. Each call to next() then
+// locks the components of one entity which has all of them.
+// The entities are found from the objects of the first
+// component, so entities missing any of the others are
+// skipped
+//
+// F filters which objects or entities are returned, see filter.rs
 #[derive(Copy, Clone)]
-pub struct Query<T>
+pub struct Query<T, F = ()>
 where
-    T: FrostyAllocatable + ?Sized,
+    T: ?Sized,
 {
     raw: *mut RawQuery,
+    // used to join the components of a tuple
+    entities: *const EntityTable,
+    // tick of the Spawner when the query was made
+    tick: u32,
     obj_ptr: usize, // index for iterating
     pub(crate) thread: u32,
     _pd: PhantomData<T>,
+    _filter: PhantomData<F>,
 }
 
 impl<T> Query<T>
 where
     T: FrostyAllocatable,
 {
-    pub(crate) fn new(raw: &RawQuery, thread_id: u32) -> Self {
-        Self {
-            raw: raw as *const RawQuery as *mut RawQuery,
-            obj_ptr: 0,
-            thread: thread_id,
-            _pd: PhantomData,
+    // Returns TypeMismatch if the query doesn't hold U's, or
+    // for a tuple if it doesn't hold the first part of U.
+    // Erased can be used to look at any query
+    pub fn try_cast<U: Fetch>(self) -> Result<Query<U>, AllocError> {
+        self.check_type::<U>()?;
+        Ok(self.retype())
+    }
+
+    // SAFETY:
+    //      the query must hold U's. Checked in debug builds
+    #[track_caller]
+    pub unsafe fn cast<U: Fetch>(self) -> Query<U> {
+        if cfg!(debug_assertions) {
+            if let Err(e) = self.check_type::<U>() {
+                panic!(
+                    "unchecked cast to Query<{}>: {}",
+                    std::any::type_name::<U>(),
+                    e
+                );
+            }
         }
+        self.retype()
     }
 
-    pub unsafe fn cast<U: FrostyAllocatable>(self) -> Query<U> {
+    fn check_type<U: Fetch>(&self) -> Result<(), AllocError> {
+        let expected = U::ids()[0];
+        let found = self.type_id();
+        if expected != Erased::id() && expected != found {
+            return Err(AllocError::TypeMismatch { expected, found });
+        }
+        Ok(())
+    }
+
+    fn retype<U: Fetch>(self) -> Query<U> {
         Query {
             raw: self.raw,
+            entities: self.entities,
+            tick: self.tick,
             obj_ptr: self.obj_ptr,
             thread: self.thread,
             _pd: PhantomData,
+            _filter: PhantomData,
         }
     }
 }
@@ -54,7 +108,7 @@ where
 //          this should become atomic
 //      raw
 //
-unsafe impl<T: FrostyAllocatable + Send> Send for Query<T> {}
+unsafe impl<T: Fetch + Send, F> Send for Query<T, F> {}
 
 impl<'a, T> Iterator for &'a mut Query<T>
 where
@@ -86,28 +140,66 @@ where
     }
 }
 
-impl<T: FrostyAllocatable> Query<T> {
-    pub fn next(&mut self, thread: u32) -> Option<DataAccessMut<T>> {
-        let inner_array = unsafe {
-            &mut self
-                .raw
-                .as_mut()
-                .expect("Failed to read from raw query")
-                .objs
-        };
-        if self.obj_ptr == inner_array.len() {
-            return None;
+impl<T: Fetch, F: QueryFilter> Query<T, F> {
+    // (raw) holds the first component of T
+    pub(crate) fn new(raw: &RawQuery, entities: &EntityTable, tick: u32, thread_id: u32) -> Self {
+        Self {
+            raw: raw as *const RawQuery as *mut RawQuery,
+            entities,
+            tick,
+            obj_ptr: 0,
+            thread: thread_id,
+            _pd: PhantomData,
+            _filter: PhantomData,
         }
-        let handle = unsafe { inner_array.get_unchecked_mut(self.obj_ptr) };
-        self.obj_ptr += 1;
-        Some(
-            handle
-                .cast_clone()
-                .get_access_mut(self.thread)
-                .expect("Failed to access component data"),
-        )
     }
 
+    pub fn next(&mut self, thread: u32) -> Option<T::Item> {
+        let raw = unsafe { self.raw.as_ref() }.expect("Failed to read from raw query");
+        let entities = unsafe { self.entities.as_ref() };
+        while let Some(first) = raw.objs.get(self.obj_ptr) {
+            self.obj_ptr += 1;
+            let row = FilterRow::new(raw.type_id, first, entities, self.tick);
+            if !F::matches(&row) {
+                continue;
+            }
+            if T::COUNT == 1 {
+                return Some(
+                    T::fetch(std::slice::from_ref(first), self.thread)
+                        .expect("Failed to access component data"),
+                );
+            }
+            // the rest of the tuple comes from the same entity
+            let handles: Option<Vec<_>> = T::ids().iter().map(|id| row.get(id).cloned()).collect();
+            if let Some(handles) = handles {
+                return Some(
+                    T::fetch(&handles, self.thread).expect("Failed to access component data"),
+                );
+            }
+        }
+        None
+    }
+
+    // resets iteration
+    pub fn reset(&mut self) {
+        self.obj_ptr = 0;
+    }
+
+    // The same query, only returning what passes G instead of F
+    pub fn filtered<G: QueryFilter>(self) -> Query<T, G> {
+        Query {
+            raw: self.raw,
+            entities: self.entities,
+            tick: self.tick,
+            obj_ptr: self.obj_ptr,
+            thread: self.thread,
+            _pd: PhantomData,
+            _filter: PhantomData,
+        }
+    }
+}
+
+impl<T: FrostyAllocatable> Query<T> {
     pub fn next_handle(&mut self) -> Option<ObjectHandleMut<T>> {
         let objs = &mut unsafe { self.raw.as_mut() }.unwrap().objs;
         let next = objs.get_mut(self.obj_ptr)?;
@@ -115,9 +207,17 @@ impl<T: FrostyAllocatable> Query<T> {
         Some(next.cast_clone())
     }
 
-    // resets iteration
-    pub fn reset(&mut self) {
-        self.obj_ptr = 0;
+    // TypeId of the components the query holds
+    pub fn type_id(&self) -> TypeId {
+        unsafe { self.raw.as_ref() }
+            .expect("Failed to read from raw query")
+            .type_id
+    }
+
+    // Look at the objects through their double buffers. Returns
+    // None if T wasn't registered as double buffered
+    pub fn buffered(self) -> Option<BufferedQuery<T>> {
+        BufferedQuery::new(unsafe { self.raw.as_ref() }?, self.thread)
     }
 
     // Consume a Query. Move the pointers stored in the
@@ -146,7 +246,7 @@ impl<T: FrostyAllocatable> Query<T> {
     //      Accesses object handles directly, so need to make sure
     //      RawQuery isn't destroyed
     // Returns none if self.raw fails to return a ref
-    pub unsafe fn as_slice<'a>(self) -> Option<&'a [ObjectHandleMut<u8>]> {
+    pub unsafe fn as_slice<'a>(self) -> Option<&'a [ObjectHandleMut<Erased>]> {
         Some(&self.raw.as_ref()?.objs[..])
     }
 
@@ -213,21 +313,99 @@ where
 // The underlying data beneath a Query.
 pub(crate) struct RawQuery {
     form: QueryForm,
-    objs: Vec<ObjectHandleMut<u8>>,
-    to_drop: Vec<usize>,
+    // component every handle in (objs) points to
+    type_id: TypeId,
+    objs: Vec<ObjectHandleMut<Erased>>,
+    // objects to remove at the end of the frame
+    to_drop: Vec<ObjectKey>,
+    // last frame's copy of each of (objs), if the
+    // component is double buffered
+    pub(crate) buffer: Option<BackBuffer>,
 }
 
 impl RawQuery {
-    pub fn new(form: QueryForm, objs: Vec<ObjectHandleMut<u8>>) -> Self {
+    pub fn new(form: QueryForm, type_id: TypeId, objs: Vec<ObjectHandleMut<Erased>>) -> Self {
         Self {
             form,
+            type_id,
             objs,
             to_drop: Vec::new(),
+            buffer: None,
         }
     }
 
-    pub(crate) fn add_handle(&mut self, handle: ObjectHandleMut<u8>) {
+    // A query for the same component without any objects
+    pub(crate) fn empty_like(&self) -> Self {
+        Self {
+            buffer: self.buffer.as_ref().map(BackBuffer::empty_like),
+            ..Self::new(self.form.clone(), self.type_id, Vec::new())
+        }
+    }
+
+    // (alloc) is only used to copy the object into its
+    // double buffer, if the component has one
+    pub(crate) fn add_handle(
+        &mut self,
+        handle: ObjectHandleMut<Erased>,
+        alloc: &mut Allocator,
+    ) -> Result<(), AllocError> {
+        if let Some(buffer) = &mut self.buffer {
+            buffer.push_copy(&handle, alloc)?;
+        }
         self.objs.push(handle);
+        Ok(())
+    }
+
+    // Remove (handle) once drop_queued() is run
+    pub(crate) fn queue_drop(&mut self, handle: &ObjectHandleMut<Erased>) {
+        self.to_drop.push(handle.key());
+    }
+
+    // Remove and free the objects queued by queue_drop(), along
+    // with their double buffered copies. Querys on worker threads
+    // index into (objs), so this only runs between frames
+    pub(crate) fn drop_queued(&mut self, alloc: &mut Allocator) -> Result<(), AllocError> {
+        if self.to_drop.is_empty() {
+            return Ok(());
+        }
+        let dropped: HashSet<ObjectKey> = self.to_drop.drain(..).collect();
+        let keep: Vec<bool> = self
+            .objs
+            .iter()
+            .map(|handle| !dropped.contains(&handle.key()))
+            .collect();
+        let mut result = match &mut self.buffer {
+            Some(buffer) => buffer.retain(&keep, alloc),
+            None => Ok(()),
+        };
+        for (mut handle, keep) in std::mem::take(&mut self.objs).into_iter().zip(keep) {
+            match keep {
+                true => self.objs.push(handle),
+                false => result = result.and(alloc.free(&mut handle)),
+            }
+        }
+        result
+    }
+
+    // Make what was written this frame the previous value
+    pub(crate) fn commit_buffer(&mut self) {
+        if let Some(buffer) = &mut self.buffer {
+            buffer.commit(&self.objs);
+        }
+    }
+
+    pub(crate) fn handles(&self) -> &[ObjectHandleMut<Erased>] {
+        &self.objs
+    }
+
+    // Handles to objects which are still spawned. Objects queued
+    // by queue_drop() stay in (objs) until the end of the frame,
+    // but their entity is already gone
+    pub(crate) fn spawned(&self) -> impl Iterator<Item = &ObjectHandleMut<Erased>> {
+        let dropped: HashSet<ObjectKey> = self.to_drop.iter().copied().collect();
+        self.objs
+            .iter()
+            .filter(move |handle| handle.is_live() && !dropped.contains(&handle.key()))
     }
 }
 
@@ -235,9 +413,10 @@ impl RawQuery {
 mod query_tests {
     use std::marker::PhantomData;
 
-    use frosty_alloc::{Allocator, FrostyAllocatable};
+    use frosty_alloc::{Allocator, Erased, FrostyAllocatable};
 
     use super::{Query, QueryForm, RawQuery};
+    use crate::{Entity, Spawner};
 
     trait HasData: FrostyAllocatable {
         fn get_data(&self) -> i32;
@@ -263,13 +442,17 @@ mod query_tests {
 
         let mut raw_query = RawQuery::new(
             QueryForm::Continuous,
+            Dummy::id(),
             vec![unsafe { dummy_handle.dissolve_data() }],
         );
         let query: Query<Dummy> = Query {
             raw: &mut raw_query as *mut RawQuery,
+            entities: std::ptr::null(),
+            tick: 0,
             obj_ptr: 0,
             thread: 0,
             _pd: PhantomData,
+            _filter: PhantomData,
         };
 
         let mut dyn_query = query.cast_dyn::<dyn HasData>();
@@ -281,4 +464,50 @@ mod query_tests {
             .get_data();
         assert_eq!(3, num);
     }
+
+    #[test]
+    fn tuples_skip_incomplete_entities() {
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Dummy>();
+        spawner.register_component::<u32>();
+        let mut ids = Vec::new();
+        for i in 0..4 {
+            let mut entity = Entity::new();
+            entity.add(Dummy { data: i });
+            if i != 1 {
+                entity.add(i as u32 * 10);
+            }
+            ids.push(spawner.spawn(entity).unwrap());
+        }
+        // has no u32, so never joined
+        spawner.spawn_batch([Dummy { data: 9 }]).unwrap();
+        spawner.despawn(ids[3]).unwrap();
+
+        let mut query = spawner.get_query::<(&Dummy, &mut u32)>(0).unwrap();
+        let mut seen = Vec::new();
+        while let Some((dummy, mut num)) = query.next(0) {
+            *num.as_mut() += 1;
+            seen.push((dummy.as_ref().data, *num.as_ref()));
+        }
+        assert_eq!(vec![(0, 1), (2, 21)], seen);
+
+        query.reset();
+        assert_eq!(1, *query.next(0).unwrap().1.as_ref());
+    }
+
+    #[test]
+    fn only_erased_queries_cast_to_anything() {
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Dummy>();
+        spawner.spawn_obj(Dummy { data: 5 }).unwrap();
+
+        let erased = spawner.get_query_by_id(&Dummy::id(), 0).unwrap();
+        let mut dummies = erased.try_cast::<Dummy>().unwrap();
+        assert_eq!(5, dummies.next(0).unwrap().as_ref().data);
+
+        // u8 is just a u8, not a wildcard
+        let dummies = spawner.get_query::<Dummy>(0).unwrap();
+        assert!(dummies.try_cast::<u8>().is_err());
+        assert!(dummies.try_cast::<Erased>().is_ok());
+    }
 }
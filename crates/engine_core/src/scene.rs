@@ -2,7 +2,8 @@ use frosty_alloc::FrostyAllocatable;
 use render::window_state::WindowState;
 
 use crate::{
-    render_core::DynamicRenderPipeline, schedule::Schedule, system::SystemInterface, Spawner,
+    render_core::DynamicRenderPipeline, schedule::Schedule, spawner::SpawnError,
+    system::SystemInterface, Spawner,
 };
 
 // A Scene defines which entities are available, which systems are active, and how rendering should occur.
@@ -81,13 +82,23 @@ impl SceneBuilder {
     }
 
     pub fn spawn_component<C: 'static + FrostyAllocatable>(mut self, comp: C) -> Self {
+        self.try_spawn_component(comp)
+            .expect("Failed to spawn component");
+        self
+    }
+
+    // Same as spawn_component, but hands back the error if the
+    // component couldn't be allocated. The builder is borrowed
+    // so it can still be used after an error
+    pub fn try_spawn_component<C: 'static + FrostyAllocatable>(
+        &mut self,
+        comp: C,
+    ) -> Result<&mut Self, SpawnError> {
         if !self.alloc.is_registered::<C>() {
             self.alloc.register_component::<C>();
         }
-        self.alloc
-            .spawn_obj(comp)
-            .expect("Component appears registered but is not");
-        self
+        self.alloc.spawn_obj(comp)?;
+        Ok(self)
     }
 
     pub fn prep_render_pipeline(mut self, render_init_fn: PipelineInitFn) -> Self {
@@ -129,3 +140,23 @@ impl Scene {
         &mut self.alloc
     }
 }
+
+#[cfg(test)]
+mod scene_tests {
+    use frosty_alloc::{AllocError, FrostyAllocatable};
+
+    use super::SceneBuilder;
+    use crate::SpawnError;
+
+    #[test]
+    fn builder_survives_spawn_errors() {
+        struct Marker;
+        unsafe impl FrostyAllocatable for Marker {}
+
+        let mut builder = SceneBuilder::new().spawn_component(1u32);
+        let err = builder.try_spawn_component(Marker).err().unwrap();
+        assert!(matches!(err, SpawnError::Alloc(AllocError::ZeroSized)));
+        builder.try_spawn_component(2u32).unwrap();
+        assert_eq!(2, builder.get_mut_spawner().entity_count());
+    }
+}
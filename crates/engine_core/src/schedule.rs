@@ -66,8 +66,8 @@ pub(crate) struct SystemNodeRaw {
 }
 
 impl SystemNodeRaw {
-    pub fn alloc_id(&self) -> TypeId {
-        self.system.alloc_id()
+    pub fn alloc_ids(&self) -> Vec<TypeId> {
+        self.system.alloc_ids()
     }
 
     pub fn get_system(&self) -> Arc<dyn SystemInterface> {
@@ -82,8 +82,8 @@ pub(crate) struct SystemNode {
 }
 
 impl SystemNode {
-    pub fn alloc_id(&self) -> TypeId {
-        self.raw.system.alloc_id()
+    pub fn alloc_ids(&self) -> Vec<TypeId> {
+        self.raw.system.alloc_ids()
     }
 
     pub fn get_system(&self) -> Arc<dyn SystemInterface> {
@@ -128,13 +128,18 @@ impl Schedule {
         }
     }
 
-    // Only fails if the system interop is not ergis
+    // Only fails if a component in the system interop
+    // is not registered, or is repeated
     pub fn add_system<S: SystemInterface + 'static>(
         &mut self,
         system: S,
         alloc: &mut Spawner,
     ) -> Option<()> {
-        let query = alloc.get_raw_query(&system.alloc_id())? as *mut RawQuery;
+        let ids = system.alloc_ids();
+        if !alloc.joinable(&ids) {
+            return None;
+        }
+        let query = alloc.get_raw_query(&ids[0])? as *mut RawQuery;
         let node = SystemNode {
             raw: SystemNodeRaw {
                 system: Arc::new(system),
@@ -0,0 +1,605 @@
+use std::any::TypeId;
+
+use frosty_alloc::{AllocError, Allocator, Erased, FrostyAllocatable, ObjectHandleMut};
+
+use crate::MASTER_THREAD;
+
+/*
+ * Snapshots are how a [Spawner] is saved and loaded.
+ *
+ * Layout (all numbers little endian):
+ *      magic       b"FRSN"
+ *      version     u32
+ *      types       u32 count, then the TYPE_NAME of each
+ *                  component as a string
+ *      objects     u64 count, then for each object
+ *                      u32 index into (types)
+ *                      u64 length of the payload
+ *                      payload written by serialize()
+ *      entities    u64 count, then for each slot of the
+ *                  entity table
+ *                      u32 generation
+ *                      bool, set if an entity lives in the slot,
+ *                      then its u64 count of components and the
+ *                      u64 index into (objects) of each
+ *
+ * Objects are restored into the Query of their type, in the
+ * order they were written. Entities keep the same slots, so
+ * their EntityIds stay the same.
+ *
+ * There is no section for query memberships. Every component
+ * belongs to exactly the Query of its type, so its type index
+ * already says which Query it goes back into.
+ *
+ * SiblingComponents aren't saved either. Restored entities are
+ * linked again, using the links of components the Spawner has
+ * spawned or registered with register_siblings().
+ *
+ * Strings are a u64 length followed by utf8 bytes. Components
+ * are found by name rather than TypeId, since TypeIds change
+ * between builds.
+ */
+
+pub(crate) const MAGIC: [u8; 4] = *b"FRSN";
+pub(crate) const VERSION: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    // a registered component has objects, but wasn't
+    // registered with register_serializable()
+    NotSerializable(TypeId),
+    // the snapshot names a component which isn't registered
+    UnknownComponent(String),
+    NotASnapshot,
+    UnsupportedVersion(u32),
+    // the snapshot ended in the middle of something
+    UnexpectedEnd,
+    // the snapshot was read, but made no sense
+    Invalid(&'static str),
+    Alloc(AllocError),
+}
+
+impl From<AllocError> for SnapshotError {
+    fn from(value: AllocError) -> Self {
+        Self::Alloc(value)
+    }
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotSerializable(id) => write!(f, "component {id:?} can't be serialized"),
+            Self::UnknownComponent(name) => write!(f, "component {name} isn't registered"),
+            Self::NotASnapshot => write!(f, "data is not a snapshot"),
+            Self::UnsupportedVersion(v) => write!(f, "snapshot version {v} is not supported"),
+            Self::UnexpectedEnd => write!(f, "snapshot ended early"),
+            Self::Invalid(why) => write!(f, "invalid snapshot: {why}"),
+            Self::Alloc(e) => write!(f, "failed to allocate component: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Alloc(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// A value which can be written to and read from a snapshot.
+// Components are made up of these
+pub trait SnapshotValue: Sized {
+    fn write(&self, out: &mut Vec<u8>);
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError>;
+}
+
+// A component which can be saved in a snapshot
+pub trait SerializableComponent: FrostyAllocatable + Sized {
+    // Identifies the component in a snapshot, so it
+    // must stay the same between builds
+    const TYPE_NAME: &'static str;
+    fn serialize(&self, out: &mut Vec<u8>);
+    fn deserialize(input: &mut &[u8]) -> Result<Self, SnapshotError>;
+}
+
+// Split the first (n) bytes off of (input)
+pub(crate) fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], SnapshotError> {
+    if input.len() < n {
+        return Err(SnapshotError::UnexpectedEnd);
+    }
+    let (head, tail) = input.split_at(n);
+    *input = tail;
+    Ok(head)
+}
+
+// Read a length and make sure it isn't larger than
+// what is left, so a bad length can't allocate forever
+pub(crate) fn read_len(input: &mut &[u8]) -> Result<usize, SnapshotError> {
+    let len = u64::read(input)?;
+    usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= input.len())
+        .ok_or(SnapshotError::UnexpectedEnd)
+}
+
+macro_rules! impl_snapshot_num {
+    ($($num:ty),*) => {
+        $(
+            impl SnapshotValue for $num {
+                fn write(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+                fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+                    let bytes = take(input, std::mem::size_of::<$num>())?;
+                    Ok(<$num>::from_le_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+impl_snapshot_num!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+// sizes are saved as 64 bit so snapshots work across platforms
+impl SnapshotValue for usize {
+    fn write(&self, out: &mut Vec<u8>) {
+        (*self as u64).write(out);
+    }
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        usize::try_from(u64::read(input)?).map_err(|_| SnapshotError::Invalid("usize too large"))
+    }
+}
+
+impl SnapshotValue for isize {
+    fn write(&self, out: &mut Vec<u8>) {
+        (*self as i64).write(out);
+    }
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        isize::try_from(i64::read(input)?).map_err(|_| SnapshotError::Invalid("isize too large"))
+    }
+}
+
+impl SnapshotValue for bool {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        match u8::read(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Invalid("bool was not 0 or 1")),
+        }
+    }
+}
+
+impl SnapshotValue for char {
+    fn write(&self, out: &mut Vec<u8>) {
+        (*self as u32).write(out);
+    }
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        char::from_u32(u32::read(input)?).ok_or(SnapshotError::Invalid("char out of range"))
+    }
+}
+
+impl SnapshotValue for String {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.len().write(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        let len = read_len(input)?;
+        let bytes = take(input, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| SnapshotError::Invalid("string was not utf8"))
+    }
+}
+
+impl<T: SnapshotValue> SnapshotValue for Vec<T> {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.len().write(out);
+        for v in self {
+            v.write(out);
+        }
+    }
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        // every value takes at least a byte, except for zero sized
+        // ones which aren't worth guarding against
+        let len = read_len(input)?;
+        (0..len).map(|_| T::read(input)).collect()
+    }
+}
+
+impl<T: SnapshotValue> SnapshotValue for Option<T> {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.is_some().write(out);
+        if let Some(v) = self {
+            v.write(out);
+        }
+    }
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        if bool::read(input)? {
+            Ok(Some(T::read(input)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// Primitives registered directly as components are saved as themselves
+macro_rules! impl_serializable {
+    ($($prim:ty),*) => {
+        $(
+            impl SerializableComponent for $prim {
+                const TYPE_NAME: &'static str = stringify!($prim);
+                fn serialize(&self, out: &mut Vec<u8>) {
+                    self.write(out);
+                }
+                fn deserialize(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+                    Self::read(input)
+                }
+            }
+        )*
+    };
+}
+
+impl_serializable!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char
+);
+
+type WriteFn = fn(&ObjectHandleMut<Erased>, &mut Vec<u8>) -> Result<(), SnapshotError>;
+type ReadFn = fn(&[u8], &mut Allocator) -> Result<ObjectHandleMut<Erased>, SnapshotError>;
+
+// How to save and load one type of component
+#[derive(Clone, Copy)]
+pub(crate) struct Serializer {
+    pub name: &'static str,
+    pub write: WriteFn,
+    pub read: ReadFn,
+}
+
+impl Serializer {
+    pub fn of<C: SerializableComponent>() -> Self {
+        Self {
+            name: C::TYPE_NAME,
+            write: write_component::<C>,
+            read: read_component::<C>,
+        }
+    }
+}
+
+fn write_component<C: SerializableComponent>(
+    handle: &ObjectHandleMut<Erased>,
+    out: &mut Vec<u8>,
+) -> Result<(), SnapshotError> {
+    let mut handle = handle.try_cast_clone::<C>()?;
+    let access = handle.get_access(MASTER_THREAD)?;
+    access.as_ref().serialize(out);
+    Ok(())
+}
+
+// (payload) has to be used up exactly, otherwise the
+// component read back isn't what was written
+fn read_component<C: SerializableComponent>(
+    mut payload: &[u8],
+    alloc: &mut Allocator,
+) -> Result<ObjectHandleMut<Erased>, SnapshotError> {
+    let comp = C::deserialize(&mut payload)?;
+    if !payload.is_empty() {
+        return Err(SnapshotError::Invalid("component payload was not used up"));
+    }
+    let mut handle = alloc.alloc(comp)?;
+    Ok(unsafe { handle.dissolve_data() })
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use frosty_alloc::{AllocError, FrostyAllocatable};
+
+    use super::{SerializableComponent, SnapshotError, SnapshotValue};
+    use crate::{
+        query::Query, ComponentLocations, Entity, ReferencesSiblingComponent, SiblingComponent,
+        SpawnError, Spawner,
+    };
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Inventory {
+        owner: String,
+        gold: u64,
+        slots: Vec<Vec<u32>>,
+        tags: Vec<String>,
+        equipped: Option<char>,
+    }
+    unsafe impl FrostyAllocatable for Inventory {}
+
+    impl SerializableComponent for Inventory {
+        const TYPE_NAME: &'static str = "test::Inventory";
+        fn serialize(&self, out: &mut Vec<u8>) {
+            self.owner.write(out);
+            self.gold.write(out);
+            self.slots.write(out);
+            self.tags.write(out);
+            self.equipped.write(out);
+        }
+        fn deserialize(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+            Ok(Self {
+                owner: String::read(input)?,
+                gold: u64::read(input)?,
+                slots: Vec::read(input)?,
+                tags: Vec::read(input)?,
+                equipped: Option::read(input)?,
+            })
+        }
+    }
+
+    // Nothing of it is saved, it is linked again on restore
+    #[derive(Default)]
+    struct Purse {
+        inventory: SiblingComponent<Inventory>,
+    }
+    unsafe impl FrostyAllocatable for Purse {}
+
+    impl SerializableComponent for Purse {
+        const TYPE_NAME: &'static str = "test::Purse";
+        fn serialize(&self, _out: &mut Vec<u8>) {}
+        fn deserialize(_input: &mut &[u8]) -> Result<Self, SnapshotError> {
+            Ok(Self::default())
+        }
+    }
+
+    impl ReferencesSiblingComponent for Purse {
+        fn sibling_ids() -> Vec<std::any::TypeId> {
+            vec![Inventory::id()]
+        }
+        fn update_references(&mut self, locs: &ComponentLocations) {
+            self.inventory.update(locs);
+        }
+    }
+
+    fn gold_in_purse(spawner: &Spawner, entity: crate::EntityId) -> u64 {
+        let mut purse = spawner.get_component::<Purse>(entity).unwrap();
+        let mut purse = purse.get_access_mut(0).unwrap();
+        let gold = purse.as_mut().inventory.get(0).unwrap().as_ref().gold;
+        gold
+    }
+
+    fn inventories() -> Vec<Inventory> {
+        vec![
+            Inventory {
+                owner: "Frosty".into(),
+                gold: 120,
+                slots: vec![vec![1, 2, 3], vec![], vec![9]],
+                tags: vec!["player".into(), "❄".into()],
+                equipped: Some('⚔'),
+            },
+            Inventory {
+                owner: String::new(),
+                gold: u64::MAX,
+                slots: Vec::new(),
+                tags: Vec::new(),
+                equipped: None,
+            },
+        ]
+    }
+
+    fn spawner() -> Spawner {
+        let mut spawner = Spawner::new();
+        spawner.register_serializable::<Inventory>();
+        spawner.register_serializable::<f32>();
+        spawner.register_serializable::<i64>();
+        spawner.register_serializable::<bool>();
+        spawner
+    }
+
+    fn collect<C: FrostyAllocatable + Clone>(spawner: &Spawner) -> Vec<C> {
+        let mut query: Query<C> = spawner.get_query(0).unwrap();
+        let mut out = Vec::new();
+        while let Some(access) = query.next(0) {
+            out.push(access.as_ref().clone());
+        }
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut spawner = spawner();
+        for inv in inventories() {
+            spawner.spawn_obj(inv).unwrap();
+        }
+        spawner.spawn_batch([1.5f32, -0.0, f32::MAX]).unwrap();
+        spawner.spawn_obj(i64::MIN).unwrap();
+        spawner.spawn_obj(true).unwrap();
+
+        let bytes = spawner.snapshot().unwrap();
+        let restored = spawner.restore(&bytes).unwrap();
+        assert_eq!(inventories(), collect::<Inventory>(&restored));
+        assert_eq!(vec![1.5f32, -0.0, f32::MAX], collect::<f32>(&restored));
+        assert_eq!(vec![i64::MIN], collect::<i64>(&restored));
+        assert_eq!(vec![true], collect::<bool>(&restored));
+
+        // saving what was loaded gives back the same snapshot
+        assert_eq!(bytes, restored.snapshot().unwrap());
+    }
+
+    #[test]
+    fn values_round_trip() {
+        fn check<T: SnapshotValue + PartialEq + std::fmt::Debug>(value: T) {
+            let mut out = Vec::new();
+            value.write(&mut out);
+            let mut input = &out[..];
+            assert_eq!(value, T::read(&mut input).unwrap());
+            assert!(input.is_empty());
+        }
+        check(u128::MAX);
+        check(-7isize);
+        check(f64::MIN_POSITIVE);
+        check('z');
+        check(String::from("snow"));
+        check(vec![vec![String::from("a")], vec![]]);
+        check(Some(vec![Some(3u8), None]));
+    }
+
+    #[test]
+    fn bad_snapshots_fail() {
+        let mut spawner = spawner();
+        spawner.spawn_obj(inventories().remove(0)).unwrap();
+        let bytes = spawner.snapshot().unwrap();
+
+        assert_eq!(
+            SnapshotError::NotASnapshot,
+            spawner.restore(b"nope").err().unwrap()
+        );
+        let mut future = bytes.clone();
+        future[4] = 99;
+        assert_eq!(
+            SnapshotError::UnsupportedVersion(99),
+            spawner.restore(&future).err().unwrap()
+        );
+        for len in 0..bytes.len() {
+            assert!(spawner.restore(&bytes[..len]).is_err());
+        }
+
+        let mut other = Spawner::new();
+        other.register_serializable::<f32>();
+        assert_eq!(
+            SnapshotError::UnknownComponent("test::Inventory".into()),
+            other.restore(&bytes).err().unwrap()
+        );
+    }
+
+    #[test]
+    fn derived_components_round_trip() {
+        #[derive(FrostyAllocatable, Debug, Clone, PartialEq)]
+        #[frosty(serialize)]
+        struct Bird {
+            name: String,
+            wings: u8,
+        }
+        #[derive(FrostyAllocatable, Debug, Clone, PartialEq)]
+        #[frosty(serialize = "test::Nest")]
+        struct Nest(Option<u32>, bool);
+
+        assert!(Bird::TYPE_NAME.ends_with("snapshot_tests::Bird"));
+        let mut spawner = Spawner::new();
+        spawner.register_serializable::<Bird>();
+        spawner.register_serializable::<Nest>();
+        let bird = Bird {
+            name: String::from("gull"),
+            wings: 2,
+        };
+        spawner.spawn_obj(bird.clone()).unwrap();
+        spawner.spawn_obj(Nest(Some(4), true)).unwrap();
+
+        let restored = spawner.restore(&spawner.snapshot().unwrap()).unwrap();
+        assert_eq!(vec![bird], collect::<Bird>(&restored));
+        assert_eq!(vec![Nest(Some(4), true)], collect::<Nest>(&restored));
+    }
+
+    #[test]
+    fn entities_round_trip() {
+        let mut spawner = spawner();
+        spawner.register_serializable::<Purse>();
+        let mut ids = Vec::new();
+        for (i, inv) in inventories().into_iter().enumerate() {
+            let mut entity = Entity::new();
+            entity.add(inv);
+            entity.add(i as i64);
+            entity.add_with_siblings(Purse::default());
+            ids.push(spawner.spawn(entity).unwrap());
+        }
+        let lone = spawner.spawn_obj(true).unwrap();
+        // leaves a free slot with a newer generation
+        spawner.despawn(ids.remove(0)).unwrap();
+        spawner.remove_despawned().unwrap();
+
+        let bytes = spawner.snapshot().unwrap();
+        let mut restored = spawner.restore(&bytes).unwrap();
+        assert_eq!(2, restored.entity_count());
+        let mut gold = restored.get_component::<Inventory>(ids[0]).unwrap();
+        assert_eq!(u64::MAX, gold.get_access(0).unwrap().as_ref().gold);
+        let mut num = restored.get_component::<i64>(ids[0]).unwrap();
+        assert_eq!(1, *num.get_access(0).unwrap().as_ref());
+        assert_eq!(Some(ids[0]), restored.entity_of(&num));
+        assert!(restored.get_component::<bool>(lone).is_some());
+        assert_eq!(bytes, restored.snapshot().unwrap());
+
+        // siblings are linked to the restored objects, not the
+        // ones in (spawner)
+        let mut template = self::spawner();
+        template.register_serializable::<Purse>();
+        template.register_siblings::<Purse>();
+        drop(spawner);
+        assert_eq!(u64::MAX, gold_in_purse(&restored, ids[0]));
+        let loaded = template.restore(&bytes).unwrap();
+        assert_eq!(u64::MAX, gold_in_purse(&loaded, ids[0]));
+
+        // the free slot is reused under its next generation
+        let next = restored.spawn_obj(false).unwrap();
+        assert_eq!(0, next.index());
+        assert_eq!(1, next.generation());
+    }
+
+    #[test]
+    fn despawned_components_are_not_saved() {
+        let mut spawner = spawner();
+        let mut entity = Entity::new();
+        entity.add(inventories().remove(0));
+        entity.add(3i64);
+        let gone = spawner.spawn(entity).unwrap();
+        let kept = spawner.spawn_obj(5i64).unwrap();
+        let mut entity = Entity::new();
+        entity.add(inventories().remove(1));
+        entity.add(true);
+        let trimmed = spawner.spawn(entity).unwrap();
+        // no frame barrier, so all of these are still in their Querys
+        spawner.despawn(gone).unwrap();
+        spawner.remove_component::<bool>(trimmed).unwrap();
+
+        let restored = spawner.restore(&spawner.snapshot().unwrap()).unwrap();
+        assert_eq!(2, restored.entity_count());
+        assert_eq!(vec![5i64], collect::<i64>(&restored));
+        assert_eq!(
+            vec![inventories().remove(1)],
+            collect::<Inventory>(&restored)
+        );
+        assert!(collect::<bool>(&restored).is_empty());
+        assert!(restored.get_component::<i64>(kept).is_some());
+        assert!(restored.get_component::<Inventory>(trimmed).is_some());
+        assert!(restored.get_component::<bool>(trimmed).is_none());
+    }
+
+    #[test]
+    fn restores_keep_the_budget() {
+        let mut spawner = Spawner::with_budget(4096);
+        spawner.register_serializable::<i64>();
+        spawner.spawn_obj(7i64).unwrap();
+        let mut restored = spawner.restore(&spawner.snapshot().unwrap()).unwrap();
+        assert_eq!(vec![7i64], collect::<i64>(&restored));
+        let err = loop {
+            if let Err(e) = restored.spawn_obj(0i64) {
+                break e;
+            }
+        };
+        assert!(matches!(
+            err,
+            SpawnError::Alloc(AllocError::OutOfBudget { .. })
+        ));
+        assert_eq!(4096, restored.stats().capacity);
+    }
+
+    #[test]
+    fn unserializable_components_fail() {
+        struct Secret(#[allow(dead_code)] u32);
+        unsafe impl FrostyAllocatable for Secret {}
+
+        let mut spawner = spawner();
+        spawner.register_component::<Secret>();
+        // nothing to lose while there are none
+        assert!(spawner.snapshot().is_ok());
+        spawner.spawn_obj(Secret(7)).unwrap();
+        assert_eq!(
+            SnapshotError::NotSerializable(Secret::id()),
+            spawner.snapshot().err().unwrap()
+        );
+    }
+}
@@ -1,58 +1,157 @@
-use std::any::TypeId;
+use std::{any::TypeId, mem::ManuallyDrop};
 
-use frosty_alloc::{Allocator, FrostyAllocatable, ObjectHandleMut};
+use frosty_alloc::{
+    AllocError, Allocator, AllocatorStats, Erased, Fetch, FrostyAllocatable, LocalCache,
+    ObjectHandleMut,
+};
 use hashbrown::HashMap;
 
 use crate::{
+    buffered::{BackBuffer, BufferedQuery},
+    entity::{link, ComponentLocations, LinkFn},
+    entity_table::{EntityId, EntityTable},
+    filter::QueryFilter,
     query::{Query, QueryForm, RawQuery},
-    Entity,
+    snapshot::{read_len, take, Serializer, MAGIC, VERSION},
+    Entity, ReferencesSiblingComponent, SerializableComponent, SnapshotError, SnapshotValue,
 };
 
-type ConverterFn = for<'a, 'b> fn(
-    &'a Box<(dyn FrostyAllocatable + 'static)>,
-    &'b mut frosty_alloc::Allocator,
-) -> ObjectHandleMut<u8>;
-//    dyn FnMut(&Box<dyn FrostyAllocatable>, &mut Allocator) -> ObjectHandleMut<u8> + 'a;
+type ConverterFn = for<'a> fn(
+    Box<(dyn FrostyAllocatable + 'static)>,
+    &'a mut frosty_alloc::Allocator,
+) -> Result<ObjectHandleMut<Erased>, AllocError>;
+//    dyn FnMut(&Box<dyn FrostyAllocatable>, &mut Allocator) -> ObjectHandleMut<Erased> + 'a;
 
 #[derive(Debug, Clone, Copy)]
 pub struct UnregisteredComponent;
 
+#[derive(Debug, Clone, Copy)]
+pub enum SpawnError {
+    Unregistered(UnregisteredComponent),
+    Alloc(AllocError),
+    // (component) references (sibling), but its Entity doesn't have one
+    MissingSibling { component: TypeId, sibling: TypeId },
+}
+
+impl From<UnregisteredComponent> for SpawnError {
+    fn from(value: UnregisteredComponent) -> Self {
+        Self::Unregistered(value)
+    }
+}
+
+impl From<AllocError> for SpawnError {
+    fn from(value: AllocError) -> Self {
+        Self::Alloc(value)
+    }
+}
+
+impl std::fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unregistered(_) => write!(f, "component has not been registered"),
+            Self::Alloc(e) => write!(f, "failed to allocate component: {e}"),
+            Self::MissingSibling { component, sibling } => write!(
+                f,
+                "component {component:?} references {sibling:?}, which its entity doesn't have"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SpawnError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Unregistered(_) | Self::MissingSibling { .. } => None,
+            Self::Alloc(e) => Some(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DespawnError {
+    // the entity was already despawned, or never existed
+    NoEntity(EntityId),
+    MissingComponent { entity: EntityId, component: TypeId },
+}
+
+impl std::fmt::Display for DespawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoEntity(entity) => write!(f, "entity {entity} does not exist"),
+            Self::MissingComponent { entity, component } => {
+                write!(f, "entity {entity} has no component {component:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DespawnError {}
+
 pub struct Spawner {
-    alloc: Allocator,
+    // queries and entities hold handles into (alloc),
+    // so they are dropped first
     queries: HashMap<TypeId, RawQuery>,
+    entities: EntityTable,
+    alloc: Allocator,
     registered_components: HashMap<TypeId, ConverterFn>,
+    // components which can be saved in a snapshot
+    serializers: HashMap<TypeId, Serializer>,
+    // how components which reference their siblings are
+    // linked, so restored entities can be linked again
+    links: HashMap<TypeId, LinkFn>,
 }
 
 impl Spawner {
     pub fn new() -> Self {
-        Self {
-            alloc: Allocator::new(),
-            queries: HashMap::new(),
-            registered_components: HashMap::new(),
-        }
+        Self::from_allocator(Allocator::new())
     }
 
     pub fn with_capacity(capacity: usize) -> Self {
+        Self::from_allocator(Allocator::with_capacity(capacity))
+    }
+
+    // Components will never take up more than (max_bytes)
+    // see Allocator::with_budget()
+    pub fn with_budget(max_bytes: usize) -> Self {
+        Self::from_allocator(Allocator::with_budget(max_bytes))
+    }
+
+    // Store all components in (buffer)
+    // see Allocator::from_buffer()
+    pub fn from_buffer(buffer: &'static mut [u8]) -> Result<Self, AllocError> {
+        Ok(Self::from_allocator(Allocator::from_buffer(buffer)?))
+    }
+
+    fn from_allocator(alloc: Allocator) -> Self {
         Self {
-            alloc: Allocator::with_capacity(capacity),
             queries: HashMap::new(),
+            entities: EntityTable::new(),
+            alloc,
             registered_components: HashMap::new(),
+            serializers: HashMap::new(),
+            links: HashMap::new(),
         }
     }
 
     fn upcast_component<C: FrostyAllocatable>(
-        obj: &Box<dyn FrostyAllocatable>,
+        obj: Box<dyn FrostyAllocatable>,
         alloc: &mut Allocator,
-    ) -> ObjectHandleMut<u8> {
-        let ptr = obj.as_ref() as *const dyn FrostyAllocatable;
-        let converted_data = ptr as *const C;
-        let interim_index = alloc
-            .alloc_raw(converted_data)
-            .expect("Issue with allocating component in Entity");
-        let mut handle = alloc
-            .get_mut::<C>(interim_index)
-            .expect("Allocator returned invalid index of interim ptr");
-        unsafe { handle.dissolve_data() }
+    ) -> Result<ObjectHandleMut<Erased>, AllocError> {
+        let ptr = Box::into_raw(obj);
+        let converted_data = ptr as *mut C;
+        let interim_index = match alloc.alloc_raw(converted_data as *const C) {
+            Ok(index) => index,
+            Err(e) => {
+                // the component was never moved, so the box still owns it
+                unsafe { drop(Box::from_raw(converted_data)) };
+                return Err(e);
+            }
+        };
+        // the allocator now owns the component, so the box only
+        // releases its memory without running C's destructor
+        unsafe { drop(Box::from_raw(converted_data as *mut ManuallyDrop<C>)) };
+        let mut handle = alloc.get_mut::<C>(interim_index)?;
+        Ok(unsafe { handle.dissolve_data() })
     }
 
     // Register a component so that it can be properly allocated
@@ -60,74 +159,551 @@ impl Spawner {
     pub fn register_component<C: FrostyAllocatable>(&mut self) {
         self.registered_components
             .insert(C::id(), Self::upcast_component::<C>);
-        self.queries
-            .insert(C::id(), RawQuery::new(QueryForm::Continuous, Vec::new()));
+        self.queries.insert(
+            C::id(),
+            RawQuery::new(QueryForm::Continuous, C::id(), Vec::new()),
+        );
+    }
+
+    // Register a component which will be saved by snapshot()
+    pub fn register_serializable<C: SerializableComponent>(&mut self) {
+        debug_assert!(
+            self.serializers
+                .iter()
+                .all(|(id, s)| *id == C::id() || s.name != C::TYPE_NAME),
+            "Two components are named {}",
+            C::TYPE_NAME
+        );
+        self.register_component::<C>();
+        self.serializers.insert(C::id(), Serializer::of::<C>());
+    }
+
+    // Register a component which references its siblings, so it
+    // is linked again when restored from a snapshot. Spawning an
+    // Entity holding one registers it too
+    pub fn register_siblings<C: ReferencesSiblingComponent>(&mut self) {
+        self.links.insert(C::id(), link::<C>);
+    }
+
+    // Register a component which keeps a copy of last frame's
+    // value for systems to read. see buffered.rs
+    pub fn register_double_buffered<C: FrostyAllocatable + Clone>(&mut self) {
+        self.register_component::<C>();
+        self.queries.get_mut(&C::id()).unwrap().buffer = Some(BackBuffer::of::<C>());
     }
 
     pub fn is_registered<C: FrostyAllocatable>(&mut self) -> bool {
         self.registered_components.get(&C::id()).is_some()
     }
 
-    // Spawn an entity and move all its components into the allocator then
-    // add them to Querys
-    pub fn spawn(&mut self, entity: Entity) -> Result<(), UnregisteredComponent> {
-        let (mut locs, comps) = entity.dissolve();
-        locs.iter_mut().try_for_each(|(id, i)| {
+    // Spawn an entity and move all its components into the allocator,
+    // link the ones which reference their siblings, then add them to
+    // Querys. If any component fails the whole entity is rolled back
+    pub fn spawn(&mut self, entity: Entity) -> Result<EntityId, SpawnError> {
+        let (indices, comps, links) = entity.dissolve();
+        for link in links.iter() {
+            self.links.insert(link.comp, link.update);
+            if let Some(sibling) = link.siblings.iter().find(|s| !indices.contains_key(*s)) {
+                return Err(SpawnError::MissingSibling {
+                    component: link.comp,
+                    sibling: *sibling,
+                });
+            }
+        }
+
+        let mut comps: Vec<_> = comps.into_iter().map(Some).collect();
+        let mut locs = ComponentLocations::new();
+        let allocated: Result<(), SpawnError> = indices.iter().try_for_each(|(id, i)| {
             let converter = match self.registered_components.get_mut(id) {
                 Some(f) => f,
-                None => return Err(UnregisteredComponent),
+                None => return Err(UnregisteredComponent.into()),
             };
-            let handle = (converter)(&comps[*i], &mut self.alloc);
+            let comp = comps[*i].take().expect("Entity stored a component twice");
+            locs.insert(*id, (converter)(comp, &mut self.alloc)?);
+            Ok(())
+        });
+
+        // linked before they are in a Query, so double
+        // buffered copies hold the references too
+        let linked = links
+            .iter()
+            .try_for_each(|link| match locs.get_raw(&link.comp) {
+                Some(handle) => (link.update)(handle, &locs),
+                None => Ok(()),
+            });
+
+        let mut handles: Vec<_> = locs.into_handles().collect();
+        if let Err(e) = allocated.and(linked.map_err(SpawnError::from)) {
+            self.free_all(&mut handles);
+            return Err(e);
+        }
+        self.add_to_queries(&mut handles)?;
+        Ok(self.entities.insert(handles))
+    }
 
-            self.queries.get_mut(id).unwrap().add_handle(handle);
+    // Move some object into the allocator and add it to the Query.
+    // It becomes an entity with a single component
+    pub fn spawn_obj<C: FrostyAllocatable>(&mut self, obj: C) -> Result<EntityId, SpawnError> {
+        if !self.queries.contains_key(&C::id()) {
+            return Err(UnregisteredComponent.into());
+        }
+        let handle = unsafe { self.alloc.alloc(obj)?.dissolve_data() };
+        let mut handles = [(C::id(), handle)];
+        self.add_to_queries(&mut handles)?;
+        Ok(self.entities.insert(handles))
+    }
+
+    // Move many objects of the same type into the allocator at
+    // once. They are stored next to each other, so iterating
+    // over them in their Query is cache friendly. Each object
+    // becomes an entity with a single component, unless any of
+    // them fail, in which case none are spawned
+    pub fn spawn_batch<C, I>(&mut self, objs: I) -> Result<Vec<EntityId>, SpawnError>
+    where
+        C: FrostyAllocatable,
+        I: IntoIterator<Item = C>,
+    {
+        if !self.queries.contains_key(&C::id()) {
+            return Err(UnregisteredComponent.into());
+        }
+        let mut handles: Vec<_> = self
+            .alloc
+            .alloc_many(objs)?
+            .into_iter()
+            .map(|mut handle| (C::id(), unsafe { handle.dissolve_data() }))
+            .collect();
+        self.add_to_queries(&mut handles)?;
+        Ok(handles
+            .into_iter()
+            .map(|comp| self.entities.insert([comp]))
+            .collect())
+    }
 
+    // Add (handles) to their Querys. If one can't be added, the ones
+    // which were are queued to be dropped the same way as a despawn,
+    // and the rest are free'd, so nothing is left without an entity
+    fn add_to_queries(
+        &mut self,
+        handles: &mut [(TypeId, ObjectHandleMut<Erased>)],
+    ) -> Result<(), SpawnError> {
+        let mut added = 0;
+        let result = handles.iter().try_for_each(|(id, handle)| {
+            self.queries
+                .get_mut(id)
+                .ok_or(UnregisteredComponent)?
+                .add_handle(handle.clone(), &mut self.alloc)?;
+            added += 1;
             Ok(())
-        })
+        });
+        if result.is_err() {
+            let (queued, rest) = handles.split_at_mut(added);
+            for (id, handle) in queued.iter() {
+                self.queries.get_mut(id).unwrap().queue_drop(handle);
+            }
+            self.free_all(rest);
+        }
+        result
     }
 
-    // Move some object into the allocator and add it to the Query
-    pub fn spawn_obj<C: FrostyAllocatable>(&mut self, obj: C) -> Result<(), UnregisteredComponent> {
-        let query = match self.queries.get_mut(&C::id()) {
-            Some(query) => query,
-            None => return Err(UnregisteredComponent),
-        };
+    // Free objects which never made it into a Query. The error
+    // which caused this is more useful than one from free()
+    fn free_all(&mut self, handles: &mut [(TypeId, ObjectHandleMut<Erased>)]) {
+        for (_, handle) in handles.iter_mut() {
+            let _ = self.alloc.free(handle);
+        }
+    }
 
-        let handle = unsafe {
-            self.alloc
-                .alloc(obj)
-                .expect("Failed to allocate object")
-                .dissolve_data()
-        };
-        query.add_handle(handle);
+    // The C belonging to (entity). None if the entity is
+    // gone or never had a C
+    pub fn get_component<C: FrostyAllocatable>(
+        &self,
+        entity: EntityId,
+    ) -> Option<ObjectHandleMut<C>> {
+        let handle = self.entities.get(entity)?.get(&C::id())?;
+        Some(handle.cast_clone())
+    }
+
+    // The entity a component belongs to
+    pub fn entity_of<T: FrostyAllocatable>(&self, handle: &ObjectHandleMut<T>) -> Option<EntityId> {
+        self.entities.entity_of(&handle.key())
+    }
+
+    // Remove an entity and all of its components. The id is invalid
+    // straight away, but the components stay in their Querys until
+    // remove_despawned() runs at the end of the frame, so Querys
+    // being iterated on other threads stay valid
+    pub fn despawn(&mut self, entity: EntityId) -> Result<(), DespawnError> {
+        let comps = self
+            .entities
+            .remove(entity)
+            .ok_or(DespawnError::NoEntity(entity))?;
+        for (id, handle) in comps {
+            self.queries.get_mut(&id).unwrap().queue_drop(&handle);
+        }
+        Ok(())
+    }
 
+    // Remove the C from an entity, which keeps the rest of its
+    // components. Deferred the same way as despawn()
+    pub fn remove_component<C: FrostyAllocatable>(
+        &mut self,
+        entity: EntityId,
+    ) -> Result<(), DespawnError> {
+        if self.entities.get(entity).is_none() {
+            return Err(DespawnError::NoEntity(entity));
+        }
+        let handle = self.entities.remove_component(entity, &C::id()).ok_or(
+            DespawnError::MissingComponent {
+                entity,
+                component: C::id(),
+            },
+        )?;
+        self.queries.get_mut(&C::id()).unwrap().queue_drop(&handle);
         Ok(())
     }
 
+    // Take everything despawned since the last call out of its
+    // Query and free it. Run by the master thread at the end of
+    // every frame. Returns the first error once the rest are free'd
+    pub fn remove_despawned(&mut self) -> Result<(), AllocError> {
+        let mut result = Ok(());
+        for query in self.queries.values_mut() {
+            result = result.and(query.drop_queued(&mut self.alloc));
+        }
+        result
+    }
+
+    // Number of entities currently spawned
+    pub fn entity_count(&self) -> usize {
+        self.entities.len()
+    }
+
     pub(crate) fn get_raw_query(&mut self, id: &TypeId) -> Option<&mut RawQuery> {
         self.queries.get_mut(id)
     }
 
-    pub fn get_query<C: FrostyAllocatable>(&self, thread: u32) -> Option<Query<C>> {
+    // C can be a single component or a tuple of them, see Query.
+    // Returns None if any of them isn't registered
+    pub fn get_query<C: Fetch>(&self, thread: u32) -> Option<Query<C>> {
+        self.get_filtered_query(thread)
+    }
+
+    // A query which only returns what passes F, see filter.rs
+    pub fn get_filtered_query<C: Fetch, F: QueryFilter>(&self, thread: u32) -> Option<Query<C, F>> {
+        let ids = C::ids();
+        if !self.joinable(&ids) {
+            return None;
+        }
+        Some(Query::new(
+            self.queries.get(&ids[0])?,
+            &self.entities,
+            self.tick(),
+            thread,
+        ))
+    }
+
+    // A query for the components (ids), which can be cast to
+    // the matching tuple. see SystemInterface::alloc_ids()
+    pub fn get_query_by_ids(&self, ids: &[TypeId], thread: u32) -> Option<Query<Erased>> {
+        if !self.joinable(ids) {
+            return None;
+        }
+        self.get_query_by_id(&ids[0], thread)
+    }
+
+    // Every component is registered, and none are repeated
+    pub(crate) fn joinable(&self, ids: &[TypeId]) -> bool {
+        !ids.is_empty()
+            && ids
+                .iter()
+                .enumerate()
+                .all(|(i, id)| self.queries.contains_key(id) && !ids[..i].contains(id))
+    }
+
+    // Returns None if C isn't double buffered
+    pub fn get_buffered_query<C: FrostyAllocatable>(
+        &self,
+        thread: u32,
+    ) -> Option<BufferedQuery<C>> {
         let raw = self.queries.get(&C::id())?;
-        Some(Query::new(raw, thread))
+        BufferedQuery::new(raw, thread)
     }
 
-    pub fn get_query_by_id(&self, id: &TypeId, thread: u32) -> Option<Query<u8>> {
+    pub fn get_query_by_id(&self, id: &TypeId, thread: u32) -> Option<Query<Erased>> {
         let raw = self.queries.get(id)?;
-        Some(Query::new(raw, thread))
+        Some(Query::new(raw, &self.entities, self.tick(), thread))
     }
 
-    pub fn get_dissolved_query(&self, id: TypeId, thread: u32) -> Option<Query<u8>> {
+    pub fn get_dissolved_query(&self, id: TypeId, thread: u32) -> Option<Query<Erased>> {
         let raw = self.queries.get(&id)?;
-        Some(Query::new(raw, thread))
+        Some(Query::new(raw, &self.entities, self.tick(), thread))
+    }
+
+    // Move objects spawned by another thread into their Querys.
+    // Like spawn_obj(), each becomes an entity with a single
+    // component. Objects which fail are dropped, and the first
+    // error is returned once the rest have been added
+    pub fn merge_cache(&mut self, cache: &mut LocalCache) -> Result<(), SpawnError> {
+        let mut result = Ok(());
+        cache.drain(|obj| {
+            let id = obj.type_id();
+            let spawned = match self.queries.contains_key(&id) {
+                true => obj
+                    .move_into(&mut self.alloc)
+                    .map_err(SpawnError::from)
+                    .and_then(|handle| {
+                        let mut handles = [(id, handle)];
+                        self.add_to_queries(&mut handles)?;
+                        self.entities.insert(handles);
+                        Ok(())
+                    }),
+                false => Err(UnregisteredComponent.into()),
+            };
+            if result.is_ok() {
+                result = spawned;
+            }
+        });
+        result
+    }
+
+    // Commit what systems wrote to double buffered components,
+    // so it is read as the previous value next frame. Run by
+    // the master thread at the end of every frame
+    pub fn swap_buffers(&mut self) {
+        for query in self.queries.values_mut() {
+            query.commit_buffer();
+        }
+    }
+
+    // Start the next frame. Added and Changed filters look at
+    // what happened during the frame before the current tick.
+    // Run by the master thread at the end of every frame
+    pub fn advance_tick(&mut self) {
+        self.alloc.advance_tick();
+    }
+
+    pub fn tick(&self) -> u32 {
+        self.alloc.tick()
+    }
+
+    // Memory usage of every component spawned so far
+    pub fn stats(&self) -> AllocatorStats {
+        self.alloc.stats()
+    }
+
+    // Save every spawned component and the entities they belong
+    // to. see snapshot.rs for the format
+    pub fn snapshot(&self) -> Result<Vec<u8>, SnapshotError> {
+        // sorted by name so the same components always
+        // give the same snapshot
+        let mut types = Vec::new();
+        for (id, query) in self.queries.iter() {
+            match self.serializers.get(id) {
+                _ if query.spawned().next().is_none() => {}
+                Some(s) => types.push((s, query)),
+                None => return Err(SnapshotError::NotSerializable(*id)),
+            }
+        }
+        types.sort_by_key(|(s, _)| s.name);
+
+        let mut out = Vec::from(MAGIC);
+        VERSION.write(&mut out);
+        (types.len() as u32).write(&mut out);
+        for (s, _) in types.iter() {
+            s.name.to_string().write(&mut out);
+        }
+
+        let mut objects = Vec::new();
+        // index of each object in (objects)
+        let mut indices = HashMap::new();
+        let mut payload = Vec::new();
+        for (ty, (s, query)) in types.iter().enumerate() {
+            for handle in query.spawned() {
+                payload.clear();
+                (s.write)(handle, &mut payload)?;
+                (ty as u32).write(&mut objects);
+                payload.len().write(&mut objects);
+                objects.extend_from_slice(&payload);
+                indices.insert(handle.key(), indices.len());
+            }
+        }
+        indices.len().write(&mut out);
+        out.extend_from_slice(&objects);
+
+        let slots: Vec<_> = self.entities.slots().collect();
+        slots.len().write(&mut out);
+        for (generation, comps) in slots {
+            generation.write(&mut out);
+            let comps = comps.map(|comps| {
+                let mut comps: Vec<usize> = comps
+                    .values()
+                    .filter_map(|handle| indices.get(&handle.key()).copied())
+                    .collect();
+                comps.sort_unstable();
+                comps
+            });
+            comps.write(&mut out);
+        }
+        Ok(out)
+    }
+
+    // Build a new Spawner from a snapshot. Every component in
+    // the snapshot has to be registered as serializable with
+    // this Spawner, and the new one has the same registrations
+    // and allocator limits. A buffer can't be shared, so a
+    // Spawner made from_buffer() gives one with a budget of the
+    // buffer's size, see restore_with() to use another buffer.
+    // Entities keep their EntityIds
+    pub fn restore(&self, snapshot: &[u8]) -> Result<Spawner, SnapshotError> {
+        let alloc = match self.alloc.budget() {
+            Some(budget) => Allocator::with_budget(budget),
+            None => Allocator::with_capacity(self.alloc.capacity()),
+        };
+        self.restore_with(snapshot, alloc)
+    }
+
+    // restore() into (alloc), which should be empty
+    pub fn restore_with(
+        &self,
+        snapshot: &[u8],
+        alloc: Allocator,
+    ) -> Result<Spawner, SnapshotError> {
+        let mut input = snapshot;
+        if take(&mut input, MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = u32::read(&mut input)?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut restored = Spawner::from_allocator(alloc);
+        restored.registered_components = self.registered_components.clone();
+        restored.serializers = self.serializers.clone();
+        restored.links = self.links.clone();
+        for (id, query) in self.queries.iter() {
+            restored.queries.insert(*id, query.empty_like());
+        }
+
+        let mut types = Vec::new();
+        for _ in 0..u32::read(&mut input)? {
+            let name = String::read(&mut input)?;
+            match self.serializers.iter().find(|(_, s)| s.name == name) {
+                Some((id, s)) => types.push((*id, *s)),
+                None => return Err(SnapshotError::UnknownComponent(name)),
+            }
+        }
+        let type_at = |input: &mut &[u8]| {
+            let index = u32::read(input)? as usize;
+            types
+                .get(index)
+                .copied()
+                .ok_or(SnapshotError::Invalid("unknown type index"))
+        };
+
+        // every object goes back in the Query of its type, once
+        // the entities holding them have been linked
+        let mut objects = Vec::new();
+        for _ in 0..read_len(&mut input)? {
+            let (id, s) = type_at(&mut input)?;
+            let len = read_len(&mut input)?;
+            let payload = take(&mut input, len)?;
+            let handle = (s.read)(payload, &mut restored.alloc)?;
+            objects.push(Some((id, handle)));
+        }
+        let ordered: Vec<_> = objects.iter().flatten().cloned().collect();
+
+        let mut slots = Vec::new();
+        for _ in 0..read_len(&mut input)? {
+            let generation = u32::read(&mut input)?;
+            let comps = match Option::<Vec<usize>>::read(&mut input)? {
+                Some(indices) => {
+                    let mut locs = ComponentLocations::new();
+                    let mut comps = HashMap::new();
+                    for index in indices {
+                        // each object belongs to one entity at most
+                        let (id, handle) = objects
+                            .get_mut(index)
+                            .and_then(Option::take)
+                            .ok_or(SnapshotError::Invalid("unknown object index"))?;
+                        locs.insert(id, handle.clone());
+                        if comps.insert(id, handle).is_some() {
+                            return Err(SnapshotError::Invalid("entity has a component twice"));
+                        }
+                    }
+                    for (id, handle) in comps.iter() {
+                        if let Some(update) = self.links.get(id) {
+                            update(handle, &locs)?;
+                        }
+                    }
+                    Some(comps)
+                }
+                None => None,
+            };
+            slots.push((generation, comps));
+        }
+        for (id, handle) in ordered {
+            let query = restored.queries.get_mut(&id).unwrap();
+            query.add_handle(handle, &mut restored.alloc)?;
+        }
+        restored.entities = EntityTable::from_slots(slots);
+        if !input.is_empty() {
+            return Err(SnapshotError::Invalid("data after the end of the snapshot"));
+        }
+        Ok(restored)
     }
 }
 
 #[cfg(test)]
 mod spawner_test {
-    use frosty_alloc::FrostyAllocatable;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use frosty_alloc::{AllocError, FrostyAllocatable};
 
-    use crate::{query::Query, Spawner};
+    use crate::{query::Query, Entity, SpawnError, Spawner};
+
+    #[test]
+    fn spawned_components_drop_once() {
+        struct Speaker {
+            text: String,
+            drops: Arc<AtomicUsize>,
+        }
+        impl Drop for Speaker {
+            fn drop(&mut self) {
+                self.drops.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        unsafe impl FrostyAllocatable for Speaker {}
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Speaker>();
+
+        let mut entity = Entity::new();
+        entity.add(Speaker {
+            text: "Hello World!".into(),
+            drops: drops.clone(),
+        });
+        spawner.spawn(entity).expect("Failed to spawn entity");
+        spawner
+            .spawn_obj(Speaker {
+                text: "Bark!".into(),
+                drops: drops.clone(),
+            })
+            .expect("Failed to spawn Speaker");
+        assert_eq!(0, drops.load(Ordering::SeqCst));
+
+        let mut speakers: Query<Speaker> =
+            spawner.get_query(0).expect("Failed to load Speaker Query");
+        assert_eq!("Hello World!", speakers.next(0).unwrap().as_ref().text);
+        assert_eq!("Bark!", speakers.next(0).unwrap().as_ref().text);
+
+        drop(spawner);
+        assert_eq!(2, drops.load(Ordering::SeqCst));
+    }
 
     #[test]
     fn spawn_generic() {
@@ -177,4 +753,203 @@ mod spawner_test {
         );
         assert!(floats.next(0).is_none(), "Too many ints read from Query");
     }
+
+    #[test]
+    fn spawn_errors_are_returned() {
+        struct Marker;
+        struct Unregistered(#[allow(dead_code)] u32);
+        unsafe impl FrostyAllocatable for Marker {}
+        unsafe impl FrostyAllocatable for Unregistered {}
+
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Marker>();
+        assert!(matches!(
+            spawner.spawn_obj(Marker),
+            Err(SpawnError::Alloc(AllocError::ZeroSized))
+        ));
+        assert!(matches!(
+            spawner.spawn_obj(Unregistered(1)),
+            Err(SpawnError::Unregistered(_))
+        ));
+
+        // the components which did spawn are rolled back
+        spawner.register_component::<Unregistered>();
+        let mut entity = Entity::new();
+        entity.add(Unregistered(2));
+        entity.add(Marker);
+        assert!(matches!(
+            spawner.spawn(entity),
+            Err(SpawnError::Alloc(AllocError::ZeroSized))
+        ));
+        assert_eq!(0, spawner.stats().live_slots);
+        assert_eq!(0, spawner.entity_count());
+        let mut query: Query<Unregistered> = spawner.get_query(0).unwrap();
+        assert!(query.next(0).is_none());
+    }
+
+    #[test]
+    fn failed_adds_are_rolled_back() {
+        #[derive(Clone)]
+        struct Wide(#[allow(dead_code)] [u8; 1024]);
+        unsafe impl FrostyAllocatable for Wide {}
+
+        // every object needs room for a double buffered copy
+        let mut spawner = Spawner::with_budget(4096);
+        spawner.register_double_buffered::<Wide>();
+        let err = loop {
+            if let Err(e) = spawner.spawn_batch([Wide([0; 1024]), Wide([1; 1024])]) {
+                break e;
+            }
+        };
+        assert!(matches!(
+            err,
+            SpawnError::Alloc(AllocError::OutOfBudget { .. })
+        ));
+        let entities = spawner.entity_count();
+        let mut query: Query<Wide> = spawner.get_query(0).unwrap();
+        let mut queued = 0;
+        while query.next(0).is_some() {
+            queued += 1;
+        }
+        // added before the copy ran out of room, but not an entity
+        assert!(queued > entities);
+        spawner.remove_despawned().unwrap();
+        let mut query: Query<Wide> = spawner.get_query(0).unwrap();
+        for _ in 0..entities {
+            query.next(0).unwrap();
+        }
+        assert!(query.next(0).is_none());
+        assert_eq!(entities * 2, spawner.stats().live_slots);
+    }
+
+    #[test]
+    fn spawn_within_budget() {
+        #[derive(Clone, Copy)]
+        struct Particle(#[allow(dead_code)] [f32; 16]);
+        unsafe impl FrostyAllocatable for Particle {}
+
+        let mut spawner = Spawner::with_budget(4096);
+        spawner.register_component::<Particle>();
+        let mut spawned = 0;
+        let err = loop {
+            match spawner.spawn_obj(Particle([0.0; 16])) {
+                Ok(_) => spawned += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(
+            err,
+            SpawnError::Alloc(AllocError::OutOfBudget { .. })
+        ));
+        assert!(spawned > 0);
+        assert_eq!(spawned, spawner.stats().of::<Particle>().count);
+        assert_eq!(4096, spawner.stats().capacity);
+    }
+
+    #[test]
+    fn spawn_batch_of_particles() {
+        struct Particle {
+            pos: [f32; 3],
+        }
+        unsafe impl FrostyAllocatable for Particle {}
+
+        let mut spawner = Spawner::new();
+        assert!(matches!(
+            spawner.spawn_batch((0..10).map(|_| Particle { pos: [0.0; 3] })),
+            Err(SpawnError::Unregistered(_))
+        ));
+
+        spawner.register_component::<Particle>();
+        spawner
+            .spawn_obj(Particle { pos: [-1.0; 3] })
+            .expect("Failed to spawn Particle");
+        let ids = spawner
+            .spawn_batch((0..50_000).map(|i| Particle { pos: [i as f32; 3] }))
+            .expect("Failed to spawn Particle batch");
+        assert_eq!(50_001, spawner.stats().of::<Particle>().count);
+        assert_eq!(50_001, spawner.entity_count());
+        let last = spawner.get_component::<Particle>(ids[49_999]).unwrap();
+        assert_eq!(Some(ids[49_999]), spawner.entity_of(&last));
+
+        let mut particles: Query<Particle> =
+            spawner.get_query(0).expect("Failed to load Particle Query");
+        assert_eq!([-1.0; 3], particles.next(0).unwrap().as_ref().pos);
+        for i in 0..50_000 {
+            assert_eq!([i as f32; 3], particles.next(0).unwrap().as_ref().pos);
+        }
+        assert!(particles.next(0).is_none());
+    }
+
+    #[test]
+    fn stats_by_component() {
+        struct Health(#[allow(dead_code)] u32);
+        struct Name(#[allow(dead_code)] String);
+        unsafe impl FrostyAllocatable for Health {}
+        unsafe impl FrostyAllocatable for Name {}
+
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Health>();
+        spawner.register_component::<Name>();
+        for i in 0..3 {
+            let mut entity = Entity::new();
+            entity.add(Health(i));
+            entity.add(Name(format!("Entity {i}")));
+            spawner.spawn(entity).expect("Failed to spawn entity");
+        }
+        spawner
+            .spawn_obj(Health(10))
+            .expect("Failed to spawn Health");
+
+        let stats = spawner.stats();
+        assert_eq!(4, stats.of::<Health>().count);
+        assert_eq!(3, stats.of::<Name>().count);
+        assert_eq!(7, stats.live_slots);
+        assert_eq!(
+            stats.live_bytes,
+            stats.of::<Health>().bytes + stats.of::<Name>().bytes
+        );
+    }
+
+    #[test]
+    fn objects_spawned_by_systems_are_merged() {
+        struct Spark(u32);
+        struct Stray(#[allow(dead_code)] u32);
+        unsafe impl FrostyAllocatable for Spark {}
+        unsafe impl FrostyAllocatable for Stray {}
+
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Spark>();
+        spawner.spawn_obj(Spark(0)).unwrap();
+
+        // what a worker sends back after running a system
+        let mut cache = std::thread::spawn(|| {
+            crate::spawn_local(Spark(1)).unwrap();
+            crate::spawn_local(Stray(2)).unwrap();
+            crate::spawn_local(Spark(3)).unwrap();
+            crate::thread::take_spawned()
+        })
+        .join()
+        .unwrap();
+        assert!(crate::thread::take_spawned().is_empty());
+
+        assert!(matches!(
+            spawner.merge_cache(&mut cache),
+            Err(SpawnError::Unregistered(_))
+        ));
+        assert!(cache.is_empty());
+        let mut sparks: Query<Spark> = spawner.get_query(0).unwrap();
+        for i in [0, 1, 3] {
+            assert_eq!(i, sparks.next(0).unwrap().as_ref().0);
+        }
+        assert!(sparks.next(0).is_none());
+        assert_eq!(3, spawner.stats().live_slots);
+        // merged objects are entities like any other
+        assert_eq!(3, spawner.entity_count());
+        let spark = spawner
+            .get_query::<Spark>(0)
+            .unwrap()
+            .next_handle()
+            .unwrap();
+        assert!(spawner.entity_of(&spark).is_some());
+    }
 }
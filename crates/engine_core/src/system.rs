@@ -1,6 +1,6 @@
 use std::{any::TypeId, task::Poll};
 
-use frosty_alloc::FrostyAllocatable;
+use frosty_alloc::{Erased, Fetch};
 
 use crate::query::Query;
 
@@ -18,7 +18,11 @@ use crate::query::Query;
  * be altered by multiple {System}s.
  *
  * A {System} is an object which defines an [Interop],
- * a query(), and an update(). A query() reads a list of
+ * a query(), and an update(). The [Interop] can be a
+ * tuple like (&A, &mut B), in which case update() is given
+ * the A and B of each (Entity) which has both. An update()
+ * can skip some of them with Query::filtered(), ie. only
+ * look at units which are Without<Dead>. A query() reads a list of
  * [Interop]s loaded, then alters the state of the {System}.
  * An update() reads a list of [Interop]s loaded and alters
  * their state or the {System}'s.
@@ -67,7 +71,10 @@ impl From<Poll<UpdateResult>> for UpdateResult {
 }
 
 pub trait System {
-    type Interop: FrostyAllocatable;
+    // a component, or a tuple of &/&mut components. References
+    // in an associated type need a lifetime, so use 'static:
+    //      type Interop = (&'static Velocity, &'static mut Position);
+    type Interop: Fetch;
     fn update(&self, objs: Query<Self::Interop>) -> UpdateResult;
 }
 
@@ -92,11 +99,160 @@ pub trait SystemInterface: Send + Sync + 'static {
     fn id() -> SystemId
     where
         Self: Sized;
-    fn alloc_id(&self) -> TypeId;
+    // Components in the system's Query, which is built from
+    // the first one. Usually <Self::Interop as Fetch>::ids()
+    fn alloc_ids(&self) -> Vec<TypeId>;
     // NOTE:
     //      currently takes Query by value, so each Interface.update() call
     //      owns the query and thus the system cannot be called across threads
     //      safely. This is fine for continuous systems, but it prevents discrete
     //      ones from being called concurrently
-    fn start_update(&self, objs: Query<u8>) -> UpdateResult;
+    fn start_update(&self, objs: Query<Erased>) -> UpdateResult;
+}
+
+#[cfg(test)]
+mod system_tests {
+    use std::any::TypeId;
+
+    use frosty_alloc::{Erased, Fetch, FrostyAllocatable};
+
+    use super::{System, SystemId, SystemInterface, UpdateResult};
+    use crate::{query::Query, Entity, Spawner};
+
+    struct Position(f32);
+    unsafe impl FrostyAllocatable for Position {}
+
+    struct Velocity(f32);
+    unsafe impl FrostyAllocatable for Velocity {}
+
+    // Moves things, but says it wants Velocity's
+    struct Mover {
+        checked: bool,
+    }
+
+    impl System for Mover {
+        type Interop = Position;
+        fn update(&self, mut objs: Query<Position>) -> UpdateResult {
+            while let Some(mut pos) = objs.next(0) {
+                pos.as_mut().0 += 1.0;
+            }
+            UpdateResult::Skip
+        }
+    }
+
+    impl SystemInterface for Mover {
+        fn dependencies() -> Vec<SystemId> {
+            vec![]
+        }
+        fn id() -> SystemId {
+            SystemId(0)
+        }
+        fn alloc_ids(&self) -> Vec<TypeId> {
+            vec![Velocity::id()]
+        }
+        fn start_update(&self, objs: Query<Erased>) -> UpdateResult {
+            if self.checked {
+                match objs.try_cast() {
+                    Ok(objs) => self.update(objs),
+                    Err(_) => UpdateResult::PollingError,
+                }
+            } else {
+                self.update(unsafe { objs.cast() })
+            }
+        }
+    }
+
+    fn spawner() -> Spawner {
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Position>();
+        spawner.register_component::<Velocity>();
+        spawner.spawn_obj(Position(0.0)).unwrap();
+        spawner.spawn_obj(Velocity(2.0)).unwrap();
+        spawner
+    }
+
+    #[test]
+    fn wrong_alloc_id_is_caught() {
+        let spawner = spawner();
+        let mover = Mover { checked: true };
+        let objs = spawner.get_query_by_ids(&mover.alloc_ids(), 0).unwrap();
+        assert_eq!(Velocity::id(), objs.type_id());
+        assert_eq!(UpdateResult::PollingError, mover.start_update(objs));
+
+        let mut vels: Query<Velocity> = spawner.get_query(0).unwrap();
+        assert_eq!(2.0, vels.next(0).unwrap().as_ref().0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "unchecked cast to Query<")]
+    fn wrong_alloc_id_asserts() {
+        let spawner = spawner();
+        let mover = Mover { checked: false };
+        let objs = spawner.get_query_by_ids(&mover.alloc_ids(), 0).unwrap();
+        mover.start_update(objs);
+    }
+
+    // Moves every entity with both a Position and a Velocity
+    struct Integrate;
+
+    impl System for Integrate {
+        type Interop = (&'static Velocity, &'static mut Position);
+        fn update(&self, mut objs: Query<Self::Interop>) -> UpdateResult {
+            while let Some((vel, mut pos)) = objs.next(0) {
+                pos.as_mut().0 += vel.as_ref().0;
+            }
+            UpdateResult::Skip
+        }
+    }
+
+    impl SystemInterface for Integrate {
+        fn dependencies() -> Vec<SystemId> {
+            vec![]
+        }
+        fn id() -> SystemId {
+            SystemId(1)
+        }
+        fn alloc_ids(&self) -> Vec<TypeId> {
+            <Self as System>::Interop::ids()
+        }
+        fn start_update(&self, objs: Query<Erased>) -> UpdateResult {
+            match objs.try_cast() {
+                Ok(objs) => self.update(objs),
+                Err(_) => UpdateResult::PollingError,
+            }
+        }
+    }
+
+    #[test]
+    fn tuple_interops_join_by_entity() {
+        let mut spawner = Spawner::new();
+        spawner.register_component::<Position>();
+        spawner.register_component::<Velocity>();
+        let mut moving = Entity::new();
+        moving.add(Position(1.0));
+        moving.add(Velocity(2.0));
+        let moving = spawner.spawn(moving).unwrap();
+        let still = spawner.spawn_obj(Position(5.0)).unwrap();
+        spawner.spawn_obj(Velocity(3.0)).unwrap();
+
+        let system = Integrate;
+        assert_eq!(vec![Velocity::id(), Position::id()], system.alloc_ids());
+        let objs = spawner.get_query_by_ids(&system.alloc_ids(), 1).unwrap();
+        assert_eq!(UpdateResult::Skip, system.start_update(objs));
+
+        let pos = |id| {
+            let mut handle = spawner.get_component::<Position>(id).unwrap();
+            let x = handle.get_access(0).unwrap().as_ref().0;
+            x
+        };
+        assert_eq!((3.0, 5.0), (pos(moving), pos(still)));
+
+        assert!(spawner
+            .get_query_by_ids(&[Position::id(), Position::id()], 0)
+            .is_none());
+        assert!(spawner
+            .get_query::<(&Velocity, &mut Position, &u32)>(0)
+            .is_none());
+    }
 }
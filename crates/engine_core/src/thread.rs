@@ -1,3 +1,4 @@
+use std::cell::RefCell;
 use std::future::Future;
 use std::io;
 use std::mem::MaybeUninit;
@@ -6,6 +7,8 @@ use std::sync::mpsc::{channel, Receiver, Sender};
 use std::task::{Context, Poll, Waker};
 use std::thread::JoinHandle;
 
+use frosty_alloc::{AllocError, Erased, FrostyAllocatable, LocalCache};
+
 use crate::query::Query;
 use crate::schedule::{NextSystem, Schedule, SystemNode, SystemNodeRaw};
 use crate::system::UpdateResult;
@@ -29,7 +32,8 @@ use crate::Spawner;
 // into the data passed into each system. This allows for an arbitrary amount
 // of read accesses and only one write access. Safely in quotes here means
 // that data will always be in some valid state, to make sure data is updated
-// deterministically you should use commutative functions
+// deterministically you should use commutative functions, or double buffer
+// the component so systems read last frame's value (see buffered.rs)
 //
 // Not all data is stored behind semaphores. The examples useres are most likely
 // to run into are Querys and InputHandler. While for the most part these objects
@@ -39,8 +43,29 @@ use crate::Spawner;
 // are used double buffers in these instances to allow for message passing. When
 // the master thread is in a single threaded context it can then update all the objects.
 
+// Systems can't reach the Spawner, so objects they spawn are kept
+// by the worker in a LocalCache. It is sent back along with the
+// system's result, then the master thread moves everything into the
+// Spawner once the frame's systems have all finished
+
+thread_local! {
+    static SPAWNED: RefCell<LocalCache> = RefCell::new(LocalCache::new());
+}
+
+// Spawn an object from inside a system. It is added to the
+// Query for its type at the end of the frame, and is dropped
+// if the type was never registered
+pub fn spawn_local<C: FrostyAllocatable>(obj: C) -> Result<(), AllocError> {
+    SPAWNED.with_borrow_mut(|cache| cache.alloc(obj))
+}
+
+// Everything spawned on this thread since the last call
+pub(crate) fn take_spawned() -> LocalCache {
+    SPAWNED.with_borrow_mut(std::mem::take)
+}
+
 // The data needed to run a system
-type SystemData = (SystemNodeRaw, Query<u8>);
+type SystemData = (SystemNodeRaw, Query<Erased>);
 
 pub(crate) enum AppAlert {
     CloseApp,
@@ -52,6 +77,7 @@ pub(crate) enum AppAlert {
 struct ThreadReturn {
     system_update: UpdateResult,
     system_node: SystemNodeRaw,
+    spawned: LocalCache,
 }
 
 impl From<Poll<ThreadReturn>> for ThreadReturn {
@@ -121,6 +147,7 @@ impl SystemThread {
                 .send(ThreadReturn {
                     system_update: update,
                     system_node: system,
+                    spawned: take_spawned(),
                 })
                 .expect("Failed to send output from system");
         })?;
@@ -136,10 +163,11 @@ pub(crate) struct ThreadPool {
 
 impl ThreadPool {
     pub(crate) fn new() -> io::Result<Self> {
-        // TODO:
-        //  find number of cores for maximum threading
-        //  efficiency
-        let thread_count = 4;
+        // one worker per core. Component semaphores don't limit
+        // how many threads can share them
+        let thread_count = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4);
         let mut threads = Vec::with_capacity(thread_count);
         for thread in 0..thread_count {
             let thread_builder = std::thread::Builder::new().name(format!("worker_{:?}", thread));
@@ -153,7 +181,7 @@ impl ThreadPool {
 
     async fn run_system(
         system: SystemNodeRaw,
-        query: Query<u8>,
+        query: Query<Erased>,
         thread: &SystemThread,
     ) -> ThreadReturn {
         thread
@@ -178,7 +206,7 @@ impl<'a> ThreadPool {
         let fut = Self::run_system(
             sys.get_raw(),
             alloc
-                .get_query_by_id(&sys.alloc_id(), thread_id as u32)
+                .get_query_by_ids(&sys.alloc_ids(), thread_id as u32)
                 .unwrap(),
             &self.threads[thread_id],
         );
@@ -213,6 +241,7 @@ impl<'a> ThreadPool {
         let mut futures: Vec<Option<PinnedFuture<'a>>> = self.prepare_futures(alloc, schedule);
 
         let mut close_requested = false;
+        let mut spawned = Vec::new();
 
         while !all_finished {
             all_finished = true;
@@ -249,6 +278,9 @@ impl<'a> ThreadPool {
                 // handle close logic
                 close_requested =
                     close_requested || system_output.system_update == UpdateResult::CloseApp;
+                if !system_output.spawned.is_empty() {
+                    spawned.push(system_output.spawned);
+                }
                 schedule.return_node(system_output.system_node);
 
                 // ask schedule what to do next
@@ -267,6 +299,19 @@ impl<'a> ThreadPool {
             }
         }
 
+        // every system is done, so what they spawned can be added
+        for mut cache in spawned {
+            if let Err(e) = alloc.merge_cache(&mut cache) {
+                eprintln!("Failed to spawn from a system: {e}");
+            }
+        }
+        // nothing is iterating Querys anymore
+        if let Err(e) = alloc.remove_despawned() {
+            eprintln!("Failed to free despawned components: {e}");
+        }
+        // what was written this frame is now last frame's change
+        alloc.advance_tick();
+
         if close_requested {
             AppAlert::CloseApp
         } else {
@@ -1,13 +1,17 @@
 use std::{
     marker::PhantomData,
     ptr::{self, NonNull},
+    sync::atomic::Ordering,
 };
 
+#[cfg(feature = "free-poison")]
+use crate::poison::PoisonLog;
 use crate::{
-    chunk::{Chunk, OrderedChunkList},
-    frosty_box::FrostyBox,
-    interim::InterimPtr,
-    FrostyAllocatable, ObjectHandle, ObjectHandleMut,
+    chunk::{Chunk, ChunkBins},
+    frosty_box::{BitMask, FrostyBox},
+    interim::{InterimPtr, InterimTable},
+    page::{Page, PAGE_ALIGN, PAGE_SIZE},
+    AllocError, AllocatorStats, FrostyAllocatable, ObjectHandle, ObjectHandleMut,
 };
 
 pub type Index = usize;
@@ -18,6 +22,17 @@ pub type Index = usize;
 // keep them in close proximity, and to make them persist
 // across frame updates.
 //
+// The region is made up of fixed size [Page]s. When the
+// region needs to grow a new page is added rather than
+// reallocating the old ones, so [FrostyBox]es never move
+// and any pointer into them stays valid across a resize.
+// Objects too large to fit in a page get a dedicated page.
+//
+// The region can be capped with a budget, after which any
+// allocation which would need a new page fails. It can also
+// live in a buffer given by the caller, in which case it can
+// never grow past that buffer.
+//
 // This object does not keep track of where objects are
 // stored in its region. Data passed in is stored in a
 // [FrostyBox], the address of which is returned to the
@@ -26,9 +41,15 @@ pub type Index = usize;
 // there. When memory is requested to be free'd, it also
 // assumes a valid address is given and frees it.
 pub struct Allocator {
-    chunks: OrderedChunkList,
-    region: Vec<u8>,
-    interim: Vec<InterimPtr>,
+    chunks: ChunkBins,
+    pages: Vec<Page>,
+    interim: InterimTable,
+    // times the region has grown to fit an allocation
+    resizes: usize,
+    // most bytes the pages are allowed to hold
+    budget: Option<usize>,
+    #[cfg(feature = "free-poison")]
+    poison: PoisonLog,
 }
 
 impl Allocator {
@@ -36,173 +57,533 @@ impl Allocator {
         Self::with_capacity(4)
     }
 
-    pub fn with_capacity(capacity: usize) -> Self {
-        let mut region = Vec::with_capacity(capacity);
-        // using region.fill(0) does not properly init data
-        for _ in 0..capacity {
-            region.push(0);
-        }
-        let major_chunk = Chunk {
-            start: 0,
-            len: region.capacity(),
-        };
-        let mut chunks = OrderedChunkList::new();
-        chunks.add(major_chunk);
+    fn empty(budget: Option<usize>) -> Self {
         Self {
-            chunks,
-            region,
-            interim: Vec::new(),
+            chunks: ChunkBins::new(),
+            pages: Vec::new(),
+            interim: InterimTable::new(),
+            resizes: 0,
+            budget,
+            #[cfg(feature = "free-poison")]
+            poison: PoisonLog::new(),
         }
     }
 
-    // increases capacity of region and returns
-    // the previous capacity
-    unsafe fn resize(&mut self, min_len: usize) -> usize {
-        let old_len = self.region.len();
-        self.region.reserve(self.region.capacity() * 2 + min_len);
-        // need to init memory
-        // TODO:
-        //      is there some built-in that allows for better SIMD?
-        for _ in old_len..self.region.capacity() {
-            self.region.push(0);
+    // Creates an allocator with enough pages to hold
+    // at least (capacity) bytes
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut alloc = Self::empty(None);
+        let mut reserved = 0;
+        while reserved < capacity.max(1) {
+            let chunk = alloc
+                .resize(PAGE_SIZE, PAGE_ALIGN)
+                .expect("Failed to reserve allocator capacity");
+            reserved += chunk.len;
+            alloc.chunks.add(chunk);
         }
-        for inter in &mut self.interim {
-            let data_start = self.region.get_unchecked_mut(inter.index);
-            let ptr = data_start as *mut u8;
-            inter.data = NonNull::new(ptr).unwrap();
+        alloc
+    }
+
+    // Creates an allocator whose region will never grow past
+    // (max_bytes). Pages are only added once they are needed
+    pub fn with_budget(max_bytes: usize) -> Self {
+        Self::empty(Some(max_bytes))
+    }
+
+    // Creates an allocator whose region is (buffer). The region
+    // can't grow, so allocations fail once the buffer is full.
+    // The start of the buffer may be skipped to align it
+    pub fn from_buffer(buffer: &'static mut [u8]) -> Result<Self, AllocError> {
+        let page = Page::from_buffer(buffer)?;
+        let mut alloc = Self::empty(Some(page.len()));
+        alloc.chunks.add(Chunk {
+            page: 0,
+            start: 0,
+            len: page.len(),
+        });
+        alloc.pages.push(page);
+        Ok(alloc)
+    }
+
+    // Total number of bytes held across all pages
+    pub fn capacity(&self) -> usize {
+        self.pages.iter().map(|p| p.len()).sum()
+    }
+
+    pub fn budget(&self) -> Option<usize> {
+        self.budget
+    }
+
+    // increases capacity of region by adding a page of at
+    // least (min_len) bytes and returns a [Chunk] spanning
+    // all of it. Existing pages are left untouched, so
+    // nothing needs to be relocated. If there is a budget, the
+    // page is shrunk to fit what is left of it
+    fn resize(&mut self, min_len: usize, align: usize) -> Result<Chunk, AllocError> {
+        let mut len = min_len.max(PAGE_SIZE);
+        if let Some(budget) = self.budget {
+            let remaining = budget.saturating_sub(self.capacity());
+            if min_len > remaining {
+                return Err(AllocError::OutOfBudget { requested: min_len });
+            }
+            len = len.min(remaining);
         }
-        old_len
+        let page = Page::new(len, align)?;
+        let chunk = Chunk {
+            page: self.pages.len(),
+            start: 0,
+            len: page.len(),
+        };
+        self.pages.push(page);
+        Ok(chunk)
     }
 
-    // Returns index into Interim vec
-    pub fn alloc<T: FrostyAllocatable>(&mut self, obj: T) -> Result<ObjectHandleMut<T>, ()> {
-        let size = std::mem::size_of::<FrostyBox<T>>();
-        let mut chunk = match self.chunks.get_best_fit(size) {
-            Some(c) => c,
-            None => unsafe {
-                // increase capacity, this is pretty bad for obvious reasons
-                // SystemVec<> will be created to avoid this
-                let old_len = self.resize(size);
-                Chunk {
-                    start: old_len,
-                    len: self.region.capacity() - old_len,
-                }
+    // Find a chunk which can hold (size) bytes starting at an
+    // offset aligned to (align), growing the region if none exist
+    fn claim_chunk(&mut self, size: usize, align: usize) -> Result<Chunk, AllocError> {
+        self.reclaim();
+        let chunk = self.find_chunk(size, align)?;
+        // only the part which is about to be written is checked, the
+        // rest goes back to the free list and is checked when used
+        #[cfg(feature = "free-poison")]
+        self.poison.claim(
+            &self.pages[chunk.page],
+            &Chunk {
+                len: size.min(chunk.len),
+                ..chunk
             },
-        };
+        );
+        Ok(chunk)
+    }
 
-        let boxed_obj = FrostyBox::new(obj);
-        let data_index = chunk.start;
-        let interim = unsafe {
-            let init_ptr = self.region.get_unchecked_mut(chunk.start) as *mut u8;
-            ptr::write_unaligned(init_ptr as *mut FrostyBox<T>, boxed_obj);
-            InterimPtr {
-                freed: false,
-                active_handles: 0,
-                data: NonNull::new(init_ptr as *mut u8).unwrap(),
-                index: data_index,
+    fn find_chunk(&mut self, size: usize, align: usize) -> Result<Chunk, AllocError> {
+        // pages are only guaranteed to be aligned to PAGE_ALIGN,
+        // so anything stricter needs a page of its own
+        if align <= PAGE_ALIGN {
+            if let Some(c) = self.chunks.get_best_fit(size, align) {
+                return Ok(c);
             }
-        };
+        }
+        // increase capacity, this is pretty bad for obvious reasons
+        // SystemVec<> will be created to avoid this
+        let chunk = self.resize(size, align)?;
+        self.resizes += 1;
+        Ok(chunk)
+    }
 
+    // Write (boxed) to the start of (chunk) and shrink (chunk) to
+    // the memory after it. The start of (chunk) must be aligned
+    // for FrostyBox<T>
+    unsafe fn write_box<T: FrostyAllocatable>(
+        &mut self,
+        chunk: &mut Chunk,
+        boxed: FrostyBox<T>,
+    ) -> Index {
+        let size = std::mem::size_of::<FrostyBox<T>>();
+        let init_ptr = self.pages[chunk.page].ptr_at(chunk.start);
+        debug_assert!(init_ptr.cast::<FrostyBox<T>>().is_aligned());
+        ptr::write(init_ptr.as_ptr() as *mut FrostyBox<T>, boxed);
+        let slot = self.interim.insert::<T>(init_ptr, chunk.page, chunk.start);
         chunk.reduce(size);
+        slot
+    }
+
+    // Write (boxed) to the start of (chunk), returning any
+    // leftover memory to the free list
+    unsafe fn place<T: FrostyAllocatable>(
+        &mut self,
+        mut chunk: Chunk,
+        boxed: FrostyBox<T>,
+    ) -> Index {
+        let slot = self.write_box(&mut chunk, boxed);
         if chunk.len > 0 {
             self.chunks.add(chunk);
         }
+        slot
+    }
 
-        self.interim.push(interim);
-        let interim_index = self.interim.len() - 1;
-        Ok(ObjectHandleMut {
-            ptr: NonNull::new(
-                self.interim
-                    .get_mut(interim_index)
-                    .expect("Allocator Interim Vec has invalid size")
-                    as *mut InterimPtr,
-            )
-            .expect("Failed to create NonNull interim Pointer"),
+    // Find a chunk able to hold (count) FrostyBox<T>s back to back.
+    // Zero sized types are rejected since there is no data
+    // to keep track of
+    fn claim_chunk_for<T: FrostyAllocatable>(&mut self, count: usize) -> Result<Chunk, AllocError> {
+        if std::mem::size_of::<T>() == 0 {
+            return Err(AllocError::ZeroSized);
+        }
+        // size is always a multiple of align, so every box
+        // after the first is aligned as well
+        let size = std::mem::size_of::<FrostyBox<T>>();
+        let align = std::mem::align_of::<FrostyBox<T>>();
+        let total = size.checked_mul(count).ok_or(AllocError::OutOfMemory {
+            requested: usize::MAX,
+        })?;
+        self.claim_chunk(total, align)
+    }
+
+    fn handle_for<T: FrostyAllocatable>(&mut self, slot: Index) -> ObjectHandleMut<T> {
+        let ptr = self
+            .interim
+            .ptr(slot)
+            .expect("Allocator Interim Table has invalid size");
+        let interim = unsafe { ptr.as_ref() };
+        interim.retain();
+        ObjectHandleMut {
+            generation: interim.generation(),
+            ptr,
             _pd: PhantomData,
-        })
+        }
     }
 
-    pub fn alloc_raw<T: FrostyAllocatable>(&mut self, data: *const T) -> Result<Index, ()> {
-        let size = std::mem::size_of::<FrostyBox<T>>();
-        let mut chunk = match self.chunks.get_best_fit(size) {
-            Some(c) => c,
-            None => unsafe {
-                // increase capacity, this is pretty bad for obvious reasons
-                // SystemVec<> will be created to avoid this
-                let old_len = self.resize(size);
-                Chunk {
-                    start: old_len,
-                    len: self.region.capacity() - old_len,
-                }
-            },
-        };
+    // Returns index into Interim vec
+    pub fn alloc<T: FrostyAllocatable>(
+        &mut self,
+        obj: T,
+    ) -> Result<ObjectHandleMut<T>, AllocError> {
+        let chunk = self.claim_chunk_for::<T>(1)?;
 
-        let data_index = chunk.start;
-        let interim = unsafe {
-            // create a frostybox
-            let boxed_data: FrostyBox<T> = FrostyBox::from_raw(data);
-            // load that box
-            let init_ptr = self.region.get_unchecked_mut(chunk.start) as *mut u8;
-            ptr::write_unaligned(init_ptr as *mut FrostyBox<T>, boxed_data);
-            InterimPtr {
-                freed: false,
-                active_handles: 0,
-                data: NonNull::new(init_ptr as *mut u8).unwrap(),
-                index: data_index,
-            }
-        };
+        let boxed_obj = FrostyBox::new(obj);
+        let interim_index = unsafe { self.place(chunk, boxed_obj) };
+        Ok(self.handle_for(interim_index))
+    }
 
-        chunk.reduce(size);
+    // Allocate every object in (objs) back to back in a single
+    // chunk, which only needs one search of the free list and
+    // keeps the objects together for iteration. Handles are
+    // returned in the same order as (objs). Each object can
+    // still be free'd on its own
+    pub fn alloc_many<T, I>(&mut self, objs: I) -> Result<Vec<ObjectHandleMut<T>>, AllocError>
+    where
+        T: FrostyAllocatable,
+        I: IntoIterator<Item = T>,
+    {
+        let objs: Vec<T> = objs.into_iter().collect();
+        if objs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut chunk = self.claim_chunk_for::<T>(objs.len())?;
+
+        let mut handles = Vec::with_capacity(objs.len());
+        for obj in objs {
+            let slot = unsafe { self.write_box(&mut chunk, FrostyBox::new(obj)) };
+            handles.push(self.handle_for(slot));
+        }
         if chunk.len > 0 {
             self.chunks.add(chunk);
         }
+        Ok(handles)
+    }
+
+    // Allocate an object which is free'd once its last strong
+    // handle is dropped. Weak handles don't keep it around.
+    // The memory is given back the next time something is
+    // allocated, or when reclaim() is called
+    pub fn alloc_shared<T: FrostyAllocatable>(
+        &mut self,
+        obj: T,
+    ) -> Result<ObjectHandleMut<T>, AllocError> {
+        let handle = self.alloc(obj)?;
+        handle.interim().auto_free.store(true, Ordering::Release);
+        Ok(handle)
+    }
+
+    // If an error is returned (data) has not been read from,
+    // so the caller still owns it
+    pub fn alloc_raw<T: FrostyAllocatable>(&mut self, data: *const T) -> Result<Index, AllocError> {
+        let chunk = self.claim_chunk_for::<T>(1)?;
 
-        self.interim.push(interim);
-        Ok(self.interim.len() - 1)
+        // create a frostybox
+        let boxed_data: FrostyBox<T> = FrostyBox::from_raw(data);
+        // load that box
+        Ok(unsafe { self.place(chunk, boxed_data) })
     }
 
     // since the region is completely controlled by [Allocator], the
-    // data is free if we say it is. If data has any important Drop
-    // functionality, that should be taken care of before free() is
-    // called
+    // data is free if we say it is. The destructor recorded when the
+    // object was allocated is run before its memory is returned, so
+    // this works for handles whose type has been dissolved as well
     // The [ObjectHandle] passed in isn't dropped immediatly. Due to
     // [InterimPtr] being free'd, the handle will no longer be able
-    // to access the data
-    pub fn free<T: FrostyAllocatable>(&mut self, obj: &mut ObjectHandle<T>) {
-        let ptr = obj.get_mut();
-        let size = std::mem::size_of::<FrostyBox<T>>();
+    // to access the data. The interim slot is then recycled for the
+    // next allocation, with a new generation so that stale handles
+    // cannot read whatever object moves into it.
+    // An object which is still being accessed isn't dropped until
+    // the access is gone, see reclaim().
+    // Freeing through a stale handle does nothing.
+    pub fn free<T: FrostyAllocatable>(
+        &mut self,
+        obj: &mut ObjectHandleMut<T>,
+    ) -> Result<(), AllocError> {
+        let generation = obj.generation;
+        let ptr = obj.interim();
+        if !ptr.is_live(generation) {
+            return Err(AllocError::HandleFreed);
+        }
+        let slot = ptr.slot;
+        self.free_slot(slot);
+        Ok(())
+    }
+
+    fn free_slot(&mut self, slot: Index) {
+        // objects still being accessed keep their memory until
+        // they are dropped by reclaim()
+        if self.interim.release(slot) {
+            self.return_memory(slot);
+        }
+    }
+
+    // Give the memory of a dropped object back to the free list
+    fn return_memory(&mut self, slot: Index) {
+        let Some(ptr) = self.interim.get_mut(slot) else {
+            return;
+        };
         let freed_chunk = Chunk {
+            page: ptr.page,
             start: ptr.index,
-            len: size,
+            len: ptr.size,
         };
-        ptr.free();
+        #[cfg(feature = "free-poison")]
+        let (align, type_id, type_name) = (ptr.align, ptr.type_id, ptr.type_name);
+        #[cfg(feature = "free-poison")]
+        self.poison.free(
+            &self.pages[freed_chunk.page],
+            &freed_chunk,
+            align,
+            type_id,
+            type_name,
+        );
         self.chunks.add(freed_chunk);
     }
 
-    pub unsafe fn get<T: FrostyAllocatable>(&mut self, index: Index) -> Option<ObjectHandle<T>> {
-        let interim = self.interim.get_mut(index)?;
-        interim.active_handles += 1;
-        Some(ObjectHandle {
-            ptr: NonNull::new(interim as *mut InterimPtr).unwrap(),
+    // Panics if any free'd memory has been written to
+    // see the `free-poison` feature
+    #[cfg(feature = "free-poison")]
+    pub fn verify_freed(&self) {
+        for chunk in self.chunks.iter() {
+            self.poison.verify(&self.pages[chunk.page], chunk);
+        }
+    }
+
+    // Free every shared object whose strong handles have all been
+    // dropped, drop objects which were free'd while being accessed,
+    // and reuse slots which were waiting on handles to be dropped.
+    // Returns the number of objects free'd.
+    // Objects still being accessed are tried again next time
+    pub fn reclaim(&mut self) -> usize {
+        let mut freed = 0;
+        for slot in self.interim.drop_undropped() {
+            self.return_memory(slot);
+            freed += 1;
+        }
+        let mut locked = Vec::new();
+        for (slot, generation) in self.interim.take_released() {
+            let Some(inter) = self.interim.get(slot) else {
+                continue;
+            };
+            if !inter.is_live(generation) {
+                self.interim.unpark(slot);
+                continue;
+            }
+            // upgraded again by the time it was reached
+            if !inter.is_auto_free() || inter.strong_count() > 0 {
+                continue;
+            }
+            // a [DataAccess] can outlive the handle it came from
+            if !unsafe { inter.data.cast::<BitMask>().as_ref() }.is_unlocked() {
+                locked.push((slot, generation));
+                continue;
+            }
+            self.free_slot(slot);
+            freed += 1;
+        }
+        self.interim.requeue(locked);
+        freed
+    }
+
+    // Find the interim at (index), making sure it is still live and
+    // holds a T. [Erased] is accepted for any type, since it is
+    // the type of dissolved handles
+    fn checked_interim<T: FrostyAllocatable>(
+        &mut self,
+        index: Index,
+    ) -> Result<NonNull<InterimPtr>, AllocError> {
+        let ptr = match self.interim.ptr(index) {
+            Some(ptr) if !unsafe { ptr.as_ref() }.is_freed() => ptr,
+            _ => return Err(AllocError::HandleFreed),
+        };
+        unsafe { ptr.as_ref() }.check_type::<T>()?;
+        Ok(ptr)
+    }
+
+    pub unsafe fn get<T: FrostyAllocatable>(
+        &mut self,
+        index: Index,
+    ) -> Result<ObjectHandle<T>, AllocError> {
+        let ptr = self.checked_interim::<T>(index)?;
+        let interim = unsafe { ptr.as_ref() };
+        interim.retain();
+        Ok(ObjectHandle {
+            generation: interim.generation(),
+            ptr,
             _pd: PhantomData {},
         })
     }
 
-    pub fn get_mut<T: FrostyAllocatable>(&mut self, index: Index) -> Option<ObjectHandleMut<T>> {
-        let interim = self.interim.get_mut(index)?;
-        interim.active_handles += 1;
-        Some(ObjectHandleMut {
-            ptr: NonNull::new(interim as *mut InterimPtr).unwrap(),
+    pub fn get_mut<T: FrostyAllocatable>(
+        &mut self,
+        index: Index,
+    ) -> Result<ObjectHandleMut<T>, AllocError> {
+        let ptr = self.checked_interim::<T>(index)?;
+        let interim = unsafe { ptr.as_ref() };
+        interim.retain();
+        Ok(ObjectHandleMut {
+            generation: interim.generation(),
+            ptr,
             _pd: PhantomData {},
         })
     }
+
+    // Objects record the current tick when they are allocated
+    // or written to. see ObjectHandleMut::ticks()
+    pub fn tick(&self) -> u32 {
+        self.interim.tick()
+    }
+
+    pub fn advance_tick(&mut self) {
+        self.interim.advance_tick();
+    }
+
+    // Collect a snapshot of how the region is being used
+    pub fn stats(&self) -> AllocatorStats {
+        let mut stats = AllocatorStats {
+            capacity: self.capacity(),
+            free_chunks: self.chunks.len(),
+            resizes: self.resizes,
+            ..Default::default()
+        };
+        for chunk in self.chunks.iter() {
+            stats.free_bytes += chunk.len;
+            stats.largest_free_chunk = stats.largest_free_chunk.max(chunk.len);
+        }
+        for inter in self.interim.iter().filter(|i| !i.is_freed()) {
+            stats.live_slots += 1;
+            stats.live_bytes += inter.size;
+            let of_type = stats.types.entry(inter.type_id).or_default();
+            of_type.count += 1;
+            of_type.bytes += inter.size;
+        }
+        // includes slots still waiting on handles to be dropped
+        stats.freed_slots = self.interim.len() - stats.live_slots;
+        stats
+    }
+
+    // Slide every live object in each page towards the start of
+    // the page, merging the gaps left behind by free'd objects
+    // into a single chunk at the end of the page. Handles go
+    // through an [InterimPtr], so moving an object only requires
+    // updating its interim.
+    // Each object is write locked while it is moved, so no access
+    // can be made to it halfway through. Objects which are locked,
+    // or have a writer waiting on them, are pinned in place since
+    // something is holding a raw ptr to them. This should be called
+    // between frames on the master thread, when no accesses should
+    // be alive.
+    // Returns the number of bytes returned to the end of pages
+    pub fn compact(&mut self) -> usize {
+        self.reclaim();
+        #[cfg(feature = "free-poison")]
+        self.verify_freed();
+        // objects waiting to be dropped are still in use
+        let mut live: Vec<&mut InterimPtr> = self
+            .interim
+            .iter_mut()
+            .filter(|i| !i.is_freed() || i.is_undropped())
+            .collect();
+        live.sort_by_key(|i| (i.page, i.index));
+
+        let mut chunks = ChunkBins::new();
+        let mut reclaimed = 0;
+        let mut live = live.into_iter().peekable();
+        for (page_index, page) in self.pages.iter().enumerate() {
+            // end of the last object placed in this page
+            let mut cursor: usize = 0;
+            let mut old_end = 0;
+            while let Some(inter) = live.next_if(|i| i.page == page_index) {
+                old_end = inter.index + inter.size;
+                // [FrostyBox] is repr(C), so the semaphore is at its start
+                let locked = unsafe { inter.data.cast::<BitMask>().as_ref() }.try_lock_idle();
+                let new_index = match locked {
+                    true => cursor.next_multiple_of(inter.align),
+                    false => inter.index,
+                };
+                if new_index < inter.index {
+                    unsafe {
+                        let dst = page.ptr_at(new_index);
+                        ptr::copy(inter.data.as_ptr(), dst.as_ptr(), inter.size);
+                        inter.data = dst;
+                    }
+                    inter.index = new_index;
+                }
+                // the lock was moved along with the object
+                if locked {
+                    unsafe { inter.data.cast::<BitMask>().as_ref() }.drop_write_access();
+                }
+                if new_index > cursor {
+                    chunks.add(Chunk {
+                        page: page_index,
+                        start: cursor,
+                        len: new_index - cursor,
+                    });
+                }
+                cursor = new_index + inter.size;
+            }
+            reclaimed += old_end.saturating_sub(cursor);
+            if cursor < page.len() {
+                chunks.add(Chunk {
+                    page: page_index,
+                    start: cursor,
+                    len: page.len() - cursor,
+                });
+            }
+        }
+        self.chunks = chunks;
+        // objects have been moved over the old free memory
+        #[cfg(feature = "free-poison")]
+        self.poison.reset(&self.pages, self.chunks.iter());
+        reclaimed
+    }
+}
+
+// Objects still alive in the region are owned by the [Allocator],
+// so their destructors have to run before the pages are released
+impl Drop for Allocator {
+    fn drop(&mut self) {
+        for inter in self.interim.iter() {
+            // includes objects free'd while they were locked
+            if !inter.is_freed() || inter.is_undropped() {
+                // nothing else can reach the objects, since the
+                // pages are about to be released
+                unsafe { inter.drop_data() };
+                inter.free();
+            }
+        }
+        // the table itself is kept alive by any handles left over
+    }
 }
 
 #[cfg(test)]
 mod allocator_tests {
     use std::any::TypeId;
+    use std::mem::ManuallyDrop;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Barrier,
+    };
+    use std::thread;
 
-    use crate::{AllocId, FrostyAllocatable};
+    use crate::{
+        frosty_box::FrostyBox,
+        page::{Page, PAGE_ALIGN, PAGE_SIZE},
+        AllocError, DynObjectHandle, Erased, FrostyAllocatable, ObjectHandleMut,
+    };
 
     use super::Allocator;
 
@@ -299,4 +680,904 @@ mod allocator_tests {
                 .data[0]
         );
     }
+
+    #[test]
+    fn resize_keeps_addresses() {
+        let mut alloc = Allocator::new();
+        let mut first = alloc.alloc(42u64).unwrap();
+        let (addr, pages) = {
+            let access = first.get_access(0).unwrap();
+            (access.as_ref() as *const u64, alloc.pages.len())
+        };
+
+        // fill well past the first page
+        let mut others = Vec::new();
+        for i in 0..(4 * PAGE_SIZE / std::mem::size_of::<u64>()) {
+            others.push(alloc.alloc(i as u64).unwrap());
+        }
+        assert!(alloc.pages.len() > pages);
+
+        let access = first.get_access(0).unwrap();
+        assert_eq!(addr, access.as_ref() as *const u64);
+        assert_eq!(42, unsafe { *addr });
+        for (i, handle) in others.iter_mut().enumerate() {
+            assert_eq!(i as u64, *handle.get_access(0).unwrap().as_ref());
+        }
+    }
+
+    #[test]
+    fn oversized_object_gets_dedicated_page() {
+        struct Huge {
+            data: [u8; 2 * PAGE_SIZE],
+        }
+        unsafe impl FrostyAllocatable for Huge {}
+
+        let mut alloc = Allocator::new();
+        let mut small = alloc.alloc(1u32).unwrap();
+        let pages = alloc.pages.len();
+        let mut huge = alloc
+            .alloc(Huge {
+                data: [7; 2 * PAGE_SIZE],
+            })
+            .unwrap();
+        assert_eq!(pages + 1, alloc.pages.len());
+        assert!(alloc.pages.last().unwrap().len() >= 2 * PAGE_SIZE);
+        assert!(huge
+            .get_access(0)
+            .unwrap()
+            .as_ref()
+            .data
+            .iter()
+            .all(|b| *b == 7));
+        assert_eq!(1, *small.get_access(0).unwrap().as_ref());
+    }
+
+    #[repr(align(64))]
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct OverAligned {
+        lanes: [f32; 4],
+    }
+    unsafe impl FrostyAllocatable for OverAligned {}
+
+    #[repr(align(8192))]
+    struct PageAligned {
+        data: u8,
+    }
+    unsafe impl FrostyAllocatable for PageAligned {}
+
+    fn assert_aligned<T: FrostyAllocatable>(handle: &mut ObjectHandleMut<T>) {
+        let access = handle.get_access(0).expect("Failed to access aligned data");
+        let addr = access.as_ref() as *const T as usize;
+        assert_eq!(0, addr % std::mem::align_of::<T>());
+    }
+
+    #[test]
+    fn alloc_aligned_u128() {
+        let mut alloc = Allocator::new();
+        // offset everything after this by a byte aligned value
+        let _ = alloc.alloc(1u8).unwrap();
+        let mut handles: Vec<_> = (0..8u128).map(|i| alloc.alloc(i << 70).unwrap()).collect();
+        for (i, handle) in handles.iter_mut().enumerate() {
+            assert_aligned(handle);
+            assert_eq!((i as u128) << 70, *handle.get_access(0).unwrap().as_ref());
+        }
+    }
+
+    #[test]
+    fn alloc_aligned_f64() {
+        let mut alloc = Allocator::new();
+        let mut handles = Vec::new();
+        for i in 0..8 {
+            let _ = alloc.alloc(i as u8).unwrap();
+            handles.push(alloc.alloc(i as f64 * 0.5).unwrap());
+        }
+        for (i, handle) in handles.iter_mut().enumerate() {
+            assert_aligned(handle);
+            handle
+                .get_access_mut(0)
+                .unwrap()
+                .as_mut()
+                .clone_from(&(i as f64));
+            assert_eq!(i as f64, *handle.get_access(0).unwrap().as_ref());
+        }
+    }
+
+    #[test]
+    fn alloc_over_aligned() {
+        let mut alloc = Allocator::new();
+        let mut handles = Vec::new();
+        for i in 0..8 {
+            let _ = alloc.alloc(i as u16).unwrap();
+            handles.push(
+                alloc
+                    .alloc(OverAligned {
+                        lanes: [i as f32; 4],
+                    })
+                    .unwrap(),
+            );
+        }
+        for (i, handle) in handles.iter_mut().enumerate() {
+            assert_aligned(handle);
+            assert_eq!([i as f32; 4], handle.get_access(0).unwrap().as_ref().lanes);
+        }
+    }
+
+    #[test]
+    fn alloc_raw_over_aligned() {
+        let mut alloc = Allocator::new();
+        let _ = alloc.alloc(1u8).unwrap();
+        let data = OverAligned { lanes: [3.0; 4] };
+        let index = alloc.alloc_raw(&data as *const OverAligned).unwrap();
+        let mut handle = alloc.get_mut::<OverAligned>(index).unwrap();
+        assert_aligned(&mut handle);
+        assert_eq!(data, *handle.get_access(0).unwrap().as_ref());
+    }
+
+    #[test]
+    fn alloc_beyond_page_alignment() {
+        let mut alloc = Allocator::new();
+        let _ = alloc.alloc(1u8).unwrap();
+        let mut handle = alloc.alloc(PageAligned { data: 9 }).unwrap();
+        assert_aligned(&mut handle);
+        assert_eq!(9, handle.get_access(0).unwrap().as_ref().data);
+    }
+
+    #[test]
+    fn alignment_padding_is_reused() {
+        let mut alloc = Allocator::new();
+        let mut small = alloc.alloc(1u8).unwrap();
+        let mut wide = alloc.alloc(2u128).unwrap();
+        let mut filler = alloc.alloc(3u8).unwrap();
+        assert_aligned(&mut wide);
+
+        let addr = |h: &mut ObjectHandleMut<u8>| h.get_access(0).unwrap().as_ref() as *const u8;
+        let (small_addr, filler_addr) = (addr(&mut small), addr(&mut filler));
+        // FrostyBox<u8> fits in the gap left before the aligned u128
+        assert_eq!(
+            std::mem::size_of::<FrostyBox<u8>>(),
+            filler_addr as usize - small_addr as usize
+        );
+    }
+
+    // counts how many times it has been dropped
+    struct DropCounter {
+        drops: Arc<AtomicUsize>,
+        _text: String,
+    }
+
+    impl DropCounter {
+        fn new(drops: &Arc<AtomicUsize>) -> Self {
+            Self {
+                drops: drops.clone(),
+                _text: "Hello World!".into(),
+            }
+        }
+    }
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    unsafe impl FrostyAllocatable for DropCounter {}
+
+    #[test]
+    fn free_runs_drop() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(DropCounter::new(&drops)).unwrap();
+        assert_eq!(0, drops.load(Ordering::SeqCst));
+        alloc.free(&mut handle).unwrap();
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+        // freeing twice must not drop twice
+        assert_eq!(Err(AllocError::HandleFreed), alloc.free(&mut handle));
+        drop(alloc);
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn dropping_allocator_runs_drop() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut alloc = Allocator::new();
+        let mut freed = alloc.alloc(DropCounter::new(&drops)).unwrap();
+        for _ in 0..9 {
+            alloc.alloc(DropCounter::new(&drops)).unwrap();
+        }
+        alloc.free(&mut freed).unwrap();
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+        drop(alloc);
+        assert_eq!(10, drops.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn free_while_accessed_waits_to_drop() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(DropCounter::new(&drops)).unwrap();
+        let mut other = handle.clone();
+        let access = other.get_access(0).unwrap();
+        alloc.free(&mut handle).unwrap();
+        // the access is still reading the object
+        assert!(!handle.is_live());
+        assert_eq!(0, drops.load(Ordering::SeqCst));
+        assert_eq!(0, alloc.reclaim());
+        assert_eq!(0, drops.load(Ordering::SeqCst));
+        // nothing may be placed over it either
+        alloc.compact();
+        let _new = alloc.alloc(DropCounter::new(&Arc::new(AtomicUsize::new(0))));
+        assert!(Arc::ptr_eq(&drops, &access.as_ref().drops));
+
+        drop(access);
+        assert_eq!(1, alloc.reclaim());
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+        drop(alloc);
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+    }
+
+    // Small enough to run under Miri, which checks the accesses
+    // for data races: cargo +nightly miri test -p frosty_alloc
+    #[test]
+    fn free_during_threaded_access() {
+        const READERS: usize = 3;
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut alloc = Allocator::new();
+        let mut handles: Vec<_> = (0..READERS)
+            .map(|_| alloc.alloc(DropCounter::new(&drops)).unwrap())
+            .collect();
+        let barrier = Arc::new(Barrier::new(READERS + 1));
+        let readers: Vec<_> = handles
+            .iter()
+            .map(|handle| {
+                let mut handle = handle.clone();
+                let barrier = barrier.clone();
+                let drops = drops.clone();
+                thread::spawn(move || {
+                    let access = handle.get_access(1).unwrap();
+                    barrier.wait();
+                    // the master thread frees the object here
+                    barrier.wait();
+                    assert!(Arc::ptr_eq(&drops, &access.as_ref().drops));
+                })
+            })
+            .collect();
+
+        barrier.wait();
+        for handle in handles.iter_mut() {
+            alloc.free(handle).unwrap();
+        }
+        // the region keeps changing while the objects are read
+        let mut churn = alloc.alloc(0u64).unwrap();
+        alloc.free(&mut churn).unwrap();
+        assert_eq!(0, drops.load(Ordering::SeqCst));
+        barrier.wait();
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(READERS, alloc.reclaim());
+        assert_eq!(READERS, drops.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn free_raw_runs_drop() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut alloc = Allocator::new();
+        let counter = ManuallyDrop::new(DropCounter::new(&drops));
+        let index = alloc.alloc_raw(&*counter as *const DropCounter).unwrap();
+        // free through a handle which no longer knows its type
+        let mut handle = unsafe { alloc.get_mut::<DropCounter>(index).unwrap().dissolve_data() };
+        alloc.free(&mut handle).unwrap();
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+        drop(alloc);
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn free_reuses_interim_slot() {
+        let mut alloc = Allocator::new();
+        let mut first = alloc.alloc(1u32).unwrap();
+        let first_ptr = first.ptr;
+        alloc.free(&mut first).unwrap();
+        // the slot can't be reused while a handle points at it
+        drop(first);
+        let mut second = alloc.alloc(2u32).unwrap();
+        assert_eq!(first_ptr, second.ptr);
+        assert_eq!(1, alloc.interim.len());
+        assert_eq!(
+            2,
+            *second
+                .get_access(0)
+                .expect("Failed to access reused slot")
+                .as_ref()
+        );
+    }
+
+    #[test]
+    fn stale_handle_fails_after_reuse() {
+        let mut alloc = Allocator::new();
+        let mut stale = alloc.alloc(1u64).unwrap();
+        let mut stale_copy = stale.cast_clone::<u64>();
+        alloc.free(&mut stale).unwrap();
+        assert!(!stale_copy.is_live());
+        assert!(matches!(
+            stale_copy.get_access(0),
+            Err(AllocError::HandleFreed)
+        ));
+
+        let mut fresh = alloc.alloc(7u64).unwrap();
+        assert!(fresh.is_live());
+        assert!(matches!(
+            stale_copy.get_access(0),
+            Err(AllocError::HandleFreed)
+        ));
+        assert!(matches!(
+            stale_copy.get_access_mut(0),
+            Err(AllocError::HandleFreed)
+        ));
+
+        // freeing through a stale handle must not touch the new object
+        assert_eq!(Err(AllocError::HandleFreed), alloc.free(&mut stale_copy));
+        assert_eq!(7, *fresh.get_access(0).unwrap().as_ref());
+    }
+
+    #[test]
+    fn stale_index_is_not_returned() {
+        let mut alloc = Allocator::new();
+        let index = alloc.alloc_raw(&5u32 as *const u32).unwrap();
+        let mut handle = alloc.get_mut::<u32>(index).unwrap();
+        alloc.free(&mut handle).unwrap();
+        assert!(matches!(
+            alloc.get_mut::<u32>(index),
+            Err(AllocError::HandleFreed)
+        ));
+        assert!(matches!(
+            unsafe { alloc.get::<u32>(index) },
+            Err(AllocError::HandleFreed)
+        ));
+    }
+
+    #[test]
+    fn get_wrong_type_fails() {
+        let mut alloc = Allocator::new();
+        let index = alloc.alloc_raw(&5u32 as *const u32).unwrap();
+        assert_eq!(
+            Some(AllocError::TypeMismatch {
+                expected: TypeId::of::<f32>(),
+                found: TypeId::of::<u32>(),
+            }),
+            alloc.get_mut::<f32>(index).err()
+        );
+        // dissolved handles match anything, but u8 is just a u8
+        assert!(alloc.get_mut::<Erased>(index).is_ok());
+        assert!(alloc.get_mut::<u8>(index).is_err());
+        assert_eq!(
+            5,
+            *alloc
+                .get_mut::<u32>(index)
+                .unwrap()
+                .get_access(0)
+                .unwrap()
+                .as_ref()
+        );
+    }
+
+    #[test]
+    fn alloc_zero_sized_fails() {
+        struct Marker;
+        unsafe impl FrostyAllocatable for Marker {}
+
+        let mut alloc = Allocator::new();
+        assert!(matches!(alloc.alloc(Marker), Err(AllocError::ZeroSized)));
+        assert_eq!(
+            Err(AllocError::ZeroSized),
+            alloc.alloc_raw(&Marker as *const Marker)
+        );
+        assert_eq!(0, alloc.stats().live_slots);
+    }
+
+    #[test]
+    fn panicking_writer_poisons_data() {
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(1u32).unwrap();
+        let mut writer = handle.cast_clone::<u32>();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut access = writer.get_access_mut(0).unwrap();
+            *access.as_mut() = 2;
+            panic!("writer panicked");
+        }));
+        assert!(result.is_err());
+        assert!(matches!(
+            handle.get_access(0),
+            Err(AllocError::LockPoisoned)
+        ));
+        assert!(matches!(
+            handle.get_access_mut(0),
+            Err(AllocError::LockPoisoned)
+        ));
+
+        handle.clear_poison().unwrap();
+        assert_eq!(2, *handle.get_access(0).unwrap().as_ref());
+    }
+
+    #[test]
+    fn budget_limits_growth() {
+        let mut alloc = Allocator::with_budget(2 * PAGE_SIZE);
+        assert_eq!(0, alloc.capacity());
+        let mut handles = Vec::new();
+        let err = loop {
+            match alloc.alloc(Small([0; 8])) {
+                Ok(handle) => handles.push(handle),
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(err, AllocError::OutOfBudget { .. }));
+        assert_eq!(2 * PAGE_SIZE, alloc.capacity());
+
+        // free'd memory can still be used
+        alloc.free(&mut handles[0]).unwrap();
+        alloc.alloc(Small([1; 8])).unwrap();
+    }
+
+    #[test]
+    fn budget_smaller_than_page() {
+        let mut alloc = Allocator::with_budget(256);
+        while alloc.alloc(1u64).is_ok() {}
+        assert_eq!(256, alloc.capacity());
+        assert!(matches!(
+            alloc.alloc(Large([0; 64])),
+            Err(AllocError::OutOfBudget { .. })
+        ));
+    }
+
+    // Hand a buffer to (f) as if it were 'static, then free it
+    fn with_buffer(len: usize, f: impl FnOnce(&'static mut [u8])) {
+        let buffer = Box::into_raw(vec![0u8; len].into_boxed_slice());
+        // SAFETY: every allocator using the buffer is dropped inside (f)
+        f(unsafe { &mut *buffer });
+        drop(unsafe { Box::from_raw(buffer) });
+    }
+
+    #[test]
+    fn from_buffer_stays_in_buffer() {
+        with_buffer(4096, |buffer| {
+            let range = buffer.as_ptr_range();
+            let (start, end) = (range.start as usize, range.end as usize);
+            let mut alloc = Allocator::from_buffer(buffer).unwrap();
+            let mut handles = Vec::new();
+            let err = loop {
+                match alloc.alloc(handles.len() as u64) {
+                    Ok(handle) => handles.push(handle),
+                    Err(e) => break e,
+                }
+            };
+            assert!(matches!(err, AllocError::OutOfBudget { .. }));
+            assert_eq!(1, alloc.pages.len());
+            for (i, handle) in handles.iter_mut().enumerate() {
+                let access = handle.get_access(0).unwrap();
+                let addr = access.as_ref() as *const u64 as usize;
+                assert!(start <= addr && addr < end);
+                assert_eq!(i as u64, *access.as_ref());
+            }
+            // a dedicated page would need to grow the region
+            assert!(matches!(
+                alloc.alloc(PageAligned { data: 0 }),
+                Err(AllocError::OutOfBudget { .. })
+            ));
+        });
+    }
+
+    #[test]
+    fn from_buffer_aligns_start() {
+        with_buffer(1024, |buffer| {
+            let mut alloc = Allocator::from_buffer(&mut buffer[1..]).unwrap();
+            assert!(alloc.capacity() <= 1023);
+            let mut handle = alloc.alloc(OverAligned { lanes: [1.0; 4] }).unwrap();
+            assert_aligned(&mut handle);
+        });
+    }
+
+    #[test]
+    fn from_empty_buffer_fails() {
+        with_buffer(1, |buffer| {
+            assert!(matches!(
+                Allocator::from_buffer(&mut buffer[..0]),
+                Err(AllocError::ZeroSized)
+            ));
+        });
+    }
+
+    #[test]
+    fn from_small_unaligned_buffer_fails() {
+        with_buffer(2 * PAGE_ALIGN, |buffer| {
+            // PAGE_ALIGN - 1 bytes starting just past an aligned address
+            let start = buffer.as_ptr().align_offset(PAGE_ALIGN) + 1;
+            let len = PAGE_ALIGN - 1;
+            assert_eq!(
+                Some(AllocError::BufferTooSmall {
+                    len,
+                    align: PAGE_ALIGN
+                }),
+                Allocator::from_buffer(&mut buffer[start..start + len]).err()
+            );
+        });
+    }
+
+    #[test]
+    fn impossible_pages_are_out_of_memory() {
+        assert!(matches!(
+            Page::new(usize::MAX, PAGE_ALIGN).err(),
+            Some(AllocError::OutOfMemory {
+                requested: usize::MAX
+            })
+        ));
+    }
+
+    #[test]
+    fn alloc_many_is_contiguous() {
+        let mut alloc = Allocator::new();
+        // leave a hole at the start which the batch is too big for
+        let mut hole = alloc.alloc(0u64).unwrap();
+        let _ = alloc.alloc(0u64).unwrap();
+        alloc.free(&mut hole).unwrap();
+
+        let mut handles = alloc.alloc_many((0..100).map(|i| i as u64)).unwrap();
+        assert_eq!(100, handles.len());
+        let stride = std::mem::size_of::<FrostyBox<u64>>();
+        let addrs: Vec<usize> = handles
+            .iter_mut()
+            .map(|h| h.get_access(0).unwrap().as_ref() as *const u64 as usize)
+            .collect();
+        for pair in addrs.windows(2) {
+            assert_eq!(stride, pair[1] - pair[0]);
+        }
+        for (i, handle) in handles.iter_mut().enumerate() {
+            assert_eq!(i as u64, *handle.get_access(0).unwrap().as_ref());
+        }
+    }
+
+    #[test]
+    fn alloc_many_larger_than_page() {
+        let mut alloc = Allocator::new();
+        let pages = alloc.pages.len();
+        let count = 2 * PAGE_SIZE / std::mem::size_of::<FrostyBox<Small>>();
+        let mut handles = alloc
+            .alloc_many((0..count).map(|i| Small([i as u64; 8])))
+            .unwrap();
+        assert_eq!(pages + 1, alloc.pages.len());
+        for (i, handle) in handles.iter_mut().enumerate() {
+            assert_eq!([i as u64; 8], handle.get_access(0).unwrap().as_ref().0);
+        }
+    }
+
+    #[test]
+    fn alloc_many_frees_individually() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut alloc = Allocator::new();
+        let mut handles = alloc
+            .alloc_many((0..10).map(|_| DropCounter::new(&drops)))
+            .unwrap();
+        alloc.free(&mut handles[3]).unwrap();
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+        assert_eq!(9, alloc.stats().live_slots);
+        let stats = alloc.stats();
+        assert_eq!(stats.capacity, stats.live_bytes + stats.free_bytes);
+        drop(alloc);
+        assert_eq!(10, drops.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn alloc_many_edge_cases() {
+        struct Marker;
+        unsafe impl FrostyAllocatable for Marker {}
+
+        let mut alloc = Allocator::new();
+        assert!(alloc.alloc_many(Vec::<u32>::new()).unwrap().is_empty());
+        assert!(matches!(
+            alloc.alloc_many([Marker, Marker]),
+            Err(AllocError::ZeroSized)
+        ));
+        let mut budgeted = Allocator::with_budget(1024);
+        assert!(matches!(
+            budgeted.alloc_many(0..1024u64),
+            Err(AllocError::OutOfBudget { .. })
+        ));
+        assert_eq!(0, budgeted.stats().live_slots);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn churn_keeps_interim_bounded() {
+        let mut alloc = Allocator::new();
+        for i in 0..2_000_000u64 {
+            let mut handle = alloc.alloc(i).unwrap();
+            alloc.free(&mut handle).unwrap();
+        }
+        assert_eq!(1, alloc.interim.len());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn batched_churn_keeps_interim_bounded() {
+        const LIVE: usize = 64;
+        const ROUNDS: usize = 1_000_000 / LIVE;
+
+        let mut alloc = Allocator::new();
+        let mut live = Vec::with_capacity(LIVE);
+        for round in 0..ROUNDS {
+            for i in 0..LIVE {
+                live.push(alloc.alloc((round * LIVE + i) as u64).unwrap());
+            }
+            for mut handle in live.drain(..) {
+                alloc.free(&mut handle).unwrap();
+            }
+        }
+        assert_eq!(LIVE, alloc.interim.len());
+    }
+
+    // allocate (count) u64s and free every other one, leaving
+    // the region full of small holes
+    fn fragmented(alloc: &mut Allocator, count: u64) -> Vec<ObjectHandleMut<u64>> {
+        let mut handles: Vec<_> = (0..count).map(|i| alloc.alloc(i).unwrap()).collect();
+        let mut kept = Vec::new();
+        for (i, mut handle) in handles.drain(..).enumerate() {
+            match i % 2 {
+                0 => kept.push(handle),
+                _ => alloc.free(&mut handle).unwrap(),
+            }
+        }
+        kept
+    }
+
+    #[test]
+    fn compact_reclaims_holes() {
+        let mut alloc = Allocator::new();
+        let mut kept = fragmented(&mut alloc, 100);
+        let box_size = std::mem::size_of::<FrostyBox<u64>>();
+        // the last object is kept, so every hole sits behind it
+        assert_eq!(50 * box_size - box_size, alloc.compact());
+        for (i, handle) in kept.iter_mut().enumerate() {
+            assert_eq!((i * 2) as u64, *handle.get_access(0).unwrap().as_ref());
+        }
+        // nothing left to move
+        assert_eq!(0, alloc.compact());
+    }
+
+    struct Small([u64; 8]);
+    unsafe impl FrostyAllocatable for Small {}
+
+    struct Large([u64; 64]);
+    unsafe impl FrostyAllocatable for Large {}
+
+    #[test]
+    fn compact_removes_need_to_resize() {
+        const PER_PAGE: usize = PAGE_SIZE / std::mem::size_of::<FrostyBox<Small>>();
+        let mut alloc = Allocator::with_capacity(PAGE_SIZE);
+        let mut handles: Vec<_> = (0..PER_PAGE)
+            .map(|i| alloc.alloc(Small([i as u64; 8])).unwrap())
+            .collect();
+        for handle in handles.iter_mut().step_by(2) {
+            alloc.free(handle).unwrap();
+        }
+        alloc.compact();
+        // the holes are the size of a Small, so a Large
+        // only fits after compaction
+        let mut large = alloc.alloc(Large([3; 64])).unwrap();
+        assert_eq!(PAGE_SIZE, alloc.capacity());
+        assert_eq!([3; 64], large.get_access(0).unwrap().as_ref().0);
+        for (i, handle) in handles.iter_mut().enumerate().skip(1).step_by(2) {
+            let access = handle.get_access(0).unwrap();
+            assert_eq!([i as u64; 8], access.as_ref().0);
+        }
+    }
+
+    #[test]
+    fn compact_keeps_alignment() {
+        let mut alloc = Allocator::new();
+        let mut small = alloc.alloc(1u8).unwrap();
+        let mut big = alloc.alloc(OverAligned { lanes: [7.0; 4] }).unwrap();
+        alloc.free(&mut small).unwrap();
+        alloc.compact();
+        assert_aligned(&mut big);
+        assert_eq!([7.0; 4], big.get_access(0).unwrap().as_ref().lanes);
+    }
+
+    #[test]
+    fn compact_skips_locked_objects() {
+        let mut alloc = Allocator::new();
+        let mut first = alloc.alloc(1u64).unwrap();
+        let mut locked = alloc.alloc(2u64).unwrap();
+        alloc.free(&mut first).unwrap();
+        let access = locked.get_access(0).unwrap();
+        let before = access.as_ref() as *const u64;
+        alloc.compact();
+        assert_eq!(before, access.as_ref() as *const u64);
+        drop(access);
+        // once unlocked it can move into the hole
+        alloc.compact();
+        assert_ne!(before, locked.get_access(0).unwrap().as_ref() as *const u64);
+        assert_eq!(2, *locked.get_access(0).unwrap().as_ref());
+    }
+
+    #[test]
+    fn compact_unlocks_moved_objects() {
+        let mut alloc = Allocator::new();
+        let mut kept = fragmented(&mut alloc, 10);
+        alloc.compact();
+        // each object was locked for its move, and the lock
+        // has to be released where it ended up
+        for handle in kept.iter_mut() {
+            drop(handle.try_get_access_mut(0).unwrap());
+            drop(handle.try_get_access(0).unwrap());
+        }
+    }
+
+    trait Named {
+        fn name(&self) -> u64;
+    }
+
+    impl Named for u64 {
+        fn name(&self) -> u64 {
+            *self
+        }
+    }
+
+    unsafe impl FrostyAllocatable for dyn Named {}
+
+    #[test]
+    fn compact_keeps_dyn_handles() {
+        let mut alloc = Allocator::new();
+        let mut kept = fragmented(&mut alloc, 10);
+        let mut dyns: Vec<DynObjectHandle<dyn Named>> =
+            kept.iter().map(|h| DynObjectHandle::new(h)).collect();
+        alloc.compact();
+        for (i, handle) in dyns.iter_mut().enumerate() {
+            assert_eq!(
+                (i * 2) as u64,
+                handle.get_access(0).unwrap().as_ref().name()
+            );
+        }
+        alloc.free(&mut kept[0]).unwrap();
+        assert!(matches!(
+            dyns[0].get_access(0),
+            Err(AllocError::HandleFreed)
+        ));
+    }
+
+    #[test]
+    fn stats_account_for_every_byte() {
+        let mut alloc = Allocator::new();
+        let mut ints: Vec<_> = (0..10u32).map(|i| alloc.alloc(i).unwrap()).collect();
+        let _floats: Vec<_> = (0..5).map(|i| alloc.alloc(i as f64).unwrap()).collect();
+        for handle in ints.iter_mut().take(4) {
+            alloc.free(handle).unwrap();
+        }
+
+        let stats = alloc.stats();
+        assert_eq!(alloc.capacity(), stats.capacity);
+        assert_eq!(stats.capacity, stats.live_bytes + stats.free_bytes);
+        assert_eq!(11, stats.live_slots);
+        assert_eq!(4, stats.freed_slots);
+        assert_eq!(0, stats.resizes);
+
+        let u32_size = std::mem::size_of::<FrostyBox<u32>>();
+        let f64_size = std::mem::size_of::<FrostyBox<f64>>();
+        assert_eq!(6, stats.of::<u32>().count);
+        assert_eq!(6 * u32_size, stats.of::<u32>().bytes);
+        assert_eq!(5 * f64_size, stats.types[&TypeId::of::<f64>()].bytes);
+        assert_eq!(0, stats.of::<u8>().count);
+        assert_eq!(6 * u32_size + 5 * f64_size, stats.live_bytes);
+    }
+
+    #[test]
+    fn stats_count_resizes() {
+        let mut alloc = Allocator::with_capacity(PAGE_SIZE);
+        assert_eq!(0, alloc.stats().resizes);
+        assert_eq!(1, alloc.stats().free_chunks);
+        assert_eq!(PAGE_SIZE, alloc.stats().largest_free_chunk);
+
+        // one more than fits in the first page
+        let per_page = PAGE_SIZE / std::mem::size_of::<FrostyBox<Small>>();
+        let _handles: Vec<_> = (0..=per_page)
+            .map(|i| alloc.alloc(Small([i as u64; 8])).unwrap())
+            .collect();
+        let stats = alloc.stats();
+        assert_eq!(1, stats.resizes);
+        assert_eq!(stats.capacity, stats.live_bytes + stats.free_bytes);
+    }
+
+    #[test]
+    fn stats_show_compaction() {
+        let mut alloc = Allocator::with_capacity(PAGE_SIZE);
+        let _kept = fragmented(&mut alloc, 100);
+        let before = alloc.stats();
+        assert!(before.free_chunks > 1);
+        alloc.compact();
+        let after = alloc.stats();
+        assert_eq!(1, after.free_chunks);
+        assert_eq!(before.free_bytes, after.free_bytes);
+        assert_eq!(after.free_bytes, after.largest_free_chunk);
+        assert!(after.fragmentation() < before.fragmentation());
+    }
+
+    #[test]
+    fn shared_objects_free_on_last_drop() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut alloc = Allocator::new();
+        let material = alloc.alloc_shared(DropCounter::new(&drops)).unwrap();
+        let user = material.clone();
+        let weak = material.downgrade();
+
+        drop(material);
+        assert_eq!(0, alloc.reclaim());
+        assert!(weak.upgrade().is_some());
+
+        drop(user);
+        // nothing is free'd until the allocator is used again
+        assert_eq!(0, drops.load(Ordering::SeqCst));
+        assert!(weak.upgrade().is_none());
+        assert_eq!(1, alloc.reclaim());
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+        assert_eq!(0, alloc.stats().live_slots);
+
+        // the memory and slot are reused
+        let next = alloc.alloc_shared(DropCounter::new(&drops)).unwrap();
+        assert_eq!(1, alloc.interim.len());
+        assert_eq!(1, alloc.stats().free_chunks);
+        drop(next);
+        alloc.alloc(0u8).unwrap();
+        assert_eq!(2, drops.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn shared_objects_wait_for_access() {
+        let mut alloc = Allocator::new();
+        let mut shared = alloc.alloc_shared(5u32).unwrap();
+        let access = shared.get_access(0).unwrap();
+        drop(shared);
+        assert_eq!(0, alloc.reclaim());
+        assert_eq!(5, *access.as_ref());
+        drop(access);
+        assert_eq!(1, alloc.reclaim());
+    }
+
+    #[test]
+    fn unshared_objects_outlive_handles() {
+        let mut alloc = Allocator::new();
+        let index = {
+            let mut handle = alloc.alloc(9u32).unwrap();
+            handle.interim().slot
+        };
+        assert_eq!(0, alloc.reclaim());
+        let mut handle = alloc.get_mut::<u32>(index).unwrap();
+        assert_eq!(9, *handle.get_access(0).unwrap().as_ref());
+    }
+
+    #[test]
+    fn handles_can_outlive_allocator() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(DropCounter::new(&drops)).unwrap();
+        let weak = handle.downgrade();
+        drop(alloc);
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+        assert!(matches!(handle.get_access(0), Err(AllocError::HandleFreed)));
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn weak_handles_can_outlive_allocator() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut alloc = Allocator::new();
+        let weak = alloc.alloc(DropCounter::new(&drops)).unwrap().downgrade();
+        let shared = alloc.alloc_shared(5u32).unwrap().downgrade();
+        let copy = weak.clone();
+        drop(alloc);
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+        // only weak handles are left holding the table
+        assert!(weak.upgrade().is_none());
+        assert!(shared.upgrade().is_none());
+        drop(weak);
+        assert!(copy.upgrade().is_none());
+    }
 }
@@ -0,0 +1,62 @@
+use std::{hint, thread, time::Duration};
+
+// spins double each step until this many steps have passed
+const SPIN_STEPS: u32 = 6;
+// after spinning, the thread yields until this many steps have passed
+const YIELD_STEPS: u32 = 10;
+// longest a thread will park for between attempts
+const MAX_PARK: Duration = Duration::from_millis(1);
+
+// Waits between attempts to take a lock, getting slower the
+// longer the lock stays taken.
+//
+// Short waits are spent spinning, since most locks on a
+// [FrostyBox] are only held for a moment. If that isn't enough
+// the thread yields to the scheduler, and past that it parks
+// for exponentially longer periods so a contended lock doesn't
+// pin a core at 100%.
+pub(crate) struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self { step: 0 }
+    }
+
+    // Wait before the next attempt. Parking is capped at
+    // (max_park) so a deadline isn't overshot by much
+    pub fn snooze(&mut self, max_park: Duration) {
+        if self.step < SPIN_STEPS {
+            for _ in 0..1 << self.step {
+                hint::spin_loop();
+            }
+        } else if self.step < YIELD_STEPS {
+            thread::yield_now();
+        } else {
+            let exp = (self.step - YIELD_STEPS).min(10);
+            let park = Duration::from_micros(1 << exp).min(MAX_PARK).min(max_park);
+            thread::park_timeout(park);
+        }
+        self.step = self.step.saturating_add(1);
+    }
+}
+
+#[cfg(test)]
+mod backoff_tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Backoff, MAX_PARK, YIELD_STEPS};
+
+    #[test]
+    fn parks_are_capped() {
+        let mut backoff = Backoff::new();
+        for _ in 0..YIELD_STEPS + 20 {
+            backoff.snooze(Duration::MAX);
+        }
+        let start = Instant::now();
+        backoff.snooze(Duration::MAX);
+        // parking can wake early but shouldn't sleep much past the cap
+        assert!(start.elapsed() < MAX_PARK * 50);
+    }
+}
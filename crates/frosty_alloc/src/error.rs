@@ -0,0 +1,63 @@
+use std::{any::TypeId, fmt};
+
+// Everything that can go wrong when storing or reaching
+// an object held by an [Allocator]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    // the region could not grow to fit (requested) bytes
+    // without going over its budget
+    OutOfBudget { requested: usize },
+    // the system could not give the region (requested) more bytes
+    OutOfMemory { requested: usize },
+    // a buffer of (len) bytes has no room for an object
+    // once it is aligned to (align)
+    BufferTooSmall { len: usize, align: usize },
+    // the object a handle or index points to has been free'd
+    HandleFreed,
+    // an object was requested as a different type than it was stored as
+    TypeMismatch { expected: TypeId, found: TypeId },
+    // a thread panicked while it had write access to the object,
+    // so it may have been left half updated
+    LockPoisoned,
+    // access was requested without waiting, but the object is locked
+    WouldBlock,
+    // the object stayed locked for longer than the timeout
+    TimedOut,
+    // a zero sized type or region was given, so there is nothing
+    // to store or nowhere to store it
+    ZeroSized,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfBudget { requested } => {
+                write!(f, "allocator could not reserve {requested} more bytes")
+            }
+            Self::OutOfMemory { requested } => {
+                write!(f, "system could not allocate {requested} more bytes")
+            }
+            Self::BufferTooSmall { len, align } => {
+                write!(
+                    f,
+                    "buffer of {len} bytes is too small once aligned to {align}"
+                )
+            }
+            Self::HandleFreed => write!(f, "object has already been free'd"),
+            Self::TypeMismatch { expected, found } => {
+                write!(
+                    f,
+                    "object is a {found:?}, but was accessed as a {expected:?}"
+                )
+            }
+            Self::LockPoisoned => {
+                write!(f, "a thread panicked while writing to the object")
+            }
+            Self::WouldBlock => write!(f, "object is locked by another access"),
+            Self::TimedOut => write!(f, "timed out waiting for access to object"),
+            Self::ZeroSized => write!(f, "zero sized types and regions cannot be used"),
+        }
+    }
+}
+
+impl std::error::Error for AllocError {}
@@ -0,0 +1,204 @@
+// Locking several objects together.
+//
+// A [Fetch] names the objects to lock at once, one per component
+// type. A single component is locked for writing, while a tuple
+// of &A and &mut B markers locks each of its parts for reading or
+// writing. Tuples lock their parts in order of where the objects
+// are kept rather than the order they are named in, so two threads
+// locking the same objects as differently ordered tuples take them
+// in the same order and can't deadlock.
+
+use std::any::TypeId;
+
+use crate::{AllocError, DataAccess, DataAccessMut, Erased, FrostyAllocatable, ObjectHandleMut};
+
+// One part of a tuple [Fetch]
+pub trait FetchParam {
+    type Item;
+    fn id() -> TypeId;
+    // Blocks until the lock is taken
+    fn fetch(handle: &ObjectHandleMut<Erased>, thread: u32) -> Result<Self::Item, AllocError>;
+}
+
+impl<T: FrostyAllocatable> FetchParam for &T {
+    type Item = DataAccess<T>;
+
+    fn id() -> TypeId {
+        T::id()
+    }
+
+    fn fetch(handle: &ObjectHandleMut<Erased>, thread: u32) -> Result<Self::Item, AllocError> {
+        handle.cast_clone::<T>().get_access(thread)
+    }
+}
+
+impl<T: FrostyAllocatable> FetchParam for &mut T {
+    type Item = DataAccessMut<T>;
+
+    fn id() -> TypeId {
+        T::id()
+    }
+
+    fn fetch(handle: &ObjectHandleMut<Erased>, thread: u32) -> Result<Self::Item, AllocError> {
+        handle.cast_clone::<T>().get_access_mut(thread)
+    }
+}
+
+pub trait Fetch {
+    type Item;
+    // number of objects locked
+    const COUNT: usize;
+    // component of each object, in order. None are repeated
+    fn ids() -> Vec<TypeId>;
+    // (handles) has one handle for each of ids(), in the same
+    // order. Blocks until every lock is taken
+    fn fetch(handles: &[ObjectHandleMut<Erased>], thread: u32) -> Result<Self::Item, AllocError>;
+}
+
+impl<T: FrostyAllocatable> Fetch for T {
+    type Item = DataAccessMut<T>;
+    const COUNT: usize = 1;
+
+    fn ids() -> Vec<TypeId> {
+        vec![T::id()]
+    }
+
+    fn fetch(handles: &[ObjectHandleMut<Erased>], thread: u32) -> Result<Self::Item, AllocError> {
+        handles[0].cast_clone::<T>().get_access_mut(thread)
+    }
+}
+
+macro_rules! impl_fetch {
+    ($count:expr; $($param:ident $i:tt),+) => {
+        impl<$($param: FetchParam),+> Fetch for ($($param,)+) {
+            type Item = ($($param::Item,)+);
+            const COUNT: usize = $count;
+
+            fn ids() -> Vec<TypeId> {
+                vec![$($param::id()),+]
+            }
+
+            fn fetch(handles: &[ObjectHandleMut<Erased>], thread: u32) -> Result<Self::Item, AllocError> {
+                // every thread locks objects from the lowest slot
+                // address up, so none can wait on one holding a
+                // later lock
+                let mut order = [$($i),+];
+                order.sort_unstable_by_key(|i: &usize| handles[*i].ptr.as_ptr() as usize);
+                // locks taken before one fails are dropped by the ?
+                let mut locked = ($(None::<$param::Item>,)+);
+                for i in order {
+                    match i {
+                        $($i => locked.$i = Some($param::fetch(&handles[$i], thread)?),)+
+                        _ => unreachable!(),
+                    }
+                }
+                Ok(($(locked.$i.unwrap(),)+))
+            }
+        }
+    };
+}
+
+impl_fetch!(1; A 0);
+impl_fetch!(2; A 0, B 1);
+impl_fetch!(3; A 0, B 1, C 2);
+impl_fetch!(4; A 0, B 1, C 2, D 3);
+
+#[cfg(test)]
+mod fetch_tests {
+    use std::{
+        sync::{Arc, Barrier},
+        thread,
+    };
+
+    use super::Fetch;
+    use crate::{Allocator, Erased, FrostyAllocatable, ObjectHandleMut};
+
+    fn erase<T: FrostyAllocatable>(mut handle: ObjectHandleMut<T>) -> ObjectHandleMut<Erased> {
+        unsafe { handle.dissolve_data() }
+    }
+
+    #[test]
+    fn tuples_lock_each_part() {
+        let mut alloc = Allocator::new();
+        let handles = [
+            erase(alloc.alloc(2.0f32).unwrap()),
+            erase(alloc.alloc(5u32).unwrap()),
+        ];
+        assert_eq!(vec![f32::id(), u32::id()], <(&f32, &mut u32)>::ids());
+        assert_eq!(2, <(&f32, &mut u32)>::COUNT);
+        assert_eq!(1, <u32 as Fetch>::COUNT);
+
+        let (speed, mut count) = <(&f32, &mut u32)>::fetch(&handles, 0).unwrap();
+        *count.as_mut() += *speed.as_ref() as u32;
+        assert_eq!(7, *count.as_ref());
+        // reads can be shared, but the write blocks everything
+        let mut speed_handle = handles[0].cast_clone::<f32>();
+        let mut count_handle = handles[1].cast_clone::<u32>();
+        assert!(speed_handle.try_get_access(1).is_ok());
+        assert!(count_handle.try_get_access(1).is_err());
+        drop((speed, count));
+
+        let single = u32::fetch(&handles[1..], 0).unwrap();
+        assert_eq!(7, *single.as_ref());
+    }
+
+    #[test]
+    fn opposite_orders_dont_deadlock() {
+        let mut alloc = Allocator::new();
+        let a = erase(alloc.alloc(0u32).unwrap());
+        let b = erase(alloc.alloc(0u64).unwrap());
+        let forward = [a.clone(), b.clone()];
+        let backward = [b, a];
+        let start = Arc::new(Barrier::new(2));
+
+        let other = {
+            let start = start.clone();
+            thread::spawn(move || {
+                start.wait();
+                for _ in 0..1000 {
+                    let (mut b, mut a) = <(&mut u64, &mut u32)>::fetch(&backward, 1).unwrap();
+                    *a.as_mut() += 1;
+                    *b.as_mut() += 1;
+                }
+            })
+        };
+        start.wait();
+        for _ in 0..1000 {
+            let (mut a, mut b) = <(&mut u32, &mut u64)>::fetch(&forward, 2).unwrap();
+            *a.as_mut() += 1;
+            *b.as_mut() += 1;
+        }
+        other.join().unwrap();
+
+        let (a, b) = <(&u32, &u64)>::fetch(&forward, 0).unwrap();
+        assert_eq!((2000, 2000), (*a.as_ref(), *b.as_ref()));
+    }
+
+    #[test]
+    fn tuples_wait_behind_writers() {
+        let mut alloc = Allocator::new();
+        let a = erase(alloc.alloc(1u32).unwrap());
+        let b = erase(alloc.alloc(0u64).unwrap());
+        let handles = [a.clone(), b.clone()];
+        let reading = a.cast_clone::<u32>().get_access(0).unwrap();
+
+        // waits for (reading), holding off new readers of (a)
+        let writer = {
+            let a = a.clone();
+            thread::spawn(move || *a.cast_clone::<u32>().get_access_mut(1).unwrap().as_mut() = 2)
+        };
+        while a.cast_clone::<u32>().try_get_access(0).is_ok() {
+            thread::yield_now();
+        }
+        let fetcher = thread::spawn(move || {
+            let (a, mut b) = <(&u32, &mut u64)>::fetch(&handles, 2).unwrap();
+            *b.as_mut() = *a.as_ref() as u64;
+        });
+        drop(reading);
+        writer.join().unwrap();
+        fetcher.join().unwrap();
+
+        let (_, b) = <(&u32, &u64)>::fetch(&[a, b], 0).unwrap();
+        assert_eq!(2, *b.as_ref());
+    }
+}
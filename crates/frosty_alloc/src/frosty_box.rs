@@ -1,81 +1,172 @@
-use crate::FrostyAllocatable;
+use crate::{backoff::Backoff, AllocError, FrostyAllocatable};
 use std::{
-    mem::MaybeUninit,
+    ptr::NonNull,
     sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
     u32,
 };
 
 // with 32 bits:
-//      2 are reserved for writing flags
-//      15 are
+//      1 is the write flag, set while a thread is writing
+//      1 is the poison flag
+//      10 count the writers waiting for their turn
+//      20 count the readers
+// Threads don't get bits of their own, so any number of
+// threads can share a semaphore
 type BitMaskType = u32;
 pub(crate) struct BitMask(pub AtomicU32);
 
+// How long to keep trying to take a lock
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Wait {
+    // until the lock is taken
+    Block,
+    // once
+    Try,
+    // until the lock is taken or the deadline has passed
+    Until(Instant),
+}
+
+impl Wait {
+    pub fn timeout(timeout: Duration) -> Self {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => Self::Until(deadline),
+            None => Self::Block,
+        }
+    }
+
+    // Keep calling (attempt) until it succeeds or the wait is over,
+    // backing off between attempts
+    fn retry<F: FnMut() -> bool>(self, mut attempt: F) -> Result<(), AllocError> {
+        let mut backoff = Backoff::new();
+        loop {
+            if attempt() {
+                return Ok(());
+            }
+            match self {
+                Self::Block => backoff.snooze(Duration::MAX),
+                Self::Try => return Err(AllocError::WouldBlock),
+                Self::Until(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(AllocError::TimedOut);
+                    }
+                    backoff.snooze(deadline - now);
+                }
+            }
+        }
+    }
+}
+
 impl BitMask {
-    pub const WRITE_FLAG: BitMaskType = 0b10_000000000000000_000000000000000;
-    // any value greater than or equal to this is locked to new reads
-    pub const LOCK_VALUE: BitMaskType = 0b00_000000000000001_000000000000000;
-    pub const NON_READ_FLAGS: BitMaskType = 0b11_111111111111111_000000000000000;
+    pub const WRITE_FLAG: BitMaskType = 1 << 31;
+    // set if a writer panicked before releasing its lock
+    pub const POISON_FLAG: BitMaskType = 1 << 30;
+    pub const PENDING_ONE: BitMaskType = 1 << 20;
+    pub const PENDING_FLAGS: BitMaskType = 0b1111111111 << 20;
+    pub const READ_FLAGS: BitMaskType = BitMask::PENDING_ONE - 1;
+
     pub fn new(v: u32) -> Self {
         Self(AtomicU32::new(v))
     }
 
-    pub fn generate_pending_flag(thread: BitMaskType) -> BitMaskType {
-        Self::LOCK_VALUE * 2u32.pow(thread)
+    // Try to add a reader. Fails if the data is being written
+    // to or a writer is waiting for its turn
+    fn try_read(&self) -> bool {
+        let blocked = BitMask::WRITE_FLAG | BitMask::PENDING_FLAGS;
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |state| {
+                let room = state & BitMask::READ_FLAGS != BitMask::READ_FLAGS;
+                (state & blocked == 0 && room).then_some(state + 1)
+            })
+            .is_ok()
+    }
+
+    // Try to take the write lock. Fails while anything is reading
+    // or writing. A writer which was (pending) stops being counted
+    // as waiting once it has the lock
+    fn try_write(&self, pending: bool) -> bool {
+        let blocked = BitMask::WRITE_FLAG | BitMask::READ_FLAGS;
+        let pending = if pending { BitMask::PENDING_ONE } else { 0 };
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |state| {
+                (state & blocked == 0).then_some((state - pending) | BitMask::WRITE_FLAG)
+            })
+            .is_ok()
+    }
+
+    // Count a writer as waiting. Returns false if the count is
+    // full, in which case the writer waits without holding off
+    // new readers
+    fn add_pending(&self) -> bool {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |state| {
+                let full = state & BitMask::PENDING_FLAGS == BitMask::PENDING_FLAGS;
+                (!full).then_some(state + BitMask::PENDING_ONE)
+            })
+            .is_ok()
+    }
+
+    pub fn lock_read(&self, wait: Wait) -> Result<(), AllocError> {
+        wait.retry(|| self.try_read())
+    }
+
+    // A writer which has to wait is counted as pending so that
+    // new readers hold off until it has had its turn
+    pub fn lock_write(&self, wait: Wait) -> Result<(), AllocError> {
+        if self.try_write(false) {
+            return Ok(());
+        }
+        if let Wait::Try = wait {
+            return Err(AllocError::WouldBlock);
+        }
+        let pending = self.add_pending();
+        let result = wait.retry(|| self.try_write(pending));
+        if result.is_err() && pending {
+            self.0.fetch_sub(BitMask::PENDING_ONE, Ordering::SeqCst);
+        }
+        result
+    }
+
+    // Take the write lock only if nothing is reading, writing or
+    // waiting to write, so no other thread can be looking at the data
+    pub fn try_lock_idle(&self) -> bool {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |state| {
+                (state & !BitMask::POISON_FLAG == 0).then_some(state | BitMask::WRITE_FLAG)
+            })
+            .is_ok()
     }
 
     // no return value. since this method is blocking,
     // code execution begins again once access is granted
-    pub fn get_access(&mut self, thread: BitMaskType) {
-        let thread_key = 2u32.pow(thread);
-        loop {
-            let join_attempt = self.0.fetch_or(thread_key, Ordering::SeqCst);
-            if join_attempt < BitMask::WRITE_FLAG {
-                return;
-            }
-            self.0.fetch_xor(thread_key, Ordering::SeqCst);
-            // this is just a slow operation to allow locks to go thru
-            // load values shouldn't be used to determine semaphore
-            // behaviour, except in slow checks
-            self.0.load(Ordering::SeqCst);
-        }
+    pub fn get_access(&self) {
+        let _ = self.lock_read(Wait::Block);
     }
 
-    // no return value due to blocking
-    // see Self.get_access()
-    pub fn get_access_mut(&mut self, thread: BitMaskType) {
-        let pend_key = BitMask::generate_pending_flag(thread);
-        let request_key = pend_key | BitMask::WRITE_FLAG;
-        loop {
-            // assume worst case scenario
-            // so state reads that there is no active reads, writes, or pendings
-            let state = self.0.fetch_or(request_key, Ordering::SeqCst);
-            // now a higher level thread comes and sees that the thread is being
-            // written to, so it cannot access it. This is the desired behaviour
-            // as level exists only to prevent deadlocks. Priority is handled by
-            // a scheduler. Two threads cannot concurrently set themselves as
-            // writing due to the atomicity of the underlying data
-            let wait_for_turn = state > pend_key;
-            let wait_for_read_end = (state | BitMask::NON_READ_FLAGS ^ BitMask::NON_READ_FLAGS) > 0;
-            if !(wait_for_turn || wait_for_read_end) {
-                self.0.fetch_xor(pend_key, Ordering::SeqCst);
-                return;
-            }
-            // need to update the fact that the thread isn't actually writing
-            self.0
-                .fetch_and(state ^ !BitMask::LOCK_VALUE, Ordering::SeqCst);
-            // slow operation to allow other threads time to do things
-            self.0.load(Ordering::SeqCst);
-        }
+    pub fn drop_read_access(&self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
 
-    pub fn drop_read_access(&mut self, thread: BitMaskType) {
-        let thread_key = 2u32.pow(thread);
-        self.0.fetch_xor(thread_key, Ordering::SeqCst);
+    pub fn drop_write_access(&self) {
+        self.0.fetch_and(!BitMask::WRITE_FLAG, Ordering::SeqCst);
     }
 
-    pub fn drop_write_access(&mut self) {
-        self.0.fetch_xor(BitMask::LOCK_VALUE, Ordering::SeqCst);
+    pub fn poison(&self) {
+        self.0.fetch_or(BitMask::POISON_FLAG, Ordering::SeqCst);
+    }
+
+    pub fn clear_poison(&self) {
+        self.0.fetch_and(!BitMask::POISON_FLAG, Ordering::SeqCst);
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.0.load(Ordering::SeqCst) & BitMask::POISON_FLAG > 0
+    }
+
+    // true if no thread is reading, writing or waiting to write
+    pub fn is_unlocked(&self) -> bool {
+        self.0.load(Ordering::SeqCst) & !BitMask::POISON_FLAG == 0
     }
 }
 
@@ -98,57 +189,23 @@ impl<T: FrostyAllocatable> FrostyBox<T> {
     }
 
     // SAFETY:
-    //  data is never read in while partially init, so
-    //  is there ever UB?
+    //  the value behind (data) is moved into the box, so the
+    //  caller must make sure it is never dropped again
     pub fn from_raw(data: *const T) -> Self {
-        let full_init = unsafe {
-            let mut partial_init: Self = MaybeUninit::zeroed().assume_init();
-            partial_init.semaphore = BitMask::new(0);
-            let data_ptr = &mut partial_init.data as *mut T;
-            std::ptr::copy(data, data_ptr, 1);
-            partial_init
-        };
-        full_init
+        Self::new(unsafe { std::ptr::read(data) })
     }
 }
 
 impl<T: FrostyAllocatable + ?Sized> FrostyBox<T> {
-    // no return value. since this method is blocking,
-    // code execution begins again once access is granted
-    pub fn get_access(&mut self, thread: BitMaskType) {
-        self.semaphore.get_access(thread);
-    }
-
-    // no return value due to blocking
-    // see Self.get_access()
-    pub fn get_access_mut(&mut self, thread: BitMaskType) {
-        self.semaphore.get_access_mut(thread);
-    }
-
-    pub fn drop_read_access(&mut self, thread: BitMaskType) {
-        self.semaphore.drop_read_access(thread);
-    }
-
-    pub fn drop_write_access(&mut self) {
-        self.semaphore.drop_write_access();
-    }
-
-    pub fn get_ref(&self) -> &T {
-        &self.data
-    }
-
-    pub fn get_mut(&mut self) -> &mut T {
-        &mut self.data
-    }
-
+    // Made without a reference to the box, since other threads
+    // may hold references into it
     // SAFETY:
-    //    The caller has to keep track of each pointer on their own
-    //    and ensure that they don't do anything bad
-    pub unsafe fn get_ptrs(&mut self) -> (*mut T, *mut BitMask) {
-        (
-            &mut self.data as *mut T,
-            &mut self.semaphore as *mut BitMask,
-        )
+    //    (this) must point to a living box. The caller has to keep
+    //    track of each pointer on their own and ensure that they
+    //    don't do anything bad
+    pub unsafe fn get_ptrs(this: NonNull<Self>) -> (*mut T, *mut BitMask) {
+        let this = this.as_ptr();
+        (&raw mut (*this).data, &raw mut (*this).semaphore)
     }
 }
 
@@ -163,18 +220,78 @@ mod box_tests {
     }
 
     #[test]
-    fn lock_value_valid() {
-        let proper_lock_value = 2u32.pow(15);
-        assert_eq!(BitMask::LOCK_VALUE, proper_lock_value);
+    fn flags_do_not_overlap() {
+        let flags = [
+            BitMask::WRITE_FLAG,
+            BitMask::POISON_FLAG,
+            BitMask::PENDING_FLAGS,
+            BitMask::READ_FLAGS,
+        ];
+        for (i, a) in flags.iter().enumerate() {
+            for b in flags.iter().skip(i + 1) {
+                assert_eq!(0, a & b);
+            }
+        }
+        assert_eq!(BitMaskType::MAX, flags.iter().fold(0, |all, f| all | f));
     }
 
     #[test]
-    fn generate_pend_flags() {
-        // with 32 bits, there are [32-2]/2 pend flags
-        // or 15 pend flags
-        for f in 0..15 {
-            let expected_key = 2u32.pow(f + 15);
-            assert_eq!(expected_key, BitMask::generate_pending_flag(f));
+    fn readers_are_counted() {
+        let mask = BitMask::new(0);
+        for _ in 0..100 {
+            mask.lock_read(Wait::Try).unwrap();
+        }
+        assert_eq!(100, mask.0.load(Ordering::SeqCst));
+        assert_eq!(Err(AllocError::WouldBlock), mask.lock_write(Wait::Try));
+        for _ in 0..100 {
+            mask.drop_read_access();
         }
+        assert!(mask.is_unlocked());
+    }
+
+    #[test]
+    fn pending_writer_holds_off_readers() {
+        let mask = BitMask::new(0);
+        mask.lock_read(Wait::Try).unwrap();
+        // a waiting writer holds off new readers
+        let deadline = Wait::timeout(std::time::Duration::from_millis(5));
+        assert!(mask.add_pending());
+        assert_eq!(Err(AllocError::WouldBlock), mask.lock_read(Wait::Try));
+        assert_eq!(Err(AllocError::TimedOut), mask.lock_read(deadline));
+        mask.drop_read_access();
+        assert!(mask.try_write(true));
+        assert_eq!(BitMask::WRITE_FLAG, mask.0.load(Ordering::SeqCst));
+        mask.drop_write_access();
+        assert!(mask.is_unlocked());
+        assert_eq!(Ok(()), mask.lock_read(Wait::Try));
+    }
+
+    #[test]
+    fn full_pending_count_still_writes() {
+        let mask = BitMask::new(BitMask::PENDING_FLAGS);
+        assert!(!mask.add_pending());
+        assert_eq!(Ok(()), mask.lock_write(Wait::Try));
+        mask.drop_write_access();
+        assert_eq!(BitMask::PENDING_FLAGS, mask.0.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn idle_lock_waits_for_everyone() {
+        let mask = BitMask::new(0);
+        mask.lock_read(Wait::Try).unwrap();
+        assert!(!mask.try_lock_idle());
+        mask.drop_read_access();
+        // a waiting writer can take the lock, but this can't
+        assert!(mask.add_pending());
+        assert!(!mask.try_lock_idle());
+        assert!(mask.try_write(true));
+        mask.drop_write_access();
+
+        mask.poison();
+        assert!(mask.try_lock_idle());
+        assert_eq!(Err(AllocError::WouldBlock), mask.lock_read(Wait::Try));
+        mask.drop_write_access();
+        assert!(mask.is_poisoned());
+        assert!(mask.is_unlocked());
     }
 }
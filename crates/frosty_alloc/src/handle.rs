@@ -1,9 +1,18 @@
 use std::{
+    any::TypeId,
     marker::{PhantomData, Unsize},
-    ptr::NonNull,
+    mem::ManuallyDrop,
+    ptr::{self, NonNull, Pointee},
+    time::Duration,
 };
 
-use crate::{frosty_box::BitMask, interim::InterimPtr, FrostyAllocatable};
+#[cfg(feature = "lock-debug")]
+use crate::lock_debug::{self, Mode, Target};
+use crate::{
+    frosty_box::{BitMask, FrostyBox, Wait},
+    interim::{check_type, InterimPtr},
+    AllocError, FrostyAllocatable,
+};
 
 /*  What is up with all the pointers?
  *      1) FrostyBox<T>
@@ -65,43 +74,125 @@ use crate::{frosty_box::BitMask, interim::InterimPtr, FrostyAllocatable};
  *`                       ---------------------------------------------------
  */
 
+// The type of handles whose type has been erased, like the ones
+// made by dissolve_data(). Checked casts from it to any type
+// succeed, so they can look at any object. It can't be made
+// outside of this crate, so no object is ever actually an Erased.
+// Copy so that Query<Erased> is Copy, like queries of other types
+#[derive(Clone, Copy)]
+pub struct Erased {
+    _private: (),
+}
+
+unsafe impl FrostyAllocatable for Erased {}
+
 //
 //      Data Access
 //
 
+// Unchecked casts trust the caller, but a wrong type is
+// almost always a bug so debug builds still look
+#[track_caller]
+fn debug_assert_type<U: FrostyAllocatable + ?Sized>(found: TypeId) {
+    if cfg!(debug_assertions) {
+        if let Err(e) = check_type::<U>(found) {
+            panic!("unchecked cast to {}: {}", std::any::type_name::<U>(), e);
+        }
+    }
+}
+
 pub struct DataAccess<T: FrostyAllocatable + ?Sized> {
     data: NonNull<T>,
     access: NonNull<BitMask>,
     thread: u32,
+    // type of the value which is actually stored
+    type_id: TypeId,
+    #[cfg(feature = "lock-debug")]
+    target: Target,
 }
 
 impl<T: FrostyAllocatable> DataAccess<T> {
+    // SAFETY:
+    //      the stored value must be a U. Checked in debug builds
     pub unsafe fn cast<U: FrostyAllocatable>(self) -> DataAccess<U> {
-        DataAccess {
-            data: self.data.clone().cast(),
-            access: self.access.clone(),
-            thread: self.thread,
-        }
+        debug_assert_type::<U>(self.type_id);
+        let data = self.data.cast();
+        self.retype(data)
+    }
+
+    // Returns TypeMismatch instead of casting if the stored
+    // value isn't a U. The lock is released if it fails
+    pub fn try_cast<U: FrostyAllocatable>(self) -> Result<DataAccess<U>, AllocError> {
+        check_type::<U>(self.type_id)?;
+        Ok(unsafe { self.cast() })
     }
 
     pub unsafe fn cast_dyn<U: FrostyAllocatable + ?Sized>(self) -> DataAccess<U>
     where
         T: Unsize<U>,
     {
-        let data_ptr = self.data.as_ptr();
-        DataAccess {
-            data: NonNull::new(data_ptr as *mut U).unwrap(),
-            access: self.access.clone(),
-            thread: self.thread,
-        }
+        let data = NonNull::new(self.data.as_ptr() as *mut U).unwrap();
+        self.retype(data)
     }
 }
 
 impl<T: FrostyAllocatable + ?Sized> DataAccess<T> {
+    // Take a read lock on (access) for (thread). Poisoned data
+    // can't be read, so the lock is given back if it is
+    fn lock(
+        data: NonNull<T>,
+        access: NonNull<BitMask>,
+        thread: u32,
+        wait: Wait,
+        inter: &InterimPtr,
+    ) -> Result<Self, AllocError> {
+        #[cfg(feature = "lock-debug")]
+        let blocking = matches!(wait, Wait::Block);
+        #[cfg(feature = "lock-debug")]
+        if blocking {
+            lock_debug::before_lock(inter.target, Mode::Read, thread);
+        }
+        unsafe { access.as_ref().lock_read(wait)? };
+        #[cfg(feature = "lock-debug")]
+        lock_debug::acquired(inter.target, Mode::Read, thread, blocking);
+        // dropping the access releases the lock
+        let locked = Self {
+            data,
+            access,
+            thread,
+            type_id: inter.type_id,
+            #[cfg(feature = "lock-debug")]
+            target: inter.target,
+        };
+        if unsafe { access.as_ref() }.is_poisoned() {
+            return Err(AllocError::LockPoisoned);
+        }
+        Ok(locked)
+    }
+
+    // Move the lock over to a ptr of another type. (self) is
+    // never dropped, so the lock isn't released
+    fn retype<U: FrostyAllocatable + ?Sized>(self, data: NonNull<U>) -> DataAccess<U> {
+        let this = ManuallyDrop::new(self);
+        DataAccess {
+            data,
+            access: this.access,
+            thread: this.thread,
+            type_id: this.type_id,
+            #[cfg(feature = "lock-debug")]
+            target: this.target,
+        }
+    }
+
     pub fn as_ref(&self) -> &T {
         unsafe { self.data.as_ref() }
     }
 
+    // TypeId of the value which is stored, which may not be T
+    pub fn type_id(&self) -> TypeId {
+        self.type_id
+    }
+
     // Print out an identifying number (internal pointer)
     pub fn print_id(&self) {
         println!("[ACCESS ID]: {:p}", self.data);
@@ -112,8 +203,10 @@ impl<T: FrostyAllocatable + ?Sized> DataAccess<T> {
 // returned to [FrostyBox]
 impl<T: FrostyAllocatable + ?Sized> Drop for DataAccess<T> {
     fn drop(&mut self) {
+        #[cfg(feature = "lock-debug")]
+        lock_debug::released(self.target, Mode::Read);
         unsafe {
-            self.access.as_mut().drop_read_access(self.thread);
+            self.access.as_ref().drop_read_access();
         }
     }
 }
@@ -126,22 +219,102 @@ pub struct DataAccessMut<T: FrostyAllocatable + ?Sized> {
     data: NonNull<T>,
     access: NonNull<BitMask>,
     thread: u32,
+    type_id: TypeId,
+    // the object's ticks are updated once the access is
+    // dropped if as_mut() was ever called
+    inter: NonNull<InterimPtr>,
+    written: bool,
+    #[cfg(feature = "lock-debug")]
+    target: Target,
 }
 
 impl<T: FrostyAllocatable + ?Sized> DataAccessMut<T> {
+    // Take the write lock on (access) for (thread)
+    // see DataAccess::lock()
+    fn lock(
+        data: NonNull<T>,
+        access: NonNull<BitMask>,
+        thread: u32,
+        wait: Wait,
+        inter: &InterimPtr,
+    ) -> Result<Self, AllocError> {
+        #[cfg(feature = "lock-debug")]
+        let blocking = matches!(wait, Wait::Block);
+        #[cfg(feature = "lock-debug")]
+        if blocking {
+            lock_debug::before_lock(inter.target, Mode::Write, thread);
+        }
+        unsafe { access.as_ref().lock_write(wait)? };
+        if unsafe { access.as_ref() }.is_poisoned() {
+            unsafe { access.as_ref().drop_write_access() };
+            return Err(AllocError::LockPoisoned);
+        }
+        #[cfg(feature = "lock-debug")]
+        lock_debug::acquired(inter.target, Mode::Write, thread, blocking);
+        Ok(Self {
+            data,
+            access,
+            thread,
+            type_id: inter.type_id,
+            inter: NonNull::from(inter),
+            written: false,
+            #[cfg(feature = "lock-debug")]
+            target: inter.target,
+        })
+    }
+
+    // SAFETY:
+    //      the stored value must be a U. Checked in debug builds
     pub unsafe fn cast<U: FrostyAllocatable>(&self) -> DataAccessMut<U> {
+        debug_assert_type::<U>(self.type_id);
         DataAccessMut {
             data: self.data.clone().cast(),
             access: self.access.clone(),
             thread: self.thread,
+            type_id: self.type_id,
+            inter: self.inter,
+            written: false,
+            #[cfg(feature = "lock-debug")]
+            target: self.target,
         }
     }
 
+    // Returns TypeMismatch instead of casting if the stored value
+    // isn't a U. Unlike cast() the lock is moved into the new
+    // access, and released if it fails
+    pub fn try_cast<U: FrostyAllocatable>(self) -> Result<DataAccessMut<U>, AllocError> {
+        check_type::<U>(self.type_id)?;
+        let this = ManuallyDrop::new(self);
+        Ok(DataAccessMut {
+            data: this.data.cast(),
+            access: this.access,
+            thread: this.thread,
+            type_id: this.type_id,
+            inter: this.inter,
+            written: this.written,
+            #[cfg(feature = "lock-debug")]
+            target: this.target,
+        })
+    }
+
+    // TypeId of the value which is stored, which may not be T
+    pub fn type_id(&self) -> TypeId {
+        self.type_id
+    }
+
     pub fn as_ref(&self) -> &T {
         unsafe { self.data.as_ref() }
     }
 
     pub fn as_mut(&mut self) -> &mut T {
+        self.written = true;
+        unsafe { self.data.as_mut() }
+    }
+
+    // Writes through this aren't recorded in the ComponentTicks.
+    // Only for writes which leave the value as it was, like
+    // moving it between buffers
+    pub fn as_mut_untracked(&mut self) -> &mut T {
         unsafe { self.data.as_mut() }
     }
 
@@ -152,12 +325,22 @@ impl<T: FrostyAllocatable + ?Sized> DataAccessMut<T> {
         // closure will drop the write access but allow us to
         // handle the ptr and thread data before returning from
         // method
-        let (data, mut access, thread) = (move |v: Self| (v.data, v.access, v.thread))(self);
-        unsafe { access.as_mut().get_access(thread) };
+        #[cfg(feature = "lock-debug")]
+        let target = self.target;
+        let type_id = self.type_id;
+        // dropping (self) records the write
+        let (data, access, thread) = (move |v: Self| (v.data, v.access, v.thread))(self);
+        unsafe { access.as_ref().get_access() };
+        // a downgrade can't deadlock, so it doesn't add to the lock order
+        #[cfg(feature = "lock-debug")]
+        lock_debug::acquired(target, Mode::Read, thread, false);
         DataAccess {
             data,
             access,
             thread,
+            type_id,
+            #[cfg(feature = "lock-debug")]
+            target,
         }
     }
 
@@ -168,11 +351,20 @@ impl<T: FrostyAllocatable + ?Sized> DataAccessMut<T> {
 }
 
 // Need to override drop to make sure read access is
-// returned to [FrostyBox]
+// returned to [FrostyBox]. If the thread is panicking the
+// data may have been left half written, so it is poisoned
 impl<T: FrostyAllocatable + ?Sized> Drop for DataAccessMut<T> {
     fn drop(&mut self) {
         unsafe {
-            self.access.as_mut().drop_write_access();
+            if std::thread::panicking() {
+                self.access.as_ref().poison();
+            }
+            if self.written {
+                self.inter.as_ref().mark_changed();
+            }
+            #[cfg(feature = "lock-debug")]
+            lock_debug::released(self.target, Mode::Write);
+            self.access.as_ref().drop_write_access();
         }
     }
 }
@@ -181,27 +373,103 @@ impl<T: FrostyAllocatable + ?Sized> Drop for DataAccessMut<T> {
 //      ObjectHandle
 //
 
+// Find the data and semaphore of the FrostyBox<T> behind (ptr)
+// SAFETY:
+//      (ptr) must point to an [InterimPtr] of a living [Allocator]
+unsafe fn locate<T: FrostyAllocatable>(
+    ptr: NonNull<InterimPtr>,
+    generation: u32,
+) -> Result<(NonNull<T>, NonNull<BitMask>), AllocError> {
+    let boxed: NonNull<FrostyBox<T>> = ptr.as_ref().try_clone_ptr(generation)?;
+    let (data, access) = FrostyBox::get_ptrs(boxed);
+    Ok((NonNull::new_unchecked(data), NonNull::new_unchecked(access)))
+}
+
+// Identifies the object a handle points to. Handles to the same
+// object have the same key, no matter their type or mutability,
+// and a slot reused after a free gets a new key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjectKey {
+    ptr: NonNull<InterimPtr>,
+    generation: u32,
+}
+
+unsafe impl Sync for ObjectKey {}
+unsafe impl Send for ObjectKey {}
+
+// When an object was allocated and last written to, measured
+// in ticks of its Allocator. see Allocator::advance_tick()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComponentTicks {
+    pub added: u32,
+    pub changed: u32,
+    // the tick of the write before (changed), so a write isn't
+    // hidden by another one a tick later
+    pub prev_changed: u32,
+}
+
+impl ComponentTicks {
+    // Returns true if the object was written to during (tick).
+    // Only the last two ticks with a write are remembered
+    pub fn changed_at(&self, tick: u32) -> bool {
+        self.changed == tick || self.prev_changed == tick
+    }
+}
+
 pub struct ObjectHandle<T: FrostyAllocatable + ?Sized> {
     pub(crate) ptr: NonNull<InterimPtr>,
+    // generation of the [InterimPtr] when this handle was made
+    pub(crate) generation: u32,
     pub(crate) _pd: PhantomData<T>,
 }
 
 impl<T: FrostyAllocatable> ObjectHandle<T> {
-    pub(crate) fn get_mut(&mut self) -> &mut InterimPtr {
-        unsafe { self.ptr.as_mut() }
+    pub fn key(&self) -> ObjectKey {
+        ObjectKey {
+            ptr: self.ptr,
+            generation: self.generation,
+        }
     }
 
-    pub fn get_access(&mut self, thread: u32) -> Option<DataAccess<T>> {
-        let (data_ptr, access_ptr) = unsafe {
-            let p = self.ptr.as_ref().try_clone_ptr()?.as_mut();
-            p.get_access(thread);
-            p.get_ptrs()
-        };
-        Some(DataAccess {
-            data: NonNull::new(data_ptr).unwrap(),
-            access: NonNull::new(access_ptr).unwrap(),
-            thread,
-        })
+    fn read(&mut self, thread: u32, wait: Wait) -> Result<DataAccess<T>, AllocError> {
+        let (data, access) = unsafe { locate::<T>(self.ptr, self.generation)? };
+        DataAccess::lock(data, access, thread, wait, unsafe { self.ptr.as_ref() })
+    }
+
+    // Blocks until no thread is writing to the data
+    pub fn get_access(&mut self, thread: u32) -> Result<DataAccess<T>, AllocError> {
+        self.read(thread, Wait::Block)
+    }
+
+    // Returns WouldBlock instead of waiting if the data is locked
+    pub fn try_get_access(&mut self, thread: u32) -> Result<DataAccess<T>, AllocError> {
+        self.read(thread, Wait::Try)
+    }
+
+    // Returns TimedOut if the data is still locked after (timeout)
+    pub fn get_access_timeout(
+        &mut self,
+        thread: u32,
+        timeout: Duration,
+    ) -> Result<DataAccess<T>, AllocError> {
+        self.read(thread, Wait::timeout(timeout))
+    }
+}
+
+impl<T: FrostyAllocatable + ?Sized> Clone for ObjectHandle<T> {
+    fn clone(&self) -> Self {
+        unsafe { self.ptr.as_ref() }.retain();
+        Self {
+            ptr: self.ptr,
+            generation: self.generation,
+            _pd: PhantomData,
+        }
+    }
+}
+
+impl<T: FrostyAllocatable + ?Sized> Drop for ObjectHandle<T> {
+    fn drop(&mut self) {
+        unsafe { InterimPtr::release_handle(self.ptr, self.generation) };
     }
 }
 
@@ -215,58 +483,257 @@ unsafe impl<T: FrostyAllocatable> Send for ObjectHandle<T> {}
 
 pub struct ObjectHandleMut<T: FrostyAllocatable + ?Sized> {
     pub(crate) ptr: NonNull<InterimPtr>,
+    // generation of the [InterimPtr] when this handle was made
+    pub(crate) generation: u32,
     pub(crate) _pd: PhantomData<T>,
 }
 
 impl<T: FrostyAllocatable> ObjectHandleMut<T> {
-    pub fn get_access(&mut self, thread: u32) -> Option<DataAccess<T>> {
-        let (data_ptr, access_ptr) = unsafe {
-            let p = self.ptr.as_ref().try_clone_ptr()?.as_mut();
-            p.get_access(thread);
-            p.get_ptrs()
-        };
-        Some(DataAccess {
-            data: NonNull::new(data_ptr).unwrap(),
-            access: NonNull::new(access_ptr).unwrap(),
-            thread,
-        })
+    // Other handles to the slot may be in use on other threads,
+    // so it is never borrowed mutably
+    pub(crate) fn interim(&self) -> &InterimPtr {
+        unsafe { self.ptr.as_ref() }
     }
 
-    pub fn get_access_mut(&mut self, thread: u32) -> Option<DataAccessMut<T>> {
-        let (data_ptr, access_ptr) = unsafe {
-            let p = self.ptr.as_ref().try_clone_ptr()?.as_mut();
-            p.get_access(thread);
-            p.get_ptrs()
-        };
-        Some(DataAccessMut {
-            data: NonNull::new(data_ptr).unwrap(),
-            access: NonNull::new(access_ptr).unwrap(),
-            thread,
-        })
+    pub fn key(&self) -> ObjectKey {
+        ObjectKey {
+            ptr: self.ptr,
+            generation: self.generation,
+        }
+    }
+
+    // Returns false once the object this handle was made
+    // for has been free'd
+    pub fn is_live(&self) -> bool {
+        unsafe { self.ptr.as_ref().is_live(self.generation) }
+    }
+
+    fn read(&mut self, thread: u32, wait: Wait) -> Result<DataAccess<T>, AllocError> {
+        let (data, access) = unsafe { locate::<T>(self.ptr, self.generation)? };
+        DataAccess::lock(data, access, thread, wait, unsafe { self.ptr.as_ref() })
+    }
+
+    // Blocks until no thread is writing to the data
+    pub fn get_access(&mut self, thread: u32) -> Result<DataAccess<T>, AllocError> {
+        self.read(thread, Wait::Block)
     }
 
-    pub unsafe fn dissolve_data(&mut self) -> ObjectHandleMut<u8> {
+    // Returns WouldBlock instead of waiting if the data is locked
+    pub fn try_get_access(&mut self, thread: u32) -> Result<DataAccess<T>, AllocError> {
+        self.read(thread, Wait::Try)
+    }
+
+    // Returns TimedOut if the data is still locked after (timeout)
+    pub fn get_access_timeout(
+        &mut self,
+        thread: u32,
+        timeout: Duration,
+    ) -> Result<DataAccess<T>, AllocError> {
+        self.read(thread, Wait::timeout(timeout))
+    }
+
+    fn write(&mut self, thread: u32, wait: Wait) -> Result<DataAccessMut<T>, AllocError> {
+        let (data, access) = unsafe { locate::<T>(self.ptr, self.generation)? };
+        DataAccessMut::lock(data, access, thread, wait, unsafe { self.ptr.as_ref() })
+    }
+
+    // Blocks until no other thread is reading or writing the data
+    pub fn get_access_mut(&mut self, thread: u32) -> Result<DataAccessMut<T>, AllocError> {
+        self.write(thread, Wait::Block)
+    }
+
+    // Returns WouldBlock instead of waiting if the data is locked
+    pub fn try_get_access_mut(&mut self, thread: u32) -> Result<DataAccessMut<T>, AllocError> {
+        self.write(thread, Wait::Try)
+    }
+
+    // Returns TimedOut if the data is still locked after (timeout)
+    pub fn get_access_mut_timeout(
+        &mut self,
+        thread: u32,
+        timeout: Duration,
+    ) -> Result<DataAccessMut<T>, AllocError> {
+        self.write(thread, Wait::timeout(timeout))
+    }
+
+    // Allow the object to be accessed again after a thread
+    // panicked while writing to it
+    pub fn clear_poison(&mut self) -> Result<(), AllocError> {
+        unsafe {
+            let p = self.ptr.as_ref().try_clone_ptr::<T>(self.generation)?;
+            (*FrostyBox::get_ptrs(p).1).clear_poison();
+        }
+        Ok(())
+    }
+
+    // A handle to the same object which can only read it
+    pub fn read_only(&self) -> ObjectHandle<T> {
+        unsafe { self.ptr.as_ref() }.retain();
+        ObjectHandle {
+            ptr: self.ptr,
+            generation: self.generation,
+            _pd: PhantomData,
+        }
+    }
+
+    pub unsafe fn dissolve_data(&mut self) -> ObjectHandleMut<Erased> {
+        self.ptr.as_ref().retain();
         ObjectHandleMut {
             ptr: self.ptr,
+            generation: self.generation,
             _pd: PhantomData,
         }
     }
 
+    // Doesn't check the stored value is a U outside of debug builds.
+    // Accessing the data through the wrong type reads garbage
+    #[track_caller]
     pub fn cast_clone<U: FrostyAllocatable>(&self) -> ObjectHandleMut<U> {
+        // freed handles will fail on access anyways
+        if self.is_live() {
+            debug_assert_type::<U>(self.type_id());
+        }
+        unsafe { self.ptr.as_ref() }.retain();
         ObjectHandleMut {
             ptr: self.ptr.clone(),
+            generation: self.generation,
+            _pd: PhantomData,
+        }
+    }
+
+    // Returns TypeMismatch if the stored value isn't a U
+    pub fn try_cast_clone<U: FrostyAllocatable>(&self) -> Result<ObjectHandleMut<U>, AllocError> {
+        let inter = unsafe { self.ptr.as_ref() };
+        if !inter.is_live(self.generation) {
+            return Err(AllocError::HandleFreed);
+        }
+        inter.check_type::<U>()?;
+        Ok(self.cast_clone())
+    }
+
+    // TypeId of the value stored in the allocator, which
+    // will be different from T if this handle was dissolved
+    pub fn type_id(&self) -> TypeId {
+        unsafe { self.ptr.as_ref().type_id }
+    }
+
+    // Size of the value stored in the allocator
+    pub fn value_size(&self) -> usize {
+        unsafe { self.ptr.as_ref().value_size }
+    }
+
+    // Make a handle which doesn't keep the object alive
+    pub fn downgrade(&self) -> WeakHandle<T> {
+        unsafe { self.ptr.as_ref() }.retain_weak();
+        WeakHandle {
+            ptr: self.ptr,
+            generation: self.generation,
             _pd: PhantomData,
         }
     }
+
+    // Number of strong handles to the object, including this one
+    pub fn strong_count(&self) -> u32 {
+        unsafe { self.ptr.as_ref().strong_count() }
+    }
+
+    // A write is recorded when an access which called
+    // DataAccessMut::as_mut() is dropped
+    pub fn ticks(&self) -> Result<ComponentTicks, AllocError> {
+        let inter = unsafe { self.ptr.as_ref() };
+        if !inter.is_live(self.generation) {
+            return Err(AllocError::HandleFreed);
+        }
+        Ok(ComponentTicks {
+            added: inter.added,
+            changed: inter.changed.load(std::sync::atomic::Ordering::Relaxed),
+            prev_changed: inter
+                .prev_changed
+                .load(std::sync::atomic::Ordering::Relaxed),
+        })
+    }
+}
+
+impl<T: FrostyAllocatable + ?Sized> Clone for ObjectHandleMut<T> {
+    fn clone(&self) -> Self {
+        unsafe { self.ptr.as_ref() }.retain();
+        Self {
+            ptr: self.ptr,
+            generation: self.generation,
+            _pd: PhantomData,
+        }
+    }
+}
+
+// Objects made with Allocator::alloc_shared() are queued to be
+// free'd once the last of these is dropped
+impl<T: FrostyAllocatable + ?Sized> Drop for ObjectHandleMut<T> {
+    fn drop(&mut self) {
+        unsafe { InterimPtr::release_handle(self.ptr, self.generation) };
+    }
 }
 
 unsafe impl<T: FrostyAllocatable> Sync for ObjectHandleMut<T> {}
 unsafe impl<T: FrostyAllocatable> Send for ObjectHandleMut<T> {}
 
-// An object handle which stores trait objects
+//
+//      WeakHandle
+//
+
+// A handle which doesn't count towards keeping an object alive.
+// It has to be upgraded into a strong handle to access the data.
+// Upgrading fails once the object, or its [Allocator], is gone
+pub struct WeakHandle<T: FrostyAllocatable + ?Sized> {
+    ptr: NonNull<InterimPtr>,
+    generation: u32,
+    _pd: PhantomData<T>,
+}
+
+impl<T: FrostyAllocatable> WeakHandle<T> {
+    // Returns None once the object has been free'd, or is
+    // waiting to be free'd automatically
+    pub fn upgrade(&self) -> Option<ObjectHandleMut<T>> {
+        if !unsafe { self.ptr.as_ref() }.try_retain(self.generation) {
+            return None;
+        }
+        Some(ObjectHandleMut {
+            ptr: self.ptr,
+            generation: self.generation,
+            _pd: PhantomData,
+        })
+    }
+}
+
+impl<T: FrostyAllocatable + ?Sized> Clone for WeakHandle<T> {
+    fn clone(&self) -> Self {
+        unsafe { self.ptr.as_ref() }.retain_weak();
+        Self {
+            ptr: self.ptr,
+            generation: self.generation,
+            _pd: PhantomData,
+        }
+    }
+}
+
+impl<T: FrostyAllocatable + ?Sized> Drop for WeakHandle<T> {
+    fn drop(&mut self) {
+        unsafe { InterimPtr::release_weak(self.ptr) };
+    }
+}
+
+unsafe impl<T: FrostyAllocatable> Sync for WeakHandle<T> {}
+unsafe impl<T: FrostyAllocatable> Send for WeakHandle<T> {}
+
+// An object handle which stores trait objects. Like [ObjectHandle]
+// this goes through the [InterimPtr] on every access, so it keeps
+// working if the object is moved by [Allocator::compact]
 pub struct DynObjectHandle<T: FrostyAllocatable + ?Sized> {
-    data: NonNull<T>,
-    access: NonNull<BitMask>,
+    ptr: NonNull<InterimPtr>,
+    generation: u32,
+    // offset of the data from the start of its [FrostyBox]
+    data_offset: usize,
+    // vtable needed to rebuild a ptr to T
+    metadata: <T as Pointee>::Metadata,
 }
 
 impl<T: FrostyAllocatable + ?Sized> DynObjectHandle<T> {
@@ -274,41 +741,75 @@ impl<T: FrostyAllocatable + ?Sized> DynObjectHandle<T> {
     where
         U: Unsize<T>,
     {
-        let (data_ptr, access_ptr): (*mut U, *mut BitMask) = unsafe {
-            handle
+        let (box_ptr, data_ptr): (*mut u8, *mut U) = unsafe {
+            let boxed = handle
                 .ptr
                 .as_ref()
-                .try_clone_ptr()
-                .unwrap()
-                .as_mut()
-                .get_ptrs()
+                .try_clone_ptr::<U>(handle.generation)
+                .unwrap();
+            (boxed.as_ptr() as *mut u8, FrostyBox::get_ptrs(boxed).0)
         };
+        unsafe { handle.ptr.as_ref() }.retain();
         Self {
-            data: NonNull::new(data_ptr).unwrap(),
-            access: NonNull::new(access_ptr).unwrap(),
+            ptr: handle.ptr,
+            generation: handle.generation,
+            data_offset: data_ptr as usize - box_ptr as usize,
+            metadata: ptr::metadata(data_ptr as *mut T),
         }
     }
 
-    pub fn get_access(&mut self, thread: u32) -> Option<DataAccess<T>> {
-        unsafe {
-            self.access.as_mut().get_access(thread);
+    // Find where the data currently lives
+    fn get_ptrs(&self) -> Result<(NonNull<T>, NonNull<BitMask>), AllocError> {
+        let inter = unsafe { self.ptr.as_ref() };
+        if !inter.is_live(self.generation) {
+            return Err(AllocError::HandleFreed);
         }
-        Some(DataAccess {
-            data: self.data.clone(),
-            access: self.access.clone(),
-            thread,
-        })
+        // [FrostyBox] is repr(C), so the semaphore is at its start
+        let data = unsafe { inter.data.as_ptr().add(self.data_offset) };
+        let data: *mut T = ptr::from_raw_parts_mut(data, self.metadata);
+        Ok((NonNull::new(data).unwrap(), inter.data.cast()))
     }
 
-    pub fn get_access_mut(&mut self, thread: u32) -> Option<DataAccessMut<T>> {
-        unsafe {
-            self.access.as_mut().get_access_mut(thread);
-        }
-        Some(DataAccessMut {
-            data: self.data.clone(),
-            access: self.access.clone(),
-            thread,
-        })
+    fn read(&mut self, thread: u32, wait: Wait) -> Result<DataAccess<T>, AllocError> {
+        let (data, access) = self.get_ptrs()?;
+        DataAccess::lock(data, access, thread, wait, unsafe { self.ptr.as_ref() })
+    }
+
+    fn write(&mut self, thread: u32, wait: Wait) -> Result<DataAccessMut<T>, AllocError> {
+        let (data, access) = self.get_ptrs()?;
+        DataAccessMut::lock(data, access, thread, wait, unsafe { self.ptr.as_ref() })
+    }
+
+    pub fn get_access(&mut self, thread: u32) -> Result<DataAccess<T>, AllocError> {
+        self.read(thread, Wait::Block)
+    }
+
+    pub fn try_get_access(&mut self, thread: u32) -> Result<DataAccess<T>, AllocError> {
+        self.read(thread, Wait::Try)
+    }
+
+    pub fn get_access_timeout(
+        &mut self,
+        thread: u32,
+        timeout: Duration,
+    ) -> Result<DataAccess<T>, AllocError> {
+        self.read(thread, Wait::timeout(timeout))
+    }
+
+    pub fn get_access_mut(&mut self, thread: u32) -> Result<DataAccessMut<T>, AllocError> {
+        self.write(thread, Wait::Block)
+    }
+
+    pub fn try_get_access_mut(&mut self, thread: u32) -> Result<DataAccessMut<T>, AllocError> {
+        self.write(thread, Wait::Try)
+    }
+
+    pub fn get_access_mut_timeout(
+        &mut self,
+        thread: u32,
+        timeout: Duration,
+    ) -> Result<DataAccessMut<T>, AllocError> {
+        self.write(thread, Wait::timeout(timeout))
     }
 }
 
@@ -317,9 +818,331 @@ where
     T: FrostyAllocatable + ?Sized,
 {
     fn clone(&self) -> Self {
+        unsafe { self.ptr.as_ref() }.retain();
         Self {
-            data: self.data.clone(),
-            access: self.access.clone(),
+            ptr: self.ptr,
+            generation: self.generation,
+            data_offset: self.data_offset,
+            metadata: self.metadata,
+        }
+    }
+}
+
+impl<T: FrostyAllocatable + ?Sized> Drop for DynObjectHandle<T> {
+    fn drop(&mut self) {
+        unsafe { InterimPtr::release_handle(self.ptr, self.generation) };
+    }
+}
+
+#[cfg(test)]
+mod handle_tests {
+    use std::{
+        any::TypeId,
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        AllocError, Allocator, ComponentTicks, DynObjectHandle, Erased, FrostyAllocatable,
+    };
+
+    #[test]
+    fn try_access_would_block() {
+        let mut alloc = Allocator::new();
+        let mut writer = alloc.alloc(1u32).unwrap();
+        let mut other = writer.cast_clone::<u32>();
+
+        let write = writer.get_access_mut(0).unwrap();
+        assert!(matches!(
+            other.try_get_access(1),
+            Err(AllocError::WouldBlock)
+        ));
+        assert!(matches!(
+            other.try_get_access_mut(1),
+            Err(AllocError::WouldBlock)
+        ));
+        drop(write);
+
+        let read = writer.get_access(0).unwrap();
+        // readers share, writers have to wait
+        assert!(other.try_get_access(1).is_ok());
+        assert!(matches!(
+            other.try_get_access_mut(1),
+            Err(AllocError::WouldBlock)
+        ));
+        drop(read);
+        assert!(other.try_get_access_mut(1).is_ok());
+    }
+
+    #[test]
+    fn access_times_out() {
+        let mut alloc = Allocator::new();
+        let mut writer = alloc.alloc(1u32).unwrap();
+        let mut other = writer.cast_clone::<u32>();
+
+        let read = writer.get_access(0).unwrap();
+        let timeout = Duration::from_millis(20);
+        let start = Instant::now();
+        assert!(matches!(
+            other.get_access_mut_timeout(1, timeout),
+            Err(AllocError::TimedOut)
+        ));
+        assert!(start.elapsed() >= timeout);
+        // the writer which gave up shouldn't hold off new readers
+        assert!(other.try_get_access(2).is_ok());
+        drop(read);
+    }
+
+    #[test]
+    fn timeout_succeeds_once_released() {
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(1u32).unwrap();
+        let mut remote = handle.cast_clone::<u32>();
+
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let holder = thread::spawn(move || {
+            let mut access = remote.get_access_mut(1).unwrap();
+            locked_tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            *access.as_mut() = 2;
+        });
+        locked_rx.recv().unwrap();
+        let access = handle
+            .get_access_timeout(0, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(2, *access.as_ref());
+        drop(access);
+        holder.join().unwrap();
+    }
+
+    struct Pair {
+        a: u64,
+        b: u64,
+    }
+    unsafe impl FrostyAllocatable for Pair {}
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn stress_64_threads() {
+        const THREADS: u32 = 64;
+        const OBJECTS: usize = 8;
+        const ROUNDS: usize = 2_000;
+
+        let mut alloc = Allocator::new();
+        let handles = alloc
+            .alloc_many((0..OBJECTS).map(|_| Pair { a: 0, b: 0 }))
+            .unwrap();
+
+        let workers: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let mut handles: Vec<_> = handles.iter().map(|h| h.cast_clone::<Pair>()).collect();
+                thread::spawn(move || {
+                    for round in 0..ROUNDS {
+                        let handle = &mut handles[(round + thread as usize) % OBJECTS];
+                        if thread % 2 == 0 {
+                            let mut access = handle.get_access_mut(thread).unwrap();
+                            let pair = access.as_mut();
+                            pair.a += 1;
+                            // readers must never see a half written pair
+                            thread::yield_now();
+                            pair.b += 1;
+                        } else {
+                            let access = handle.get_access(thread).unwrap();
+                            assert_eq!(access.as_ref().a, access.as_ref().b);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let mut total = 0;
+        for mut handle in handles {
+            let access = handle.try_get_access(0).unwrap();
+            assert_eq!(access.as_ref().a, access.as_ref().b);
+            total += access.as_ref().a;
         }
+        assert_eq!((THREADS as usize / 2 * ROUNDS) as u64, total);
+    }
+
+    trait Counter {
+        fn count(&self) -> u32;
+    }
+
+    impl Counter for u32 {
+        fn count(&self) -> u32 {
+            *self
+        }
+    }
+
+    unsafe impl FrostyAllocatable for dyn Counter {}
+
+    #[test]
+    fn dyn_try_access() {
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(7u32).unwrap();
+        let mut dyn_handle: DynObjectHandle<dyn Counter> = DynObjectHandle::new(&handle);
+
+        let write = handle.get_access_mut(0).unwrap();
+        assert!(matches!(
+            dyn_handle.try_get_access(1),
+            Err(AllocError::WouldBlock)
+        ));
+        assert!(matches!(
+            dyn_handle.get_access_mut_timeout(1, Duration::from_millis(5)),
+            Err(AllocError::TimedOut)
+        ));
+        drop(write);
+        assert_eq!(7, dyn_handle.try_get_access(1).unwrap().as_ref().count());
+    }
+
+    #[test]
+    fn checked_casts() {
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(3u32).unwrap();
+        let erased = unsafe { handle.dissolve_data() };
+        assert_eq!(TypeId::of::<u32>(), erased.type_id());
+        assert_eq!(4, erased.value_size());
+
+        assert!(erased.try_cast_clone::<u32>().is_ok());
+        assert_eq!(
+            Some(AllocError::TypeMismatch {
+                expected: TypeId::of::<f32>(),
+                found: TypeId::of::<u32>(),
+            }),
+            erased.try_cast_clone::<f32>().err()
+        );
+
+        let access = erased.cast_clone::<Erased>().get_access(0).unwrap();
+        assert!(matches!(
+            access.try_cast::<i32>(),
+            Err(AllocError::TypeMismatch { .. })
+        ));
+        // the failed cast gave the lock back
+        let access = handle
+            .try_get_access_mut(0)
+            .unwrap()
+            .try_cast::<u32>()
+            .unwrap();
+        assert_eq!(3, *access.as_ref());
+        drop(access);
+
+        alloc.free(&mut handle).unwrap();
+        assert!(matches!(
+            erased.try_cast_clone::<u32>(),
+            Err(AllocError::HandleFreed)
+        ));
+    }
+
+    #[test]
+    fn handles_are_counted() {
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(1u32).unwrap();
+        assert_eq!(1, handle.strong_count());
+
+        let copy = handle.clone();
+        let erased = unsafe { handle.dissolve_data() };
+        let dyn_handle: DynObjectHandle<dyn Counter> = DynObjectHandle::new(&handle);
+        let weak = handle.downgrade();
+        assert_eq!(4, handle.strong_count());
+
+        let reader = handle.read_only();
+        assert_eq!(5, handle.strong_count());
+        assert_eq!(handle.key(), reader.key());
+        assert_eq!(handle.key(), erased.key());
+
+        drop((copy, erased, dyn_handle, reader));
+        assert_eq!(1, handle.strong_count());
+        let upgraded = weak.upgrade().unwrap();
+        assert_eq!(2, upgraded.strong_count());
+        drop(upgraded);
+
+        let key = handle.key();
+        alloc.free(&mut handle).unwrap();
+        assert!(weak.upgrade().is_none());
+        // the slot is reused for the next object, under a new key
+        let reused = alloc.alloc(2u32).unwrap();
+        assert_ne!(key, reused.key());
+    }
+
+    #[test]
+    fn upgrades_race_free() {
+        let mut alloc = Allocator::new();
+        for round in 0..8u32 {
+            let mut handle = alloc.alloc(round).unwrap();
+            let weak = handle.downgrade();
+            let upgrader = thread::spawn(move || {
+                let mut upgraded = 0;
+                for _ in 0..4 {
+                    if let Some(strong) = weak.upgrade() {
+                        upgraded += 1;
+                        drop(strong);
+                    }
+                }
+                (weak, upgraded)
+            });
+
+            alloc.free(&mut handle).unwrap();
+            drop(handle);
+            let (weak, _) = upgrader.join().unwrap();
+            assert!(weak.upgrade().is_none());
+
+            // a handle which won the race parks the slot until the
+            // next reclaim, so it is never handed out while counted
+            alloc.reclaim();
+            let mut reused = alloc.alloc(round + 1).unwrap();
+            assert_eq!(1, reused.strong_count());
+            assert_eq!(round + 1, *reused.get_access(0).unwrap().as_ref());
+            assert!(weak.upgrade().is_none());
+        }
+    }
+
+    #[test]
+    fn writes_record_the_tick() {
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(5u32).unwrap();
+        alloc.advance_tick();
+        let mut late = alloc.alloc(1u32).unwrap();
+        assert_eq!(1, alloc.tick());
+        assert!(handle.ticks().unwrap().changed_at(0));
+        assert_eq!(1, late.ticks().unwrap().added);
+
+        // taking write access alone doesn't count
+        alloc.advance_tick();
+        drop(handle.get_access_mut(0).unwrap());
+        assert_eq!(0, handle.ticks().unwrap().changed);
+        *handle.get_access_mut(0).unwrap().as_mut() = 6;
+        *handle.get_access_mut(0).unwrap().as_mut() = 7;
+        let ticks = handle.ticks().unwrap();
+        assert!(ticks.changed_at(2) && ticks.changed_at(0));
+
+        // the write a tick earlier is still remembered
+        alloc.advance_tick();
+        let mut write = handle.get_access_mut(0).unwrap();
+        *write.as_mut() = 8;
+        assert_eq!(8, *write.drop_mut().as_ref());
+        assert_eq!(
+            ComponentTicks {
+                added: 0,
+                changed: 3,
+                prev_changed: 2
+            },
+            handle.ticks().unwrap()
+        );
+
+        alloc.free(&mut late).unwrap();
+        assert!(matches!(late.ticks(), Err(AllocError::HandleFreed)));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "unchecked cast to f64")]
+    fn unchecked_cast_asserts() {
+        let mut alloc = Allocator::new();
+        let handle = alloc.alloc(3u32).unwrap();
+        let _ = handle.cast_clone::<f64>();
     }
 }
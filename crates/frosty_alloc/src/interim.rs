@@ -1,37 +1,306 @@
-use std::ptr::NonNull;
+use std::{
+    any::TypeId,
+    ptr::{self, NonNull},
+    sync::{
+        atomic::{fence, AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
 
-use crate::{frosty_box::FrostyBox, FrostyAllocatable};
+#[cfg(feature = "lock-debug")]
+use crate::lock_debug;
+use crate::{
+    allocator::Index,
+    frosty_box::{BitMask, FrostyBox},
+    AllocError, Erased, FrostyAllocatable,
+};
+
+// number of [InterimPtr]s stored in a single page of an [InterimTable]
+const INTERIM_PAGE_LEN: usize = 256;
+
+// Runs the destructor of the FrostyBox<T> stored at some ptr
+pub(crate) type DropFn = unsafe fn(NonNull<u8>);
+
+// SAFETY:
+//      data must point to a valid FrostyBox<T> which is never
+//      read from again
+pub(crate) unsafe fn drop_boxed<T: FrostyAllocatable>(data: NonNull<u8>) {
+    ptr::drop_in_place(data.as_ptr() as *mut FrostyBox<T>);
+}
+
+unsafe fn drop_nothing(_: NonNull<u8>) {}
+
+// Slots are queued here by the last strong handle to drop, along
+// with that handle's generation, if the slot was waiting on it.
+// Handles can't reach the free list, so the [Allocator] picks
+// them up later
+pub(crate) type ReleaseQueue = Mutex<Vec<(Index, u32)>>;
+
+// The tick of an [InterimTable]. Written objects record it so
+// changes can be found later, see ComponentTicks
+pub(crate) type Clock = AtomicU32;
+
+// The parts of an [InterimTable] which handles can reach. Every
+// handle, strong or weak, holds a count on it, so the slots stay
+// readable once the [Allocator] is gone
+pub(crate) struct TableShared {
+    released: ReleaseQueue,
+    clock: Clock,
+    // every page the table has made, free'd with the last count
+    pages: Mutex<Vec<NonNull<[InterimPtr]>>>,
+}
+
+// Pages are only written by the [Allocator], see [InterimTable]
+unsafe impl Send for TableShared {}
+unsafe impl Sync for TableShared {}
+
+impl Drop for TableShared {
+    fn drop(&mut self) {
+        let pages = self.pages.get_mut().unwrap_or_else(|e| e.into_inner());
+        for page in pages.drain(..) {
+            unsafe { drop(Box::from_raw(page.as_ptr())) };
+        }
+    }
+}
+
+// Returns an error unless (found) is the TypeId of T. [Erased]
+// stands in for every type
+pub(crate) fn check_type<T: ?Sized + 'static>(found: TypeId) -> Result<(), AllocError> {
+    let expected = TypeId::of::<T>();
+    if expected != TypeId::of::<Erased>() && expected != found {
+        return Err(AllocError::TypeMismatch { expected, found });
+    }
+    Ok(())
+}
 
 pub(crate) struct InterimPtr {
-    pub(crate) freed: bool,
-    pub(crate) active_handles: u32,
+    // read by handles on any thread, but only written by the [Allocator]
+    pub(crate) freed: AtomicBool,
+    // bumped every time the slot is free'd, so a handle created
+    // for a previous occupant of the slot can tell its data is gone.
+    // Read by handles on any thread, like (freed)
+    pub(crate) generation: AtomicU32,
+    // number of strong handles pointing at this slot. Slots aren't
+    // reused until this is 0, so every handle counted is for the
+    // current occupant
+    pub(crate) active_handles: AtomicU32,
+    // free the object once its last strong handle is dropped
+    pub(crate) auto_free: AtomicBool,
+    // free'd while handles were still around, so the slot can't
+    // be reused until they are gone. Only used by the [Allocator],
+    // but handles may be looking at the slot when it is changed
+    pub(crate) parked: AtomicBool,
+    // free'd while still being accessed, so the object hasn't been
+    // dropped and its memory is still in use. see (parked)
+    pub(crate) undropped: AtomicBool,
+    // the shared parts of the [InterimTable] this belongs to
+    table: NonNull<TableShared>,
+    // ticks the object was allocated and last written at, and
+    // the tick of the write before that. Only changed under the
+    // write lock
+    pub(crate) added: u32,
+    pub(crate) changed: AtomicU32,
+    pub(crate) prev_changed: AtomicU32,
     // data pointer: quick access during gameloop
-    // index:        slow access during allocator resize
+    // page, index:  location of the data in the allocator region
     pub(crate) data: NonNull<u8>,
+    pub(crate) page: usize,
     pub(crate) index: usize,
+    // layout of the FrostyBox<T> and how to drop it. Recorded at
+    // allocation so objects can be free'd or moved without knowing T
+    pub(crate) size: usize,
+    pub(crate) align: usize,
+    pub(crate) drop_fn: DropFn,
+    // what T is, so handles can't be cast to the wrong type
+    pub(crate) type_id: TypeId,
+    pub(crate) value_size: usize,
+    #[cfg(feature = "free-poison")]
+    pub(crate) type_name: &'static str,
+    // position of this ptr in the [InterimTable]
+    pub(crate) slot: Index,
+    // what accesses to the object are traced as
+    #[cfg(feature = "lock-debug")]
+    pub(crate) target: lock_debug::Target,
 }
 
 impl InterimPtr {
-    pub unsafe fn new<T: FrostyAllocatable>(data: &mut FrostyBox<T>, index: usize) -> Self {
+    fn vacant(slot: Index, table: NonNull<TableShared>) -> Self {
         Self {
-            freed: false,
-            active_handles: 0,
-            data: NonNull::new_unchecked(data as *mut FrostyBox<T> as *mut u8),
-            index,
+            freed: AtomicBool::new(true),
+            generation: AtomicU32::new(0),
+            active_handles: AtomicU32::new(0),
+            auto_free: AtomicBool::new(false),
+            parked: AtomicBool::new(false),
+            undropped: AtomicBool::new(false),
+            table,
+            added: 0,
+            changed: AtomicU32::new(0),
+            prev_changed: AtomicU32::new(0),
+            data: NonNull::dangling(),
+            page: 0,
+            index: 0,
+            size: 0,
+            align: 1,
+            drop_fn: drop_nothing,
+            type_id: TypeId::of::<()>(),
+            value_size: 0,
+            #[cfg(feature = "free-poison")]
+            type_name: "()",
+            slot,
+            #[cfg(feature = "lock-debug")]
+            target: lock_debug::Target::new::<()>(slot),
+        }
+    }
+
+    // Takes &self, since handles on other threads can be
+    // looking at the slot while it is free'd
+    pub(crate) fn free(&self) {
+        self.freed.store(true, Ordering::Release);
+        self.generation.fetch_add(1, Ordering::Release);
+        #[cfg(feature = "lock-debug")]
+        lock_debug::forget(self.target);
+    }
+
+    pub(crate) fn is_freed(&self) -> bool {
+        self.freed.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn is_auto_free(&self) -> bool {
+        self.auto_free.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn generation(&self) -> u32 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn is_parked(&self) -> bool {
+        self.parked.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_undropped(&self) -> bool {
+        self.undropped.load(Ordering::Relaxed)
+    }
+
+    // Take the write lock of the stored object if nothing else is
+    // reading, writing or waiting to write it. Nothing can access
+    // it afterwards, so it is safe to drop
+    fn try_lock_idle(&self) -> bool {
+        // [FrostyBox] is repr(C), so the semaphore is at its start
+        unsafe { self.data.cast::<BitMask>().as_ref() }.try_lock_idle()
+    }
+
+    // Run the destructor of the stored object
+    // SAFETY:
+    //      must be called at most once for each object stored in
+    //      the slot, while nothing else is accessing it
+    pub(crate) unsafe fn drop_data(&self) {
+        (self.drop_fn)(self.data);
+    }
+
+    // A new strong handle has been made
+    pub(crate) fn retain(&self) {
+        self.active_handles.fetch_add(1, Ordering::Relaxed);
+        self.retain_weak();
+    }
+
+    // A new weak handle has been made. It only keeps the table alive
+    pub(crate) fn retain_weak(&self) {
+        unsafe { Arc::increment_strong_count(self.table.as_ptr()) };
+    }
+
+    // A weak handle has been dropped. This may free the table, so
+    // (ptr) can't be used afterwards
+    pub(crate) unsafe fn release_weak(ptr: NonNull<InterimPtr>) {
+        let table = ptr.as_ref().table;
+        Arc::decrement_strong_count(table.as_ptr());
+    }
+
+    // Make a strong handle out of a weak one. Objects which are
+    // free'd automatically can't be revived once their last strong
+    // handle is gone, even if they haven't been free'd yet
+    pub(crate) fn try_retain(&self, generation: u32) -> bool {
+        if !self.is_live(generation) {
+            return false;
+        }
+        if !self.is_auto_free() {
+            self.active_handles.fetch_add(1, Ordering::Relaxed);
+        } else if self
+            .active_handles
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |n| {
+                (n > 0).then_some(n + 1)
+            })
+            .is_err()
+        {
+            return false;
+        }
+        // The object may have been free'd since it was checked. The
+        // master thread reads the count after freeing, so either it
+        // sees this handle and parks the slot or the free is seen here
+        fence(Ordering::SeqCst);
+        if !self.is_live(generation) {
+            self.release_count(generation);
+            return false;
+        }
+        self.retain_weak();
+        true
+    }
+
+    // A strong handle made for (generation) has been dropped. This
+    // may free the table, so (ptr) can't be used afterwards
+    pub(crate) unsafe fn release_handle(ptr: NonNull<InterimPtr>, generation: u32) {
+        ptr.as_ref().release_count(generation);
+        Self::release_weak(ptr);
+    }
+
+    // Drop one from the strong count, queueing the slot to be
+    // reclaimed if it was the last handle to a free'd object
+    fn release_count(&self, generation: u32) {
+        if self.active_handles.fetch_sub(1, Ordering::AcqRel) == 1
+            && (self.is_freed() || self.is_auto_free())
+        {
+            unsafe { self.table.as_ref() }
+                .released
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push((self.slot, generation));
+        }
+    }
+
+    // The object was written to during the current tick
+    pub(crate) fn mark_changed(&self) {
+        let now = unsafe { self.table.as_ref() }.clock.load(Ordering::Relaxed);
+        let last = self.changed.load(Ordering::Relaxed);
+        if last != now {
+            self.prev_changed.store(last, Ordering::Relaxed);
+            self.changed.store(now, Ordering::Relaxed);
         }
     }
 
-    pub(crate) fn free(&mut self) {
-        self.freed = true;
+    pub(crate) fn strong_count(&self) -> u32 {
+        self.active_handles.load(Ordering::Acquire)
+    }
+
+    // Returns true if a handle created with [generation] still
+    // points at live data
+    pub(crate) fn is_live(&self, generation: u32) -> bool {
+        !self.is_freed() && self.generation() == generation
+    }
+
+    // Returns an error if the boxed value isn't a T
+    pub(crate) fn check_type<T: ?Sized + 'static>(&self) -> Result<(), AllocError> {
+        check_type::<T>(self.type_id)
     }
 
     // Returns a clone of internal ptr to FrostyBox<T> if the data
-    // has not been free'd. If it has been, returns None
-    pub(crate) fn try_clone_ptr<T: FrostyAllocatable>(&self) -> Option<NonNull<FrostyBox<T>>> {
-        if self.freed {
-            return None;
+    // has not been free'd
+    pub(crate) fn try_clone_ptr<T: FrostyAllocatable>(
+        &self,
+        generation: u32,
+    ) -> Result<NonNull<FrostyBox<T>>, AllocError> {
+        if !self.is_live(generation) {
+            return Err(AllocError::HandleFreed);
         }
-        Some(self.data.clone().cast())
+        Ok(self.data.cast())
     }
 
     // Returns a clone of internal ptr to FrostyBox<T> without checking
@@ -41,3 +310,225 @@ impl InterimPtr {
         self.data.clone().cast()
     }
 }
+
+// Storage for every [InterimPtr] an [Allocator] has handed out.
+//
+// [ObjectHandle]s point directly at an [InterimPtr], so the ptrs
+// can never move once created. They are kept in fixed size pages
+// which are only ever appended to. Slots which have been free'd
+// are recycled through (free_slots) instead of growing the table.
+// The pages are owned by a [TableShared], which lives until the
+// table and every handle into it are gone.
+pub(crate) struct InterimTable {
+    // the first slot of each page. Handles are made from these
+    // ptrs, never from a reference into a page
+    pages: Vec<NonNull<InterimPtr>>,
+    // number of slots which have ever been handed out
+    len: usize,
+    free_slots: Vec<Index>,
+    // slots free'd while their object was locked, waiting
+    // to be dropped
+    undropped: Vec<Index>,
+    shared: Arc<TableShared>,
+}
+
+impl InterimTable {
+    pub fn new() -> Self {
+        Self {
+            pages: Vec::new(),
+            len: 0,
+            free_slots: Vec::new(),
+            undropped: Vec::new(),
+            shared: Arc::new(TableShared {
+                released: Mutex::new(Vec::new()),
+                clock: AtomicU32::new(0),
+                pages: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    // Number of slots which have been created, whether or
+    // not they are currently in use
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // Claim a slot for a FrostyBox<T> stored at (index) in (page) of
+    // the allocator region and return the slot's index
+    pub fn insert<T: FrostyAllocatable>(
+        &mut self,
+        data: NonNull<u8>,
+        page: usize,
+        index: usize,
+    ) -> Index {
+        let slot = match self.free_slots.pop() {
+            Some(slot) => slot,
+            None => {
+                let slot = self.len;
+                if slot == self.pages.len() * INTERIM_PAGE_LEN {
+                    let table = NonNull::new(Arc::as_ptr(&self.shared).cast_mut()).unwrap();
+                    let page: Box<[InterimPtr]> = (slot..slot + INTERIM_PAGE_LEN)
+                        .map(|slot| InterimPtr::vacant(slot, table))
+                        .collect();
+                    let page = NonNull::from(Box::leak(page));
+                    self.pages.push(page.cast());
+                    self.shared
+                        .pages
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(page);
+                }
+                self.len += 1;
+                slot
+            }
+        };
+        let now = self.tick();
+        // (active_handles) is left alone, a weak handle racing the
+        // slot's last free may still be backing out its count
+        let inter = self.get_mut(slot).expect("InterimTable slot out of bounds");
+        inter.auto_free.store(false, Ordering::Release);
+        *inter.parked.get_mut() = false;
+        *inter.undropped.get_mut() = false;
+        inter.added = now;
+        *inter.changed.get_mut() = now;
+        *inter.prev_changed.get_mut() = now;
+        inter.data = data;
+        inter.page = page;
+        inter.index = index;
+        inter.size = std::mem::size_of::<FrostyBox<T>>();
+        inter.align = std::mem::align_of::<FrostyBox<T>>();
+        inter.drop_fn = drop_boxed::<T>;
+        inter.type_id = TypeId::of::<T>();
+        inter.value_size = std::mem::size_of::<T>();
+        #[cfg(feature = "free-poison")]
+        {
+            inter.type_name = std::any::type_name::<T>();
+        }
+        #[cfg(feature = "lock-debug")]
+        {
+            inter.target = lock_debug::Target::new::<T>(slot);
+        }
+        // everything else has to be written before handles see it
+        inter.freed.store(false, Ordering::Release);
+        slot
+    }
+
+    pub fn tick(&self) -> u32 {
+        self.shared.clock.load(Ordering::Relaxed)
+    }
+
+    pub fn advance_tick(&mut self) {
+        self.shared.clock.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The slot handles for (slot) should point at
+    pub fn ptr(&self, slot: Index) -> Option<NonNull<InterimPtr>> {
+        if slot >= self.len {
+            return None;
+        }
+        let page = self.pages.get(slot / INTERIM_PAGE_LEN)?;
+        Some(unsafe { page.add(slot % INTERIM_PAGE_LEN) })
+    }
+
+    pub fn get(&self, slot: Index) -> Option<&InterimPtr> {
+        self.ptr(slot).map(|ptr| unsafe { ptr.as_ref() })
+    }
+
+    pub fn get_mut(&mut self, slot: Index) -> Option<&mut InterimPtr> {
+        self.ptr(slot).map(|mut ptr| unsafe { ptr.as_mut() })
+    }
+
+    // Drop and free the data held by a slot and allow the slot
+    // to be reused once no strong handles point at it.
+    // A [DataAccess] can outlive the handle it came from, so an
+    // object which is still locked is only marked as free'd. It
+    // is dropped by a later call to drop_undropped(). Returns
+    // false if the object's memory is still in use
+    // Handles may still point at the slot, so it is only
+    // reached through a shared reference
+    pub fn release(&mut self, slot: Index) -> bool {
+        let Some(inter) = self.get(slot) else {
+            return true;
+        };
+        if inter.is_freed() {
+            return !inter.is_undropped();
+        }
+        let idle = inter.try_lock_idle();
+        if idle {
+            // the write lock is held, and the object isn't free'd yet
+            unsafe { inter.drop_data() };
+        }
+        inter.free();
+        // pairs with the fence in try_retain()
+        fence(Ordering::SeqCst);
+        if !idle {
+            inter.undropped.store(true, Ordering::Relaxed);
+            inter.parked.store(true, Ordering::Relaxed);
+            self.undropped.push(slot);
+            return false;
+        }
+        if inter.strong_count() > 0 {
+            inter.parked.store(true, Ordering::Relaxed);
+            return true;
+        }
+        self.free_slots.push(slot);
+        true
+    }
+
+    // Drop every object free'd while locked which isn't locked
+    // anymore. Returns the slots which were dropped, whose
+    // memory can now be reused
+    pub fn drop_undropped(&mut self) -> Vec<Index> {
+        let mut dropped = Vec::new();
+        for slot in std::mem::take(&mut self.undropped) {
+            let inter = self.get(slot).expect("InterimTable slot out of bounds");
+            if !inter.try_lock_idle() {
+                self.undropped.push(slot);
+                continue;
+            }
+            // only objects which were never dropped are in (undropped)
+            unsafe { inter.drop_data() };
+            inter.undropped.store(false, Ordering::Relaxed);
+            dropped.push(slot);
+            self.unpark(slot);
+        }
+        dropped
+    }
+
+    // Reuse a parked slot once its handles have all been dropped
+    pub fn unpark(&mut self, slot: Index) {
+        if let Some(inter) = self.get(slot) {
+            if !inter.is_parked() || inter.is_undropped() || inter.strong_count() > 0 {
+                return;
+            }
+            inter.parked.store(false, Ordering::Relaxed);
+            self.free_slots.push(slot);
+        }
+    }
+
+    // Take every slot queued since the last call
+    pub fn take_released(&mut self) -> Vec<(Index, u32)> {
+        std::mem::take(&mut *self.released())
+    }
+
+    // Put slots back in the queue to try again later
+    pub fn requeue(&mut self, released: Vec<(Index, u32)>) {
+        self.released().extend(released);
+    }
+
+    fn released(&self) -> std::sync::MutexGuard<'_, Vec<(Index, u32)>> {
+        self.shared
+            .released
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &InterimPtr> {
+        (0..self.len).map(|slot| unsafe { self.ptr(slot).unwrap().as_ref() })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut InterimPtr> {
+        let table = &*self;
+        (0..self.len).map(|slot| unsafe { table.ptr(slot).unwrap().as_mut() })
+    }
+}
@@ -1,17 +1,40 @@
 #![feature(unsize)]
+#![feature(ptr_metadata)]
 
 mod access;
 mod allocator;
+mod backoff;
 mod chunk;
+mod error;
+mod fetch;
 mod frosty_box;
 mod handle;
 mod interim;
+mod local;
+#[cfg(feature = "lock-debug")]
+mod lock_debug;
+mod page;
+#[cfg(feature = "free-poison")]
+mod poison;
+mod reflect;
+mod stats;
 
 use std::any::TypeId;
 
+// the derive names the crate by path, even in here
+extern crate self as frosty_alloc;
+
 pub use access::*;
 pub use allocator::Allocator;
+pub use error::AllocError;
+pub use fetch::{Fetch, FetchParam};
+pub use frosty_alloc_derive::FrostyAllocatable;
 pub use handle::*;
+pub use local::{CachedObject, LocalCache};
+#[cfg(feature = "lock-debug")]
+pub use lock_debug::{held_locks, LockRecord};
+pub use reflect::{FieldInfo, Reflect};
+pub use stats::{AllocatorStats, TypeStats};
 
 /*
 *  Object Lifetime:
@@ -66,6 +89,13 @@ pub use handle::*;
 *          -----------------------------------------------------
 */
 
+// Anything which can be stored in an [Allocator].
+//
+// Objects are shared between every thread the engine runs and live
+// until they are free'd, no matter what they borrowed. So implementors
+// must be 'static and safe to use from any thread, even though neither
+// is checked by the compiler. Prefer #[derive(FrostyAllocatable)], which
+// refuses lifetime params and types which aren't Send + Sync
 pub unsafe trait FrostyAllocatable: 'static {
     fn id() -> TypeId
     where
@@ -0,0 +1,340 @@
+// Allocation caches for threads which can't reach the [Allocator].
+//
+// Only one thread owns an Allocator, so other threads put the objects
+// they create in a [LocalCache] instead. A cache is a bump arena owned
+// by a single thread, so allocating into it never takes a lock. Later
+// the owner of the Allocator drains the cache, moving each object into
+// the main region.
+
+use std::{
+    alloc::{self, handle_alloc_error, Layout},
+    any::TypeId,
+    mem::ManuallyDrop,
+    ptr::NonNull,
+};
+
+use crate::{AllocError, Allocator, Erased, FrostyAllocatable, ObjectHandleMut};
+
+const BLOCK_SIZE: usize = 16 * 1024;
+const BLOCK_ALIGN: usize = 16;
+
+struct Block {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    ptr: NonNull<u8>,
+    type_id: TypeId,
+    move_into:
+        unsafe fn(NonNull<u8>, &mut Allocator) -> Result<ObjectHandleMut<Erased>, AllocError>,
+    drop: unsafe fn(NonNull<u8>),
+}
+
+pub struct LocalCache {
+    // objects too large or too aligned for a normal
+    // block get a block of their own
+    blocks: Vec<Block>,
+    // bytes used in the last block
+    used: usize,
+    entries: Vec<Entry>,
+}
+
+// Components have to be usable from any thread, see [FrostyAllocatable]
+unsafe impl Send for LocalCache {}
+
+impl LocalCache {
+    // Nothing is reserved until the first object is added
+    pub fn new() -> Self {
+        Self {
+            blocks: Vec::new(),
+            used: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn alloc<T: FrostyAllocatable>(&mut self, obj: T) -> Result<(), AllocError> {
+        if std::mem::size_of::<T>() == 0 {
+            return Err(AllocError::ZeroSized);
+        }
+        let ptr = self.reserve(Layout::new::<T>());
+        unsafe { ptr.cast::<T>().as_ptr().write(obj) };
+        self.entries.push(Entry {
+            ptr,
+            type_id: TypeId::of::<T>(),
+            move_into: move_into::<T>,
+            drop: drop_value::<T>,
+        });
+        Ok(())
+    }
+
+    fn reserve(&mut self, layout: Layout) -> NonNull<u8> {
+        if let Some(block) = self.blocks.last() {
+            let start = self.used.next_multiple_of(layout.align());
+            if block.layout.align() >= layout.align()
+                && start + layout.size() <= block.layout.size()
+            {
+                self.used = start + layout.size();
+                return unsafe { block.ptr.add(start) };
+            }
+        }
+        let block_layout = Layout::from_size_align(
+            layout.size().max(BLOCK_SIZE),
+            layout.align().max(BLOCK_ALIGN),
+        )
+        .expect("LocalCache block too large");
+        let ptr = NonNull::new(unsafe { alloc::alloc(block_layout) })
+            .unwrap_or_else(|| handle_alloc_error(block_layout));
+        self.blocks.push(Block {
+            ptr,
+            layout: block_layout,
+        });
+        self.used = layout.size();
+        ptr
+    }
+
+    // objects waiting to be moved into an Allocator
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Hand each object to (f) in the order they were added.
+    // Objects which (f) doesn't move into an Allocator are
+    // dropped. The cache is empty afterwards
+    pub fn drain<F: FnMut(CachedObject<'_>)>(&mut self, mut f: F) {
+        // taken first so a panic in (f) can't drop anything twice.
+        // The guard drops whatever (f) didn't get to, even if it panics
+        let mut guard = DrainGuard {
+            entries: std::mem::take(&mut self.entries).into_iter(),
+            cache: self,
+        };
+        for entry in guard.entries.by_ref() {
+            f(CachedObject {
+                entry,
+                _cache: std::marker::PhantomData,
+            });
+        }
+    }
+
+    // Free every block but the first, which is kept for reuse
+    fn reset(&mut self) {
+        let keep = match self.blocks.first() {
+            Some(block) => block.layout == standard_block(),
+            None => false,
+        };
+        for block in self.blocks.drain(keep as usize..) {
+            unsafe { alloc::dealloc(block.ptr.as_ptr(), block.layout) };
+        }
+        self.used = 0;
+    }
+}
+
+// Finishes a drain(), see above
+struct DrainGuard<'a> {
+    entries: std::vec::IntoIter<Entry>,
+    cache: &'a mut LocalCache,
+}
+
+impl Drop for DrainGuard<'_> {
+    fn drop(&mut self) {
+        for entry in self.entries.by_ref() {
+            unsafe { (entry.drop)(entry.ptr) };
+        }
+        self.cache.reset();
+    }
+}
+
+fn standard_block() -> Layout {
+    Layout::from_size_align(BLOCK_SIZE, BLOCK_ALIGN).unwrap()
+}
+
+impl Default for LocalCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for LocalCache {
+    fn drop(&mut self) {
+        for entry in std::mem::take(&mut self.entries) {
+            unsafe { (entry.drop)(entry.ptr) };
+        }
+        for block in self.blocks.drain(..) {
+            unsafe { alloc::dealloc(block.ptr.as_ptr(), block.layout) };
+        }
+    }
+}
+
+unsafe fn move_into<T: FrostyAllocatable>(
+    ptr: NonNull<u8>,
+    alloc: &mut Allocator,
+) -> Result<ObjectHandleMut<Erased>, AllocError> {
+    let data = ptr.cast::<T>().as_ptr();
+    match alloc.alloc_raw(data as *const T) {
+        // the Allocator copied the object, so it owns it now
+        Ok(index) => Ok(alloc.get_mut::<T>(index)?.dissolve_data()),
+        Err(e) => {
+            data.drop_in_place();
+            Err(e)
+        }
+    }
+}
+
+unsafe fn drop_value<T>(ptr: NonNull<u8>) {
+    ptr.cast::<T>().as_ptr().drop_in_place();
+}
+
+// An object taken out of a [LocalCache] by drain()
+pub struct CachedObject<'a> {
+    entry: Entry,
+    _cache: std::marker::PhantomData<&'a mut LocalCache>,
+}
+
+impl CachedObject<'_> {
+    pub fn type_id(&self) -> TypeId {
+        self.entry.type_id
+    }
+
+    // The object is dropped if it can't be allocated
+    pub fn move_into(self, alloc: &mut Allocator) -> Result<ObjectHandleMut<Erased>, AllocError> {
+        let this = ManuallyDrop::new(self);
+        unsafe { (this.entry.move_into)(this.entry.ptr, alloc) }
+    }
+}
+
+impl Drop for CachedObject<'_> {
+    fn drop(&mut self) {
+        unsafe { (self.entry.drop)(self.entry.ptr) };
+    }
+}
+
+#[cfg(test)]
+mod local_tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::{LocalCache, BLOCK_SIZE};
+    use crate::{AllocError, Allocator, Erased, FrostyAllocatable, ObjectHandleMut};
+
+    #[derive(FrostyAllocatable)]
+    #[repr(align(64))]
+    struct Aligned(u8);
+
+    // larger than a block
+    #[derive(FrostyAllocatable)]
+    struct Big([u8; BLOCK_SIZE * 2]);
+
+    #[derive(FrostyAllocatable)]
+    struct Named(String);
+
+    #[derive(FrostyAllocatable)]
+    struct Empty;
+
+    #[derive(FrostyAllocatable)]
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn read<T: FrostyAllocatable, U>(
+        handle: &ObjectHandleMut<Erased>,
+        f: impl FnOnce(&T) -> U,
+    ) -> U {
+        f(handle.cast_clone::<T>().get_access(0).unwrap().as_ref())
+    }
+
+    #[test]
+    fn objects_move_into_allocator() {
+        let mut cache = LocalCache::new();
+        cache.alloc(7u16).unwrap();
+        cache.alloc(Aligned(3)).unwrap();
+        cache.alloc(Named(String::from("gull"))).unwrap();
+        cache.alloc(Big([9; BLOCK_SIZE * 2])).unwrap();
+        for i in 0..2000u64 {
+            cache.alloc(i).unwrap();
+        }
+        assert_eq!(2004, cache.len());
+        assert!(matches!(cache.alloc(Empty), Err(AllocError::ZeroSized)));
+
+        let mut alloc = Allocator::new();
+        let mut handles = Vec::new();
+        cache.drain(|obj| handles.push(obj.move_into(&mut alloc).unwrap()));
+        assert!(cache.is_empty());
+
+        assert_eq!(7, read(&handles[0], |n: &u16| *n));
+        assert_eq!(3, read(&handles[1], |a: &Aligned| a.0));
+        assert_eq!("gull", read(&handles[2], |n: &Named| n.0.clone()));
+        assert!(read(&handles[3], |b: &Big| b.0.iter().all(|b| *b == 9)));
+        assert_eq!(1999, read(&handles[2003], |n: &u64| *n));
+    }
+
+    #[test]
+    fn unmoved_objects_are_dropped() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut cache = LocalCache::new();
+        for _ in 0..3 {
+            cache.alloc(Counted(drops.clone())).unwrap();
+        }
+        let mut alloc = Allocator::new();
+        let mut kept = None;
+        cache.drain(|obj| {
+            if kept.is_none() {
+                kept = Some(obj.move_into(&mut alloc).unwrap());
+            }
+        });
+        assert_eq!(2, drops.load(Ordering::Relaxed));
+
+        // the cache is reused, then dropped without draining
+        cache.alloc(Counted(drops.clone())).unwrap();
+        drop(cache);
+        assert_eq!(3, drops.load(Ordering::Relaxed));
+
+        alloc.free(&mut kept.unwrap()).unwrap();
+        assert_eq!(4, drops.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn panicking_drains_drop_the_rest() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut cache = LocalCache::new();
+        for _ in 0..3 {
+            cache.alloc(Counted(drops.clone())).unwrap();
+        }
+        cache.alloc(Big([0; BLOCK_SIZE * 2])).unwrap();
+        let drained = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cache.drain(|_| panic!("failed to spawn"));
+        }));
+        assert!(drained.is_err());
+        assert_eq!(3, drops.load(Ordering::Relaxed));
+        assert!(cache.is_empty());
+        // the oversized block was given back
+        assert_eq!(1, cache.blocks.len());
+    }
+
+    #[test]
+    fn caches_fill_on_other_threads() {
+        let mut cache = std::thread::spawn(|| {
+            let mut cache = LocalCache::new();
+            cache.alloc(Named(String::from("snow"))).unwrap();
+            cache
+        })
+        .join()
+        .unwrap();
+
+        let mut alloc = Allocator::new();
+        cache.drain(|obj| {
+            let handle = obj.move_into(&mut alloc).unwrap();
+            assert_eq!("snow", read(&handle, |n: &Named| n.0.clone()));
+        });
+    }
+}
@@ -0,0 +1,399 @@
+// Lock tracing, turned on with the `lock-debug` feature.
+//
+// Every [DataAccess] and [DataAccessMut] is recorded while it is
+// held: which thread took it, what object it locks and a backtrace
+// of where it was taken. Before a blocking lock is taken the records
+// are checked for requests which can never be granted:
+//      - a thread writing an object it already holds, or reading
+//        an object it is already writing
+//      - two objects being locked in opposite orders, which will
+//        deadlock once two threads do it at the same time. Readers
+//        don't block each other, so this is only reported if both
+//        objects would be waited on
+// Both panic with a report naming the two holders involved.
+//
+// [DataAccess]: crate::DataAccess
+// [DataAccessMut]: crate::DataAccessMut
+
+use std::{
+    any::{type_name, TypeId},
+    backtrace::Backtrace,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex, MutexGuard,
+    },
+    thread::{self, ThreadId},
+};
+
+use hashbrown::HashMap;
+
+use crate::allocator::Index;
+
+// Identifies one allocated object. (id) is never reused, so objects
+// which share a slot of the [InterimTable] are kept apart
+//
+// [InterimTable]: crate::interim::InterimTable
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Target {
+    id: u64,
+    index: Index,
+    type_id: TypeId,
+    type_name: &'static str,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl Target {
+    pub(crate) fn new<T: ?Sized + 'static>(index: Index) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            index,
+            type_id: TypeId::of::<T>(),
+            type_name: type_name::<T>(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Read,
+    Write,
+}
+
+// A lock which is currently held
+#[derive(Debug, Clone)]
+pub struct LockRecord {
+    pub thread_id: ThreadId,
+    pub thread_name: Option<String>,
+    // thread number given to get_access() by the engine
+    pub engine_thread: u32,
+    pub type_id: TypeId,
+    pub type_name: &'static str,
+    // slot of the object in its [Allocator]
+    pub index: Index,
+    pub write: bool,
+    pub backtrace: Arc<Backtrace>,
+    object: u64,
+}
+
+impl LockRecord {
+    fn new(target: Target, mode: Mode, engine_thread: u32) -> Self {
+        let current = thread::current();
+        Self {
+            thread_id: current.id(),
+            thread_name: current.name().map(str::to_owned),
+            engine_thread,
+            type_id: target.type_id,
+            type_name: target.type_name,
+            index: target.index,
+            write: mode == Mode::Write,
+            backtrace: Arc::new(Backtrace::force_capture()),
+            object: target.id,
+        }
+    }
+
+    fn mode(&self) -> &'static str {
+        if self.write {
+            "write"
+        } else {
+            "read"
+        }
+    }
+}
+
+impl fmt::Display for LockRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} access to {} #{} ({:?}) by thread {:?} '{}' (engine thread {}), taken at:",
+            self.mode(),
+            self.type_name,
+            self.index,
+            self.type_id,
+            self.thread_id,
+            self.thread_name.as_deref().unwrap_or("<unnamed>"),
+            self.engine_thread,
+        )?;
+        write!(f, "{}", self.backtrace)
+    }
+}
+
+// (a, b, a written, b written), an object a being held
+// while b is taken
+type OrderKey = (u64, u64, bool, bool);
+
+struct State {
+    held: Vec<LockRecord>,
+    // locks which showed a being held while b was taken. Only kept
+    // if one of them is a write, since two reads can't deadlock
+    order: HashMap<OrderKey, (LockRecord, LockRecord)>,
+    // every key in (order) an object is part of, so they can be
+    // removed once the object is free'd
+    edges: HashMap<u64, Vec<OrderKey>>,
+}
+
+static STATE: LazyLock<Mutex<State>> = LazyLock::new(|| {
+    Mutex::new(State {
+        held: Vec::new(),
+        order: HashMap::new(),
+        edges: HashMap::new(),
+    })
+});
+
+// A panic in one test shouldn't stop the others from being traced
+fn state() -> MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Every lock held by any thread right now
+pub fn held_locks() -> Vec<LockRecord> {
+    state().held.clone()
+}
+
+// Check a lock can be granted before blocking on it
+pub(crate) fn before_lock(target: Target, mode: Mode, engine_thread: u32) {
+    let report = {
+        let state = state();
+        let thread = thread::current().id();
+        let mut report = None;
+        for held in state.held.iter().filter(|h| h.thread_id == thread) {
+            if held.object == target.id {
+                if held.write || mode == Mode::Write {
+                    report = Some(format!(
+                        "re-entrant lock: thread already holds {}\nand is requesting {}",
+                        held,
+                        LockRecord::new(target, mode, engine_thread),
+                    ));
+                    break;
+                }
+            } else if let Some((first, second)) = inversion(&state, target, mode, held) {
+                report = Some(format!(
+                    "lock order inversion: previously\n{}\nwas held while taking\n{}\nbut now\n{}\nis held while requesting\n{}",
+                    first,
+                    second,
+                    held,
+                    LockRecord::new(target, mode, engine_thread),
+                ));
+                break;
+            }
+        }
+        report
+    };
+    if let Some(report) = report {
+        panic!("[lock-debug] {}", report);
+    }
+}
+
+// A lock order recorded earlier which, along with requesting
+// (target) while holding (held), can deadlock. Each object has to
+// be written by one of the two threads for both of them to wait
+fn inversion<'a>(
+    state: &'a State,
+    target: Target,
+    mode: Mode,
+    held: &LockRecord,
+) -> Option<&'a (LockRecord, LockRecord)> {
+    [(true, true), (true, false), (false, true)]
+        .into_iter()
+        .filter(|(first, second)| (*first || mode == Mode::Write) && (*second || held.write))
+        .find_map(|(first, second)| state.order.get(&(target.id, held.object, first, second)))
+}
+
+// Record a lock which has been taken. (ordered) locks add to the
+// lock order, locks which didn't block (try/downgrades) don't
+pub(crate) fn acquired(target: Target, mode: Mode, engine_thread: u32, ordered: bool) {
+    let record = LockRecord::new(target, mode, engine_thread);
+    let mut state = state();
+    if ordered {
+        let earlier: Vec<_> = state
+            .held
+            .iter()
+            .filter(|h| h.thread_id == record.thread_id && h.object != target.id)
+            .filter(|h| h.write || record.write)
+            .cloned()
+            .collect();
+        for held in earlier {
+            let key = (held.object, target.id, held.write, record.write);
+            if state.order.contains_key(&key) {
+                continue;
+            }
+            state.order.insert(key, (held, record.clone()));
+            for object in [key.0, key.1] {
+                state.edges.entry(object).or_default().push(key);
+            }
+        }
+    }
+    state.held.push(record);
+}
+
+// Remove the latest matching lock taken by this thread
+pub(crate) fn released(target: Target, mode: Mode) {
+    let thread = thread::current().id();
+    let write = mode == Mode::Write;
+    let mut state = state();
+    let pos = state
+        .held
+        .iter()
+        .rposition(|h| h.object == target.id && h.thread_id == thread && h.write == write)
+        // accesses can be sent to and dropped on other threads
+        .or_else(|| {
+            state
+                .held
+                .iter()
+                .rposition(|h| h.object == target.id && h.write == write)
+        });
+    if let Some(pos) = pos {
+        state.held.remove(pos);
+    }
+}
+
+// Forget the lock order of an object which has been free'd.
+// Its id is never reused, so it can't show up again
+pub(crate) fn forget(target: Target) {
+    let mut state = state();
+    let Some(keys) = state.edges.remove(&target.id) else {
+        return;
+    };
+    for key in keys {
+        state.order.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod lock_debug_tests {
+    use std::thread;
+
+    use crate::{
+        lock_debug::{held_locks, state},
+        Allocator,
+    };
+
+    fn panic_message(result: thread::Result<()>) -> String {
+        let err = result.expect_err("expected a lock-debug panic");
+        match err.downcast::<String>() {
+            Ok(msg) => *msg,
+            Err(err) => err.downcast_ref::<&str>().unwrap().to_string(),
+        }
+    }
+
+    #[test]
+    fn records_acquire_and_release() {
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(1u32).unwrap();
+        let index = handle.interim().slot;
+
+        let mine = |write| {
+            held_locks()
+                .into_iter()
+                .filter(|h| h.thread_id == thread::current().id() && h.write == write)
+                .filter(|h| h.type_name == "u32" && h.index == index)
+                .count()
+        };
+        let write = handle.get_access_mut(3).unwrap();
+        assert_eq!(1, mine(true));
+        let read = write.drop_mut();
+        assert_eq!((0, 1), (mine(true), mine(false)));
+        drop(read);
+        assert_eq!(0, mine(false));
+    }
+
+    #[test]
+    fn reentrant_write_panics() {
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(1u32).unwrap();
+        let mut other = handle.cast_clone::<u32>();
+
+        let msg = panic_message(
+            thread::Builder::new()
+                .name("reentrant".into())
+                .spawn(move || {
+                    let _write = handle.get_access_mut(0).unwrap();
+                    let _again = other.get_access_mut(0);
+                })
+                .unwrap()
+                .join(),
+        );
+        assert!(msg.contains("re-entrant lock"), "{}", msg);
+        assert!(msg.contains("write access to u32"), "{}", msg);
+        assert!(msg.contains("'reentrant'"), "{}", msg);
+    }
+
+    #[test]
+    fn reentrant_reads_are_fine() {
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(1u32).unwrap();
+        let mut other = handle.cast_clone::<u32>();
+        let _a = handle.get_access(0).unwrap();
+        let _b = other.get_access(0).unwrap();
+        // a try can't deadlock, so it is left to fail normally
+        assert!(other.try_get_access_mut(0).is_err());
+    }
+
+    #[test]
+    fn lock_order_inversion_panics() {
+        let mut alloc = Allocator::new();
+        let mut a = alloc.alloc(1u32).unwrap();
+        let mut b = alloc.alloc(2u64).unwrap();
+        let (mut a2, mut b2) = (a.cast_clone::<u32>(), b.cast_clone::<u64>());
+
+        thread::spawn(move || {
+            let _a = a.get_access_mut(1).unwrap();
+            let _b = b.get_access_mut(1).unwrap();
+        })
+        .join()
+        .unwrap();
+
+        let msg = panic_message(
+            thread::spawn(move || {
+                let _b = b2.get_access(2).unwrap();
+                let _a = a2.get_access_mut(2);
+            })
+            .join(),
+        );
+        assert!(msg.contains("lock order inversion"), "{}", msg);
+        assert!(msg.contains("engine thread 1"), "{}", msg);
+        assert!(msg.contains("read access to u64"), "{}", msg);
+        assert!(msg.contains("write access to u32"), "{}", msg);
+        // the panicking thread must not leave its locks behind
+        assert!(!held_locks().iter().any(|h| h.engine_thread == 2));
+    }
+
+    #[test]
+    fn reads_in_opposite_orders_are_fine() {
+        let mut alloc = Allocator::new();
+        let mut a = alloc.alloc(1u32).unwrap();
+        let mut b = alloc.alloc(2u64).unwrap();
+        let (mut a2, mut b2) = (a.cast_clone::<u32>(), b.cast_clone::<u64>());
+
+        thread::spawn(move || {
+            let _a = a.get_access(3).unwrap();
+            let _b = b.get_access(3).unwrap();
+        })
+        .join()
+        .unwrap();
+        thread::spawn(move || {
+            let _b = b2.get_access(4).unwrap();
+            let _a = a2.get_access(4).unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn free_forgets_lock_order() {
+        let mut alloc = Allocator::new();
+        let mut a = alloc.alloc(1u32).unwrap();
+        let mut b = alloc.alloc(2u64).unwrap();
+        {
+            let _a = a.get_access_mut(5).unwrap();
+            let _b = b.get_access(5).unwrap();
+        }
+        let id = a.interim().target.id;
+        let remembered = || state().order.keys().any(|k| k.0 == id || k.1 == id);
+        assert!(remembered());
+        alloc.free(&mut a).unwrap();
+        assert!(!remembered());
+        assert!(!state().edges.contains_key(&id));
+    }
+}
@@ -0,0 +1,97 @@
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::ptr::NonNull;
+
+use crate::AllocError;
+
+// Default size of a [Page]. Objects larger than this are given
+// a page of their own
+pub(crate) const PAGE_SIZE: usize = 16 * 1024;
+// Every page starts on a cache line. Objects aligned to more than
+// this are given a page of their own
+pub(crate) const PAGE_ALIGN: usize = 64;
+
+// A block of memory owned by an [Allocator].
+//
+// Unlike a Vec<u8>, a page is never resized or moved once it has
+// been created. Any pointer into a page (from an [InterimPtr],
+// [DataAccess], [DynObjectHandle], etc.) stays valid until the
+// page is dropped along with its [Allocator], no matter how many
+// more pages get added.
+pub(crate) struct Page {
+    ptr: NonNull<u8>,
+    len: usize,
+    // None if the memory was handed over by the caller
+    // rather than allocated by the page
+    layout: Option<Layout>,
+}
+
+impl Page {
+    // Create a page of (len) bytes whose first byte is aligned
+    // to at least (align)
+    pub fn new(len: usize, align: usize) -> Result<Self, AllocError> {
+        let out_of_memory = AllocError::OutOfMemory { requested: len };
+        let layout = Layout::from_size_align(len.max(1), align.max(PAGE_ALIGN))
+            .map_err(|_| out_of_memory)?;
+        // pages are zeroed to match the previous behaviour of
+        // initializing the entire region
+        let ptr = unsafe { alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).ok_or(out_of_memory)?;
+        let page = Self {
+            ptr,
+            len: layout.size(),
+            layout: Some(layout),
+        };
+        #[cfg(feature = "free-poison")]
+        page.poison();
+        Ok(page)
+    }
+
+    // Use a caller supplied buffer as a page. Bytes before the
+    // first PAGE_ALIGN aligned address are skipped
+    pub fn from_buffer(buffer: &'static mut [u8]) -> Result<Self, AllocError> {
+        if buffer.is_empty() {
+            return Err(AllocError::ZeroSized);
+        }
+        let skip = buffer.as_ptr().align_offset(PAGE_ALIGN);
+        if skip >= buffer.len() {
+            return Err(AllocError::BufferTooSmall {
+                len: buffer.len(),
+                align: PAGE_ALIGN,
+            });
+        }
+        let usable = &mut buffer[skip..];
+        let page = Self {
+            ptr: NonNull::new(usable.as_mut_ptr()).unwrap(),
+            len: usable.len(),
+            layout: None,
+        };
+        #[cfg(feature = "free-poison")]
+        page.poison();
+        Ok(page)
+    }
+
+    // Nothing lives in a new page, so all of it is poisoned
+    #[cfg(feature = "free-poison")]
+    fn poison(&self) {
+        unsafe { std::ptr::write_bytes(self.ptr.as_ptr(), crate::poison::POISON, self.len) };
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // SAFETY:
+    //      offset must be less than self.len()
+    pub unsafe fn ptr_at(&self, offset: usize) -> NonNull<u8> {
+        debug_assert!(offset < self.len());
+        NonNull::new_unchecked(self.ptr.as_ptr().add(offset))
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        if let Some(layout) = self.layout {
+            unsafe { dealloc(self.ptr.as_ptr(), layout) };
+        }
+    }
+}
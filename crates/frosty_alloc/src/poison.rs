@@ -0,0 +1,220 @@
+// Use after free detection, turned on with the `free-poison` feature.
+//
+// Every byte of the region which isn't holding an object is filled
+// with POISON: new pages, and objects as soon as they are free'd.
+// Before memory is handed out again it is checked to still be
+// POISON. Anything else means something wrote through a ptr to an
+// object after it was free'd, so the allocator panics naming what
+// used to live there and where in it the write landed.
+
+use std::{any::TypeId, collections::BTreeMap, fmt::Write};
+
+use crate::{chunk::Chunk, frosty_box::BitMask, page::Page};
+
+pub(crate) const POISON: u8 = 0xDF;
+
+// An object which has been free'd, and where it was
+struct FreedObject {
+    len: usize,
+    type_id: TypeId,
+    type_name: &'static str,
+    // offset of the value inside its FrostyBox
+    value_offset: usize,
+}
+
+pub(crate) struct PoisonLog {
+    // (page, start) -> object free'd there
+    freed: BTreeMap<(usize, usize), FreedObject>,
+}
+
+impl PoisonLog {
+    pub fn new() -> Self {
+        Self {
+            freed: BTreeMap::new(),
+        }
+    }
+
+    // Poison a FrostyBox which has just been free'd. (align) is
+    // the alignment of the box, as stored in its [InterimPtr]
+    //
+    // [InterimPtr]: crate::interim::InterimPtr
+    pub fn free(
+        &mut self,
+        page: &Page,
+        chunk: &Chunk,
+        align: usize,
+        type_id: TypeId,
+        type_name: &'static str,
+    ) {
+        fill(page, chunk);
+        let value_offset = std::mem::size_of::<BitMask>().next_multiple_of(align);
+        self.freed.insert(
+            (chunk.page, chunk.start),
+            FreedObject {
+                len: chunk.len,
+                type_id,
+                type_name,
+                value_offset,
+            },
+        );
+    }
+
+    // Panics if anything in (chunk) has been written to since it
+    // was poisoned. The objects free'd in (chunk) are forgotten,
+    // since it is about to be reused
+    pub fn claim(&mut self, page: &Page, chunk: &Chunk) {
+        self.verify(page, chunk);
+        let overlapping: Vec<_> = self
+            .freed
+            .range((chunk.page, 0)..(chunk.page, chunk.start + chunk.len))
+            .rev()
+            .take_while(|((_, start), obj)| start + obj.len > chunk.start)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in overlapping {
+            self.freed.remove(&key);
+        }
+    }
+
+    pub fn verify(&self, page: &Page, chunk: &Chunk) {
+        let bytes =
+            unsafe { std::slice::from_raw_parts(page.ptr_at(chunk.start).as_ptr(), chunk.len) };
+        let Some(first) = bytes.iter().position(|b| *b != POISON) else {
+            return;
+        };
+        let written = bytes.iter().filter(|b| **b != POISON).count();
+        let offset = chunk.start + first;
+        panic!("{}", self.report(chunk.page, offset, written));
+    }
+
+    // Forget every free'd object and poison (chunks) again. Used
+    // once free memory has been rearranged by compaction
+    pub fn reset<'a>(&mut self, pages: &[Page], chunks: impl Iterator<Item = &'a Chunk>) {
+        self.freed.clear();
+        for chunk in chunks {
+            fill(&pages[chunk.page], chunk);
+        }
+    }
+
+    fn report(&self, page: usize, offset: usize, written: usize) -> String {
+        let mut msg = format!(
+            "use after free: {} free'd byte(s) were written to, the first at offset {} of page {}",
+            written, offset, page
+        );
+        let owner = self
+            .freed
+            .range((page, 0)..=(page, offset))
+            .next_back()
+            .filter(|((_, start), obj)| start + obj.len > offset);
+        match owner {
+            Some(((_, start), obj)) => {
+                let into = offset - start;
+                let _ = write!(
+                    msg,
+                    "\nit belonged to a free'd {} ({:?}), ",
+                    obj.type_name, obj.type_id
+                );
+                if into < obj.value_offset {
+                    let _ = write!(msg, "in its semaphore");
+                } else {
+                    let _ = write!(msg, "{} byte(s) into the value", into - obj.value_offset);
+                }
+            }
+            None => {
+                let _ = write!(
+                    msg,
+                    "\nno object has been free'd there since the last compaction"
+                );
+            }
+        }
+        msg
+    }
+}
+
+pub(crate) fn fill(page: &Page, chunk: &Chunk) {
+    if chunk.len == 0 {
+        return;
+    }
+    unsafe { std::ptr::write_bytes(page.ptr_at(chunk.start).as_ptr(), POISON, chunk.len) };
+}
+
+#[cfg(test)]
+mod poison_tests {
+    use std::{panic, panic::AssertUnwindSafe};
+
+    use super::POISON;
+    use crate::{Allocator, FrostyAllocatable};
+
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct Vertex {
+        pos: u64,
+        color: u64,
+    }
+    unsafe impl FrostyAllocatable for Vertex {}
+
+    fn panic_message(f: impl FnOnce()) -> String {
+        let err = panic::catch_unwind(AssertUnwindSafe(f)).expect_err("expected a poison panic");
+        match err.downcast::<String>() {
+            Ok(msg) => *msg,
+            Err(err) => err.downcast_ref::<&str>().unwrap().to_string(),
+        }
+    }
+
+    #[test]
+    fn freed_memory_is_poisoned() {
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(Vertex { pos: 1, color: 2 }).unwrap();
+        let raw = handle.get_access(0).unwrap().as_ref() as *const Vertex as *const u8;
+        alloc.free(&mut handle).unwrap();
+        let bytes = unsafe { std::slice::from_raw_parts(raw, std::mem::size_of::<Vertex>()) };
+        assert!(bytes.iter().all(|b| *b == POISON));
+        // untouched memory can be handed out again
+        alloc.alloc(Vertex { pos: 3, color: 4 }).unwrap();
+    }
+
+    #[test]
+    fn write_after_free_is_caught() {
+        let mut alloc = Allocator::new();
+        let mut handle = alloc.alloc(Vertex { pos: 1, color: 2 }).unwrap();
+        let raw = handle.get_access_mut(0).unwrap().as_mut() as *mut Vertex;
+        alloc.free(&mut handle).unwrap();
+        unsafe { (*raw).color = 7 };
+
+        let msg = panic_message(|| {
+            alloc.alloc(Vertex { pos: 3, color: 4 }).unwrap();
+        });
+        assert!(msg.contains("use after free"), "{}", msg);
+        assert!(msg.contains("poison_tests::Vertex"), "{}", msg);
+        assert!(msg.contains("8 byte(s) into the value"), "{}", msg);
+    }
+
+    #[test]
+    fn verify_freed_checks_everything() {
+        let mut alloc = Allocator::new();
+        let mut handles: Vec<_> = (0..4).map(|i| alloc.alloc(i as u32).unwrap()).collect();
+        let raw = handles[2].get_access_mut(0).unwrap().as_mut() as *mut u32;
+        for handle in handles.iter_mut() {
+            alloc.free(handle).unwrap();
+        }
+        alloc.verify_freed();
+        unsafe { *raw = 1 };
+        let msg = panic_message(|| alloc.verify_freed());
+        assert!(msg.contains("free'd u32"), "{}", msg);
+        assert!(msg.contains("0 byte(s) into the value"), "{}", msg);
+    }
+
+    #[test]
+    fn compaction_keeps_poison() {
+        let mut alloc = Allocator::new();
+        let mut handles: Vec<_> = (0..8).map(|i| alloc.alloc(i as u64).unwrap()).collect();
+        for handle in handles.iter_mut().step_by(2) {
+            alloc.free(handle).unwrap();
+        }
+        alloc.compact();
+        alloc.verify_freed();
+        for handle in handles.iter_mut().skip(1).step_by(2) {
+            assert_eq!(1, *handle.get_access(0).unwrap().as_ref() % 2);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+// Runtime descriptions of components, for editors and debug views.
+// Derived with #[derive(FrostyAllocatable)] and #[frosty(reflect)]
+
+use crate::FrostyAllocatable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldInfo {
+    // the field name, or its position in a tuple struct
+    pub name: &'static str,
+    pub type_name: &'static str,
+    // offset of the field from the start of the object
+    pub offset: usize,
+    pub size: usize,
+}
+
+pub trait Reflect: FrostyAllocatable + Sized {
+    fn type_name() -> &'static str;
+    // fields in the order they are declared
+    fn fields() -> Vec<FieldInfo>;
+}
+
+#[cfg(test)]
+mod reflect_tests {
+    use super::{FieldInfo, Reflect};
+    use crate::{Allocator, FrostyAllocatable};
+
+    #[derive(FrostyAllocatable)]
+    #[frosty(reflect)]
+    #[repr(C)]
+    struct Cloud {
+        height: f32,
+        density: u8,
+        drift: (f32, f32),
+    }
+
+    #[derive(FrostyAllocatable)]
+    #[frosty(reflect)]
+    struct Pair<T>(T, u16);
+
+    #[test]
+    fn fields_are_described() {
+        assert!(Cloud::type_name().ends_with("reflect_tests::Cloud"));
+        let fields = Cloud::fields();
+        assert_eq!(
+            FieldInfo {
+                name: "density",
+                type_name: "u8",
+                offset: 4,
+                size: 1,
+            },
+            fields[1]
+        );
+        assert_eq!(
+            ("drift", 8, 8),
+            (fields[2].name, fields[2].offset, fields[2].size)
+        );
+
+        let fields = Pair::<u64>::fields();
+        assert_eq!(
+            vec!["0", "1"],
+            fields.iter().map(|f| f.name).collect::<Vec<_>>()
+        );
+        assert_eq!("u64", fields[0].type_name);
+    }
+
+    #[test]
+    fn derived_types_allocate() {
+        let mut alloc = Allocator::new();
+        let mut handle = alloc
+            .alloc(Cloud {
+                height: 3.0,
+                density: 9,
+                drift: (0.5, 0.0),
+            })
+            .unwrap();
+        assert_eq!(9, handle.get_access(0).unwrap().as_ref().density);
+        alloc.alloc(Pair(String::from("rain"), 2)).unwrap();
+    }
+}
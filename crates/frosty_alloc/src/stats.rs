@@ -0,0 +1,52 @@
+use std::any::TypeId;
+
+use hashbrown::HashMap;
+
+// Number of objects of a single type held by an [Allocator] and
+// how many bytes they take up, including their [FrostyBox]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TypeStats {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+// A snapshot of how an [Allocator] is using its region.
+// Collecting one walks every interim slot and free chunk, so
+// it's meant for debug overlays and tests rather than every frame
+#[derive(Debug, Clone, Default)]
+pub struct AllocatorStats {
+    // total bytes held across all pages
+    pub capacity: usize,
+    // bytes taken up by live objects
+    pub live_bytes: usize,
+    // bytes sitting in free chunks
+    pub free_bytes: usize,
+    pub largest_free_chunk: usize,
+    pub free_chunks: usize,
+    // interim slots holding live objects and slots waiting to be reused
+    pub live_slots: usize,
+    pub freed_slots: usize,
+    // number of times the region has grown past its initial capacity
+    pub resizes: usize,
+    pub types: HashMap<TypeId, TypeStats>,
+}
+
+impl AllocatorStats {
+    // Returns the stats of objects of type T
+    pub fn of<T: 'static>(&self) -> TypeStats {
+        self.types
+            .get(&TypeId::of::<T>())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Fraction of free memory which is unusable for an object of
+    // the size of the largest free chunk. 0.0 when all free memory
+    // is in one chunk
+    pub fn fragmentation(&self) -> f32 {
+        if self.free_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free_chunk as f32 / self.free_bytes as f32
+    }
+}
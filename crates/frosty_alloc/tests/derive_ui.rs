@@ -0,0 +1,7 @@
+// The derive refuses types which can't be shared between threads.
+// Update the .stderr files with TRYBUILD=overwrite
+#[test]
+fn derive_ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
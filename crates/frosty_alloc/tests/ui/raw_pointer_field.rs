@@ -0,0 +1,8 @@
+use frosty_alloc::FrostyAllocatable;
+
+#[derive(FrostyAllocatable)]
+struct Target {
+    entity: *const u32,
+}
+
+fn main() {}
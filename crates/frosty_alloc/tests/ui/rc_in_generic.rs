@@ -0,0 +1,14 @@
+use std::rc::Rc;
+
+use frosty_alloc::FrostyAllocatable;
+
+#[derive(FrostyAllocatable)]
+struct Wrapper<T> {
+    inner: Vec<T>,
+}
+
+fn store<T: FrostyAllocatable>() {}
+
+fn main() {
+    store::<Wrapper<Rc<u32>>>();
+}
@@ -0,0 +1,10 @@
+use std::cell::RefCell;
+
+use frosty_alloc::FrostyAllocatable;
+
+#[derive(FrostyAllocatable)]
+struct Inventory {
+    items: RefCell<Vec<u32>>,
+}
+
+fn main() {}
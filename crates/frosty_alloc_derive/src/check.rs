@@ -0,0 +1,47 @@
+// Objects in an Allocator are shared between every worker thread and
+// live until they are free'd, which the compiler can't see through the
+// unsafe impl. These checks catch the types which would break that:
+//      - lifetime params, since the object could outlive what it borrows
+//      - types which aren't Send + Sync, like Rc, RefCell or raw
+//        pointers, since the threads would race on them. This is left
+//        to the compiler through an assertion on the type, so fields
+//        are checked no matter what they are named
+
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{spanned::Spanned, DeriveInput};
+
+pub(crate) fn check_input(input: &DeriveInput) -> syn::Result<()> {
+    let mut errors = input.generics.lifetimes().map(|lifetime| {
+        syn::Error::new(
+            lifetime.span(),
+            format!(
+                "FrostyAllocatable types can't borrow for {}, since the Allocator decides how long they live",
+                lifetime.lifetime
+            ),
+        )
+    });
+    match errors.next() {
+        Some(mut first) => {
+            errors.for_each(|e| first.combine(e));
+            Err(first)
+        }
+        None => Ok(()),
+    }
+}
+
+// Fails to compile unless the type is Send + Sync. (input) should
+// already have the bounds added by expand()
+pub(crate) fn assert_send_sync(input: &DeriveInput) -> TokenStream2 {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    quote! {
+        #[allow(dead_code)]
+        const _: () = {
+            fn assert_send_sync<T: ?Sized + Send + Sync>() {}
+            fn assert_thread_safe #impl_generics () #where_clause {
+                assert_send_sync::<#name #ty_generics>();
+            }
+        };
+    }
+}
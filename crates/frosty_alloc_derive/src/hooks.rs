@@ -0,0 +1,100 @@
+// The impls asked for with #[frosty(...)]
+
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{spanned::Spanned, Data, DeriveInput, Fields, Index, LitStr};
+
+use crate::Options;
+
+fn struct_fields<'a>(input: &'a DeriveInput, option: &str) -> syn::Result<&'a Fields> {
+    match &input.data {
+        Data::Struct(s) => Ok(&s.fields),
+        _ => Err(syn::Error::new(
+            input.ident.span(),
+            format!("#[frosty({option})] only supports structs"),
+        )),
+    }
+}
+
+// impl SerializableComponent by writing each field in order
+pub(crate) fn serialize(
+    input: &DeriveInput,
+    options: &Options,
+    type_name: Option<&LitStr>,
+) -> syn::Result<TokenStream2> {
+    let fields = struct_fields(input, "serialize")?;
+    // every instance of a generic type would share the same name
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            input.generics.span(),
+            "#[frosty(serialize)] doesn't support generic types",
+        ));
+    }
+
+    let name = &input.ident;
+    let engine = &options.engine;
+    let type_name = match type_name {
+        Some(type_name) => quote!(#type_name),
+        None => quote!(concat!(module_path!(), "::", stringify!(#name))),
+    };
+    let members: Vec<_> = fields.members().collect();
+    let reads = members
+        .iter()
+        .map(|_| quote!(#engine::SnapshotValue::read(input)?));
+    let construct = match fields {
+        Fields::Named(_) => quote!(Self { #(#members: #reads),* }),
+        Fields::Unnamed(_) => quote!(Self(#(#reads),*)),
+        Fields::Unit => quote!(Self),
+    };
+
+    Ok(quote! {
+        impl #engine::SerializableComponent for #name {
+            const TYPE_NAME: &'static str = #type_name;
+            fn serialize(&self, out: &mut Vec<u8>) {
+                #(#engine::SnapshotValue::write(&self.#members, out);)*
+            }
+            fn deserialize(input: &mut &[u8]) -> Result<Self, #engine::SnapshotError> {
+                // fields are read in the order they are written
+                Ok(#construct)
+            }
+        }
+    })
+}
+
+// impl Reflect by listing where each field is
+pub(crate) fn reflect(input: &DeriveInput, options: &Options) -> syn::Result<TokenStream2> {
+    let fields = struct_fields(input, "reflect")?;
+    let name = &input.ident;
+    let alloc = &options.alloc;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let infos = fields.iter().enumerate().map(|(i, field)| {
+        let member = match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = Index::from(i);
+                quote!(#index)
+            }
+        };
+        let ty = &field.ty;
+        quote! {
+            #alloc::FieldInfo {
+                name: stringify!(#member),
+                type_name: ::std::any::type_name::<#ty>(),
+                offset: ::std::mem::offset_of!(Self, #member),
+                size: ::std::mem::size_of::<#ty>(),
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics #alloc::Reflect for #name #ty_generics #where_clause {
+            fn type_name() -> &'static str {
+                ::std::any::type_name::<Self>()
+            }
+            fn fields() -> Vec<#alloc::FieldInfo> {
+                vec![#(#infos),*]
+            }
+        }
+    })
+}
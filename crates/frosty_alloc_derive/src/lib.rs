@@ -0,0 +1,190 @@
+// #[derive(FrostyAllocatable)]
+//
+// Emits the `unsafe impl FrostyAllocatable` for a type after checking
+// it is sound to store in an Allocator, see check.rs. Extra impls can
+// be asked for with #[frosty(...)]:
+//      serialize           impl engine_core::SerializableComponent,
+//                          named after the path of the type
+//      serialize = "name"  the same, with a TYPE_NAME given by hand
+//      reflect             impl frosty_alloc::Reflect
+//      crate = "path"      where frosty_alloc is, if it isn't a
+//                          direct dependency
+//      engine = "path"     where engine_core is, for serialize
+
+mod check;
+mod hooks;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, DeriveInput, GenericParam, LitStr, Path};
+
+#[proc_macro_derive(FrostyAllocatable, attributes(frosty))]
+pub fn derive_frosty_allocatable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+pub(crate) struct Options {
+    pub alloc: Path,
+    pub engine: Path,
+    // Some(None) when serialize is asked for without a name
+    pub serialize: Option<Option<LitStr>>,
+    pub reflect: bool,
+}
+
+impl Options {
+    fn parse(input: &DeriveInput) -> syn::Result<Self> {
+        let mut options = Self {
+            alloc: parse_quote!(::frosty_alloc),
+            engine: parse_quote!(::engine_core),
+            serialize: None,
+            reflect: false,
+        };
+        for attr in input.attrs.iter().filter(|a| a.path().is_ident("frosty")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("serialize") {
+                    let name = match meta.input.peek(syn::Token![=]) {
+                        true => Some(meta.value()?.parse()?),
+                        false => None,
+                    };
+                    options.serialize = Some(name);
+                } else if meta.path.is_ident("reflect") {
+                    options.reflect = true;
+                } else if meta.path.is_ident("crate") {
+                    options.alloc = meta.value()?.parse::<LitStr>()?.parse()?;
+                } else if meta.path.is_ident("engine") {
+                    options.engine = meta.value()?.parse::<LitStr>()?.parse()?;
+                } else {
+                    return Err(meta.error(
+                        "unknown frosty option, expected serialize, reflect, crate or engine",
+                    ));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(options)
+    }
+}
+
+pub(crate) fn expand(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let options = Options::parse(&input)?;
+    check::check_input(&input)?;
+
+    // objects live for as long as the Allocator wants, so nothing
+    // inside them can be borrowed. They are also shared between
+    // threads, so the params have to be too
+    let params: Vec<_> = input
+        .generics
+        .params
+        .iter()
+        .filter_map(|p| match p {
+            GenericParam::Type(t) => Some(t.ident.clone()),
+            _ => None,
+        })
+        .collect();
+    let where_clause = input.generics.make_where_clause();
+    for param in params {
+        where_clause
+            .predicates
+            .push(parse_quote!(#param: 'static + Send + Sync));
+    }
+
+    let name = &input.ident;
+    let alloc = &options.alloc;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut out = quote! {
+        unsafe impl #impl_generics #alloc::FrostyAllocatable for #name #ty_generics #where_clause {}
+    };
+    out.extend(check::assert_send_sync(&input));
+    if let Some(type_name) = &options.serialize {
+        out.extend(hooks::serialize(&input, &options, type_name.as_ref())?);
+    }
+    if options.reflect {
+        out.extend(hooks::reflect(&input, &options)?);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod derive_tests {
+    use syn::parse_quote;
+
+    use super::expand;
+
+    fn error(input: syn::DeriveInput) -> String {
+        let err = expand(input).expect_err("expected the derive to fail");
+        err.into_iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn plain_structs_derive() {
+        let out = expand(parse_quote! {
+            struct Bird { height: f32, name: String }
+        })
+        .unwrap()
+        .to_string();
+        assert!(out.contains("unsafe impl :: frosty_alloc :: FrostyAllocatable for Bird"));
+        assert!(!out.contains("SerializableComponent"));
+    }
+
+    #[test]
+    fn generics_must_be_static() {
+        let out = expand(parse_quote! {
+            struct Wrapper<T> { inner: Vec<T> }
+        })
+        .unwrap()
+        .to_string();
+        assert!(out.contains("T : 'static + Send + Sync"), "{}", out);
+
+        let msg = error(parse_quote! {
+            struct Borrowed<'a> { name: &'a str }
+        });
+        assert!(msg.contains("can't borrow"), "{}", msg);
+        // references which live forever are fine
+        expand(parse_quote! {
+            struct Named { name: &'static str }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn types_are_asserted_thread_safe() {
+        let out = expand(parse_quote! {
+            struct Shared { a: std::rc::Rc<u32> }
+        })
+        .unwrap()
+        .to_string();
+        // the compiler rejects the field, see tests/ui in frosty_alloc
+        assert!(out.contains("assert_send_sync :: < Shared > ()"), "{}", out);
+    }
+
+    #[test]
+    fn options_are_checked() {
+        let out = expand(parse_quote! {
+            #[frosty(serialize = "game::Sun", reflect, crate = "engine_core::alloc")]
+            struct Sun { heat: f32 }
+        })
+        .unwrap()
+        .to_string();
+        assert!(out.contains("engine_core :: alloc :: FrostyAllocatable for Sun"));
+        assert!(out.contains("\"game::Sun\""));
+        assert!(out.contains("Reflect for Sun"));
+
+        let msg = error(parse_quote! {
+            #[frosty(serialise)]
+            struct Typo;
+        });
+        assert!(msg.contains("unknown frosty option"), "{}", msg);
+        let msg = error(parse_quote! {
+            #[frosty(serialize)]
+            enum Weather { Sun, Rain }
+        });
+        assert!(msg.contains("only supports structs"), "{}", msg);
+    }
+}
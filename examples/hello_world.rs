@@ -8,7 +8,7 @@ use std::any::TypeId;
 use engine_core::app::WindowlessApp;
 use engine_core::system::*;
 use engine_core::{query::Query, SceneBuilder};
-use frosty_alloc::FrostyAllocatable;
+use frosty_alloc::{Erased, FrostyAllocatable};
 
 struct HelloWorldSystem {}
 impl System for HelloWorldSystem {
@@ -28,13 +28,13 @@ impl SystemInterface for HelloWorldSystem {
         SystemId(0)
     }
 
-    fn start_update(&self, objs: Query<u8>) -> UpdateResult {
+    fn start_update(&self, objs: Query<Erased>) -> UpdateResult {
         let real_objs = unsafe { objs.cast::<Speaker>() };
         self.update(real_objs)
     }
 
-    fn alloc_id(&self) -> TypeId {
-        Speaker::id()
+    fn alloc_ids(&self) -> Vec<TypeId> {
+        vec![Speaker::id()]
     }
 
     fn query_type() -> SystemQuerySchedule
@@ -8,7 +8,7 @@ use engine_core::{
     system::{System, SystemId, SystemInterface, UpdateResult},
     App, SceneBuilder, MASTER_THREAD,
 };
-use frosty_alloc::FrostyAllocatable;
+use frosty_alloc::{Erased, FrostyAllocatable};
 use render::{
     mesh::{IndexArray, Mesh},
     vertex::MeshVertex,
@@ -51,7 +51,7 @@ impl System for TriangleRotater {
 }
 
 impl SystemInterface for TriangleRotater {
-    fn start_update(&self, objs: engine_core::query::Query<u8>) -> UpdateResult {
+    fn start_update(&self, objs: engine_core::query::Query<Erased>) -> UpdateResult {
         self.update(unsafe { objs.cast() })
     }
     fn dependencies() -> Vec<SystemId>
@@ -66,8 +66,8 @@ impl SystemInterface for TriangleRotater {
     {
         SystemId(0)
     }
-    fn alloc_id(&self) -> std::any::TypeId {
-        Mesh::<MeshVertex>::id()
+    fn alloc_ids(&self) -> Vec<std::any::TypeId> {
+        vec![Mesh::<MeshVertex>::id()]
     }
 }
 
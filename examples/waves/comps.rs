@@ -8,7 +8,7 @@
 //
 
 use engine_core::SceneBuilder;
-use frosty_alloc::{AllocId, FrostyAllocatable};
+use frosty_alloc::FrostyAllocatable;
 
 pub(crate) fn register_comps(scene: SceneBuilder) -> SceneBuilder {
     scene
@@ -20,18 +20,14 @@ pub(crate) fn register_comps(scene: SceneBuilder) -> SceneBuilder {
 
 struct OceanVertex {}
 
+#[derive(FrostyAllocatable)]
 struct OceanMesh {}
 
+#[derive(FrostyAllocatable)]
 struct Sun {}
 
+#[derive(FrostyAllocatable)]
 struct Cloud {}
 
+#[derive(FrostyAllocatable)]
 struct Bird {}
-
-unsafe impl FrostyAllocatable for OceanMesh {}
-
-unsafe impl FrostyAllocatable for Sun {}
-
-unsafe impl FrostyAllocatable for Cloud {}
-
-unsafe impl FrostyAllocatable for Bird {}